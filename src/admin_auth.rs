@@ -0,0 +1,89 @@
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Request, Response, StatusCode};
+use tower_http::validate_request::ValidateRequest;
+
+/// Lets through requests with `Authorization: Bearer <token>`. Unlike
+/// `ValidateRequestHeaderLayer::bearer` it compares in constant time, so
+/// response times don't tell how much of a guess was right.
+#[derive(Clone)]
+pub struct BearerToken {
+    expected: Vec<u8>,
+}
+
+impl BearerToken {
+    pub fn new(token: &str) -> Self {
+        Self {
+            expected: format!("Bearer {}", token).into_bytes(),
+        }
+    }
+}
+
+impl<B> ValidateRequest<B> for BearerToken {
+    type ResponseBody = Body;
+
+    fn validate(&mut self, request: &mut Request<B>) -> Result<(), Response<Self::ResponseBody>> {
+        let given = request
+            .headers()
+            .get(AUTHORIZATION)
+            .map_or(&[][..], |value| value.as_bytes());
+        if constant_time_eq(given, &self.expected) {
+            return Ok(());
+        }
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        Err(response)
+    }
+}
+
+// Looks at every byte whatever the first difference. Only the length, which
+// isn't secret, ends it early.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(authorization: Option<&str>) -> Result<(), StatusCode> {
+        let mut request = Request::builder();
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        let mut request = request.body(()).unwrap();
+        BearerToken::new("secret")
+            .validate(&mut request)
+            .map_err(|response| response.status())
+    }
+
+    #[test]
+    fn lets_through_the_token_only() {
+        assert_eq!(check(Some("Bearer secret")), Ok(()));
+        for wrong in [
+            None,
+            Some(""),
+            Some("secret"),
+            Some("Bearer "),
+            Some("Bearer secreT"),
+            Some("Bearer secret2"),
+            Some("Bearer secre"),
+            Some("Basic secret"),
+        ] {
+            assert_eq!(check(wrong), Err(StatusCode::UNAUTHORIZED), "{:?}", wrong);
+        }
+    }
+
+    #[test]
+    fn compares_every_byte() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"xbc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
@@ -0,0 +1,366 @@
+use ethers::types::{H160, H256};
+use jsonrpsee::core::{async_trait, RpcResult};
+use jsonrpsee::proc_macros::rpc;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::miner::{get_miner_pool, MinerReport};
+use crate::model::ledger_entry::LedgerEntry;
+use crate::model::pool_batch::BatchStatus;
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::schedule::force_seal_batch;
+use crate::service::admin;
+use crate::service::admin::{BundlerStatus, BundlingMode};
+use crate::service::paymaster;
+use crate::service::paymaster::PaymasterExposure;
+use crate::service::pool;
+use crate::service::pool::{CommitmentCheck, EntryPointReport};
+use crate::service::state_root;
+use crate::service::state_root::StateRoots;
+use crate::service::treasury;
+use crate::service::treasury::TreasuryReport;
+use crate::store::get_pool_store;
+use crate::upstream::cache::{CacheReport, RpcCache};
+use crate::upstream::{get_upstream, NodeHealth};
+
+// Served on `admin.host:admin.port` only, never on the public RPC port.
+// Every `admin_*` method is also `debug_bundler_*`.
+#[rpc(server, namespace = "admin")]
+pub trait AdminRpc {
+    #[method(name = "status", aliases = ["debug_bundler_status"])]
+    async fn status(&self) -> RpcResult<BundlerStatus>;
+
+    #[method(name = "dumpMempool", aliases = ["debug_bundler_dumpMempool"])]
+    async fn dump_mempool(&self, status: Option<TxStatus>) -> RpcResult<Vec<PoolTx>>;
+
+    #[method(name = "clearMempool", aliases = ["debug_bundler_clearMempool"])]
+    async fn clear_mempool(&self) -> RpcResult<usize>;
+
+    #[method(name = "sealBatch", aliases = ["debug_bundler_sealBatch"])]
+    async fn seal_batch(&self) -> RpcResult<Vec<H256>>;
+
+    #[method(name = "retryBatch", aliases = ["debug_bundler_retryBatch"])]
+    async fn retry_batch(&self, batch_hash: H256) -> RpcResult<BatchStatus>;
+
+    #[method(name = "cancelBatch", aliases = ["debug_bundler_cancelBatch"])]
+    async fn cancel_batch(&self, batch_hash: H256) -> RpcResult<usize>;
+
+    #[method(name = "verifyBatch", aliases = ["debug_bundler_verifyBatch"])]
+    async fn verify_batch(&self, batch_hash: H256) -> RpcResult<CommitmentCheck>;
+
+    #[method(name = "pauseIntake", aliases = ["debug_bundler_pauseIntake"])]
+    async fn pause_intake(&self) -> RpcResult<BundlerStatus>;
+
+    #[method(name = "resumeIntake", aliases = ["debug_bundler_resumeIntake"])]
+    async fn resume_intake(&self) -> RpcResult<BundlerStatus>;
+
+    #[method(name = "pauseSubmission", aliases = ["debug_bundler_pauseSubmission"])]
+    async fn pause_submission(&self) -> RpcResult<BundlerStatus>;
+
+    #[method(name = "resumeSubmission", aliases = ["debug_bundler_resumeSubmission"])]
+    async fn resume_submission(&self) -> RpcResult<BundlerStatus>;
+
+    #[method(name = "setBundlingMode", aliases = ["debug_bundler_setBundlingMode"])]
+    async fn set_bundling_mode(&self, mode: BundlingMode) -> RpcResult<BundlerStatus>;
+
+    #[method(name = "upstreams", aliases = ["debug_bundler_upstreams"])]
+    async fn upstreams(&self) -> RpcResult<Vec<NodeHealth>>;
+
+    #[method(name = "cacheStats", aliases = ["debug_bundler_cacheStats"])]
+    async fn cache_stats(&self) -> RpcResult<CacheReport>;
+
+    #[method(name = "miners", aliases = ["debug_bundler_miners"])]
+    async fn miners(&self) -> RpcResult<Vec<MinerReport>>;
+
+    #[method(name = "treasury", aliases = ["debug_bundler_treasury"])]
+    async fn treasury(&self) -> RpcResult<TreasuryReport>;
+
+    #[method(name = "paymasters", aliases = ["debug_bundler_paymasters"])]
+    async fn paymasters(&self) -> RpcResult<Vec<PaymasterExposure>>;
+
+    // Amounts are in ether, `entry_point` defaults to
+    // `bundler.entry_point_address`
+    #[method(name = "depositTo", aliases = ["debug_bundler_depositTo"])]
+    async fn deposit_to(&self, amount: String, entry_point: Option<H160>) -> RpcResult<H256>;
+
+    #[method(name = "addStake", aliases = ["debug_bundler_addStake"])]
+    async fn add_stake(
+        &self,
+        unstake_delay_sec: u32,
+        amount: String,
+        entry_point: Option<H160>,
+    ) -> RpcResult<H256>;
+
+    #[method(name = "unlockStake", aliases = ["debug_bundler_unlockStake"])]
+    async fn unlock_stake(&self, entry_point: Option<H160>) -> RpcResult<H256>;
+
+    #[method(name = "withdrawStake", aliases = ["debug_bundler_withdrawStake"])]
+    async fn withdraw_stake(&self, to: Option<H160>, entry_point: Option<H160>) -> RpcResult<H256>;
+
+    #[method(name = "withdrawTo", aliases = ["debug_bundler_withdrawTo"])]
+    async fn withdraw_to(
+        &self,
+        amount: String,
+        to: Option<H160>,
+        entry_point: Option<H160>,
+    ) -> RpcResult<H256>;
+
+    // Every EntryPoint with its verifier, read from the chain now
+    #[method(name = "entryPoints", aliases = ["debug_bundler_entryPoints"])]
+    async fn entry_points(&self) -> RpcResult<Vec<EntryPointReport>>;
+
+    // Read from the chain now, for every EntryPoint
+    #[method(name = "stateRoots", aliases = ["debug_bundler_stateRoots"])]
+    async fn state_roots(&self) -> RpcResult<Vec<StateRoots>>;
+
+    #[method(name = "transferStateRoot", aliases = ["debug_bundler_transferStateRoot"])]
+    async fn transfer_state_root(
+        &self,
+        old_state_root: H256,
+        new_state_root: H256,
+        entry_point: Option<H160>,
+    ) -> RpcResult<H256>;
+
+    // Sweeps now instead of waiting for the next `treasury.sweep_secs`
+    #[method(name = "sweep", aliases = ["debug_bundler_sweep"])]
+    async fn sweep(&self) -> RpcResult<Vec<LedgerEntry>>;
+
+    // Newest first, 100 if `limit` is not given
+    #[method(name = "ledger", aliases = ["debug_bundler_ledger"])]
+    async fn ledger(&self, limit: Option<usize>) -> RpcResult<Vec<LedgerEntry>>;
+}
+
+pub struct AdminRpcServerImpl {
+    pub config: Arc<Config>,
+    pub cache: Arc<RpcCache>,
+}
+
+#[async_trait]
+impl AdminRpcServer for AdminRpcServerImpl {
+    async fn status(&self) -> RpcResult<BundlerStatus> {
+        Ok(admin::bundler_status(self.config.chain_id()))
+    }
+
+    async fn dump_mempool(&self, status: Option<TxStatus>) -> RpcResult<Vec<PoolTx>> {
+        let result = admin::dump_mempool(&self.config, status).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn clear_mempool(&self) -> RpcResult<usize> {
+        let result = admin::clear_mempool(&self.config).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn seal_batch(&self) -> RpcResult<Vec<H256>> {
+        let result = force_seal_batch(&self.config).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn retry_batch(&self, batch_hash: H256) -> RpcResult<BatchStatus> {
+        // The submission task runs on with the server
+        let result = pool::retry_batch(&self.config, batch_hash).await;
+
+        match result {
+            Ok((status, _)) => Ok(status),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn cancel_batch(&self, batch_hash: H256) -> RpcResult<usize> {
+        let result = pool::cancel_batch(&self.config, batch_hash).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn verify_batch(&self, batch_hash: H256) -> RpcResult<CommitmentCheck> {
+        let result = pool::verify_batch_commitment(&self.config, batch_hash).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn pause_intake(&self) -> RpcResult<BundlerStatus> {
+        admin::set_intake_paused(self.config.chain_id(), true);
+        Ok(admin::bundler_status(self.config.chain_id()))
+    }
+
+    async fn resume_intake(&self) -> RpcResult<BundlerStatus> {
+        admin::set_intake_paused(self.config.chain_id(), false);
+        Ok(admin::bundler_status(self.config.chain_id()))
+    }
+
+    async fn pause_submission(&self) -> RpcResult<BundlerStatus> {
+        admin::set_submission_paused(self.config.chain_id(), true);
+        Ok(admin::bundler_status(self.config.chain_id()))
+    }
+
+    async fn resume_submission(&self) -> RpcResult<BundlerStatus> {
+        admin::set_submission_paused(self.config.chain_id(), false);
+        Ok(admin::bundler_status(self.config.chain_id()))
+    }
+
+    async fn set_bundling_mode(&self, mode: BundlingMode) -> RpcResult<BundlerStatus> {
+        admin::set_bundling_mode(self.config.chain_id(), mode);
+        Ok(admin::bundler_status(self.config.chain_id()))
+    }
+
+    async fn upstreams(&self) -> RpcResult<Vec<NodeHealth>> {
+        Ok(get_upstream(self.config.chain_id()).as_ref().health())
+    }
+
+    async fn cache_stats(&self) -> RpcResult<CacheReport> {
+        Ok(self.cache.report())
+    }
+
+    async fn miners(&self) -> RpcResult<Vec<MinerReport>> {
+        Ok(get_miner_pool(self.config.chain_id()).report())
+    }
+
+    async fn treasury(&self) -> RpcResult<TreasuryReport> {
+        let result = treasury::treasury_report(&self.config).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn paymasters(&self) -> RpcResult<Vec<PaymasterExposure>> {
+        let result = paymaster::paymaster_exposure(&self.config).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn deposit_to(&self, amount: String, entry_point: Option<H160>) -> RpcResult<H256> {
+        let result = treasury::deposit_to(&self.config, &amount, entry_point).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn add_stake(
+        &self,
+        unstake_delay_sec: u32,
+        amount: String,
+        entry_point: Option<H160>,
+    ) -> RpcResult<H256> {
+        let result =
+            treasury::add_stake(&self.config, unstake_delay_sec, &amount, entry_point).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn unlock_stake(&self, entry_point: Option<H160>) -> RpcResult<H256> {
+        let result = treasury::unlock_stake(&self.config, entry_point).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn withdraw_stake(&self, to: Option<H160>, entry_point: Option<H160>) -> RpcResult<H256> {
+        let result = treasury::withdraw_stake(&self.config, to, entry_point).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn withdraw_to(
+        &self,
+        amount: String,
+        to: Option<H160>,
+        entry_point: Option<H160>,
+    ) -> RpcResult<H256> {
+        let result = treasury::withdraw_to(&self.config, &amount, to, entry_point).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+    async fn entry_points(&self) -> RpcResult<Vec<EntryPointReport>> {
+        let result = pool::read_entry_point_verifiers(&self.config).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn state_roots(&self) -> RpcResult<Vec<StateRoots>> {
+        let result = state_root::follow_state_roots(&self.config).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn transfer_state_root(
+        &self,
+        old_state_root: H256,
+        new_state_root: H256,
+        entry_point: Option<H160>,
+    ) -> RpcResult<H256> {
+        let result = state_root::transfer_state_root(
+            &self.config,
+            old_state_root,
+            new_state_root,
+            entry_point,
+        )
+        .await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn sweep(&self) -> RpcResult<Vec<LedgerEntry>> {
+        let result = treasury::sweep_excess(&self.config).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn ledger(&self, limit: Option<usize>) -> RpcResult<Vec<LedgerEntry>> {
+        let result = get_pool_store(self.config.chain_id())
+            .list_ledger(limit.unwrap_or(100))
+            .await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+}
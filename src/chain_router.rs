@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http_body::{LengthLimitError, Limited};
+use hyper::header::CONTENT_TYPE;
+use hyper::http::HeaderValue;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use jsonrpsee::core::server::rpc_module::Methods;
+use jsonrpsee::core::TEN_MB_SIZE_BYTES;
+use serde_json::Value;
+use tower::{Layer, Service};
+
+use crate::config::parse_chain_id;
+
+const CHAIN_PATH_PREFIX: &str = "/rpc/";
+
+const PARSE_ERROR: &str =
+    r#"{"jsonrpc":"2.0","error":{"code":-32700,"message":"Parse error"},"id":null}"#;
+const INVALID_REQUEST: &str =
+    r#"{"jsonrpc":"2.0","error":{"code":-32600,"message":"Invalid request"},"id":null}"#;
+
+// Same shape as the errors jsonrpsee answers oversized requests with
+fn too_big(message: String) -> String {
+    format!(
+        r#"{{"jsonrpc":"2.0","error":{{"code":-32701,"message":"{}"}},"id":null}}"#,
+        message
+    )
+}
+
+/// Layer that applies [`ChainRouter`].
+#[derive(Clone)]
+pub struct ChainRouterLayer {
+    chains: Arc<HashMap<u64, Methods>>,
+    max_request_body_size: u32,
+    max_batch_size: usize,
+}
+
+impl ChainRouterLayer {
+    /// `chains` are the RPC methods of every chain, by chain id. The limits
+    /// default to jsonrpsee's: 10 MB bodies, batches of any size.
+    pub fn new(chains: HashMap<u64, Methods>) -> Self {
+        Self {
+            chains: Arc::new(chains),
+            max_request_body_size: TEN_MB_SIZE_BYTES,
+            max_batch_size: usize::MAX,
+        }
+    }
+
+    /// Larger bodies are answered with 413, like the inner server does.
+    pub fn max_request_body_size(mut self, size: u32) -> Self {
+        self.max_request_body_size = size;
+        self
+    }
+
+    /// Batches with more calls are answered with 413.
+    pub fn max_batch_size(mut self, size: usize) -> Self {
+        self.max_batch_size = size;
+        self
+    }
+}
+
+impl<S> Layer<S> for ChainRouterLayer {
+    type Service = ChainRouter<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ChainRouter {
+            inner,
+            chains: self.chains.clone(),
+            max_request_body_size: self.max_request_body_size,
+            max_batch_size: self.max_batch_size,
+        }
+    }
+}
+
+/// Serves `POST /rpc/{chainId}` with the RPC methods of that chain, the
+/// chain id in hex (`/rpc/0x5`) or decimal (`/rpc/5`). Every other path goes
+/// to the inner server, which serves the top-level chain as it always has.
+#[derive(Clone)]
+pub struct ChainRouter<S> {
+    inner: S,
+    chains: Arc<HashMap<u64, Methods>>,
+    max_request_body_size: u32,
+    max_batch_size: usize,
+}
+
+impl<S> Service<Request<Body>> for ChainRouter<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let chain_id = match req.uri().path().strip_prefix(CHAIN_PATH_PREFIX) {
+            Some(chain_id) => chain_id.trim_end_matches('/').to_string(),
+            None => {
+                let fut = self.inner.call(req);
+                return Box::pin(async move { fut.await.map_err(Into::into) });
+            }
+        };
+        let methods = parse_chain_id(&chain_id)
+            .ok()
+            .and_then(|chain_id| self.chains.get(&chain_id).cloned());
+        let max_request_body_size = self.max_request_body_size;
+        let max_batch_size = self.max_batch_size;
+
+        Box::pin(async move {
+            if req.method() != Method::POST {
+                return Ok(text_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "Only POST is allowed".to_string(),
+                ));
+            }
+            let methods = match methods {
+                Some(methods) => methods,
+                None => {
+                    return Ok(text_response(
+                        StatusCode::NOT_FOUND,
+                        format!("Unknown chain: {}", chain_id),
+                    ))
+                }
+            };
+
+            let body = Limited::new(req.into_body(), max_request_body_size as usize);
+            let body = match hyper::body::to_bytes(body).await {
+                Ok(body) => body,
+                Err(err) if err.is::<LengthLimitError>() => {
+                    return Ok(json_response(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        too_big(format!(
+                            "Request is too big, the limit is {} bytes",
+                            max_request_body_size
+                        )),
+                    ))
+                }
+                Err(err) => return Err(err),
+            };
+            let (status, result) = call_methods(&methods, &body, max_batch_size).await;
+
+            Ok(json_response(status, result))
+        })
+    }
+}
+
+fn text_response(status: StatusCode, text: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(text));
+    *response.status_mut() = status;
+    response
+}
+
+fn json_response(status: StatusCode, json: String) -> Response<Body> {
+    let mut response = text_response(status, json);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+async fn call_method(methods: &Methods, call: &Value) -> String {
+    match methods.raw_json_request(&call.to_string()).await {
+        Ok((response, _)) => response.result,
+        Err(_) => INVALID_REQUEST.to_string(),
+    }
+}
+
+// A single call or a batch of them, answered in order
+async fn call_methods(
+    methods: &Methods,
+    body: &[u8],
+    max_batch_size: usize,
+) -> (StatusCode, String) {
+    let result = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Array(calls)) if calls.is_empty() => INVALID_REQUEST.to_string(),
+        Ok(Value::Array(calls)) if calls.len() > max_batch_size => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                too_big(format!(
+                    "Batch is too big, the limit is {} calls",
+                    max_batch_size
+                )),
+            )
+        }
+        Ok(Value::Array(calls)) => {
+            let mut responses = vec![];
+            for call in calls.iter() {
+                responses.push(call_method(methods, call).await);
+            }
+            format!("[{}]", responses.join(","))
+        }
+        Ok(call) => call_method(methods, &call).await,
+        Err(_) => PARSE_ERROR.to_string(),
+    };
+    (StatusCode::OK, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use jsonrpsee::RpcModule;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    // Answers `eth_chainId` with `chain_id`
+    fn chain_methods(chain_id: u64) -> Methods {
+        let mut module = RpcModule::new(());
+        module
+            .register_method("eth_chainId", move |_, _| Ok(format!("{:#x}", chain_id)))
+            .unwrap();
+        module.into()
+    }
+
+    async fn route(method: Method, path: &str, body: &str) -> (StatusCode, String) {
+        let inner = service_fn(|_: Request<Body>| async {
+            Ok::<_, Infallible>(text_response(StatusCode::OK, "inner".to_string()))
+        });
+        let router = ChainRouterLayer::new(HashMap::from([
+            (5, chain_methods(5)),
+            (10, chain_methods(10)),
+        ]))
+        .max_request_body_size(MAX_BODY_SIZE)
+        .max_batch_size(2)
+        .layer(inner);
+
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    const MAX_BODY_SIZE: u32 = 1024;
+
+    const CHAIN_ID_CALL: &str = r#"{"jsonrpc":"2.0","method":"eth_chainId","params":[],"id":1}"#;
+
+    #[tokio::test]
+    async fn routes_by_chain_id() {
+        for path in ["/rpc/5", "/rpc/0x5", "/rpc/5/"] {
+            let (status, body) = route(Method::POST, path, CHAIN_ID_CALL).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, r#"{"jsonrpc":"2.0","result":"0x5","id":1}"#);
+        }
+        let (_, body) = route(Method::POST, "/rpc/0xa", CHAIN_ID_CALL).await;
+        assert_eq!(body, r#"{"jsonrpc":"2.0","result":"0xa","id":1}"#);
+    }
+
+    #[tokio::test]
+    async fn answers_batches_in_order() {
+        let body = format!(
+            "[{},{}]",
+            CHAIN_ID_CALL,
+            CHAIN_ID_CALL.replace(r#""id":1"#, r#""id":2"#)
+        );
+        let (_, body) = route(Method::POST, "/rpc/10", &body).await;
+        assert_eq!(
+            body,
+            r#"[{"jsonrpc":"2.0","result":"0xa","id":1},{"jsonrpc":"2.0","result":"0xa","id":2}]"#
+        );
+
+        let (_, body) = route(Method::POST, "/rpc/10", "[]").await;
+        assert_eq!(body, INVALID_REQUEST);
+        let (_, body) = route(Method::POST, "/rpc/10", "{").await;
+        assert_eq!(body, PARSE_ERROR);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_chains_and_other_methods() {
+        let (status, body) = route(Method::POST, "/rpc/1", CHAIN_ID_CALL).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "Unknown chain: 1");
+        let (status, _) = route(Method::POST, "/rpc/mainnet", CHAIN_ID_CALL).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = route(Method::GET, "/rpc/5", "").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_bodies_and_batches() {
+        let padded = format!(
+            "{}{}",
+            CHAIN_ID_CALL,
+            " ".repeat(MAX_BODY_SIZE as usize - CHAIN_ID_CALL.len())
+        );
+        let (status, _) = route(Method::POST, "/rpc/5", &padded).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = route(Method::POST, "/rpc/5", &format!("{} ", padded)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            body,
+            too_big("Request is too big, the limit is 1024 bytes".to_string())
+        );
+
+        let batch = format!("[{},{},{}]", CHAIN_ID_CALL, CHAIN_ID_CALL, CHAIN_ID_CALL);
+        let (status, body) = route(Method::POST, "/rpc/5", &batch).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            body,
+            too_big("Batch is too big, the limit is 2 calls".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn passes_other_paths_to_the_inner_server() {
+        for path in ["/", "/rpc", "/health"] {
+            let (status, body) = route(Method::POST, path, CHAIN_ID_CALL).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, "inner");
+        }
+    }
+}
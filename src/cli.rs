@@ -0,0 +1,268 @@
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use ethers::abi::AbiEncode;
+use ethers::signers::Signer;
+use ethers::types::H256;
+
+use crate::config::{parse_chain_id, Config};
+use crate::miner::init_miner_pool;
+use crate::model::pool_batch::BatchStatus;
+use crate::model::pool_tx::TxStatus;
+use crate::service::pool;
+use crate::signer::BundlerSigner;
+use crate::store::init_pool_store;
+use crate::store::migration::run_migrations;
+
+#[derive(Parser)]
+#[command(version, about = "zkProver ERC-4337 bundler")]
+pub struct Cli {
+    /// Config file, defaults to $BUNDLER_CONFIG, then bundler.toml
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+
+    /// Chain to work on (hex or decimal), defaults to the top-level one
+    #[arg(long, global = true, value_parser = parse_chain_id)]
+    pub chain_id: Option<u64>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start the RPC server and scheduled jobs (the default)
+    Serve,
+    /// Inspect and edit pool txs
+    #[command(subcommand)]
+    Pool(PoolCommand),
+    /// Manage batches
+    #[command(subcommand)]
+    Batch(BatchCommand),
+    /// Configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Miner keys
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Copy the MongoDB pool into the SQLite file at store.sqlite_path
+    #[cfg(feature = "embedded")]
+    MigrateMongoToSqlite,
+}
+
+#[derive(Subcommand)]
+pub enum PoolCommand {
+    /// List txs, oldest first
+    List {
+        /// Only txs in this status (name or number)
+        #[arg(long, value_parser = parse_tx_status)]
+        status: Option<TxStatus>,
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Print a tx
+    Show { tx_hash: H256 },
+    /// Remove a tx that no batch holds
+    Drop { tx_hash: H256 },
+}
+
+#[derive(Subcommand)]
+pub enum BatchCommand {
+    /// List batches, oldest first
+    List {
+        /// Only batches in this status (name or number)
+        #[arg(long, value_parser = parse_batch_status)]
+        status: Option<BatchStatus>,
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Print a batch
+    Show { batch_hash: H256 },
+    /// Resubmit a failed batch, or hand an unproven one to the provers again
+    Retry { batch_hash: H256 },
+    /// Cancel an unsubmitted batch and return its txs to the pool
+    Cancel { batch_hash: H256 },
+    /// Recompute a batch's commitment from its txs and check it and its proof
+    Verify { batch_hash: H256 },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Load and validate the configuration
+    Check,
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    /// Print the address of every miner account's signer
+    Address,
+}
+
+// Accepts the status number or its name, case-insensitive
+fn parse_status<T>(s: &str) -> Result<T, String>
+where
+    T: TryFrom<u8> + std::fmt::Debug,
+{
+    if let Ok(n) = s.parse::<u8>() {
+        return T::try_from(n).map_err(|_| format!("Unknown status: {}", s));
+    }
+    (0..=u8::MAX)
+        .filter_map(|n| T::try_from(n).ok())
+        .find(|status| format!("{:?}", status).eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("Unknown status: {}", s))
+}
+
+fn parse_tx_status(s: &str) -> Result<TxStatus, String> {
+    parse_status(s)
+}
+
+fn parse_batch_status(s: &str) -> Result<BatchStatus, String> {
+    parse_status(s)
+}
+
+async fn run_pool(config: &Config, command: PoolCommand) -> anyhow::Result<()> {
+    let store = init_pool_store(config.chain_id(), &config.store).await?;
+    run_migrations(store.as_ref()).await?;
+
+    match command {
+        PoolCommand::List { status, limit } => {
+            let txs = store.list_txs().await?;
+            for pt in txs
+                .iter()
+                .filter(|pt| status.is_none() || status == Some(pt.status))
+                .take(limit)
+            {
+                println!(
+                    "{} {:?} from={} entry_point={} created_at={}",
+                    pt.tx_hash.encode_hex(),
+                    pt.status,
+                    pt.tx_from.encode_hex(),
+                    pt.entry_point.encode_hex(),
+                    pt.created_at
+                );
+            }
+        }
+        PoolCommand::Show { tx_hash } => match store.find_tx(tx_hash).await? {
+            Some(pt) => println!("{}", serde_json::to_string_pretty(&pt)?),
+            None => anyhow::bail!("Tx not found: {}", tx_hash.encode_hex()),
+        },
+        PoolCommand::Drop { tx_hash } => {
+            pool::drop_tx(config, tx_hash).await?;
+            println!("Dropped {}", tx_hash.encode_hex());
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_batch(config: &Config, command: BatchCommand) -> anyhow::Result<()> {
+    let store = init_pool_store(config.chain_id(), &config.store).await?;
+    run_migrations(store.as_ref()).await?;
+
+    match command {
+        BatchCommand::List { status, limit } => {
+            let batches = store.list_batches().await?;
+            for pb in batches
+                .iter()
+                .filter(|pb| status.is_none() || status == Some(pb.status))
+                .take(limit)
+            {
+                println!(
+                    "{} {:?} txs={} entry_point={} miner={} created_at={}",
+                    pb.batch_hash.encode_hex(),
+                    pb.status,
+                    pb.tx_hash_list.len(),
+                    pb.entry_point.encode_hex(),
+                    pb.miner.encode_hex(),
+                    pb.created_at
+                );
+            }
+        }
+        BatchCommand::Show { batch_hash } => match store.find_batch(batch_hash).await? {
+            Some(pb) => println!("{}", serde_json::to_string_pretty(&pb)?),
+            None => anyhow::bail!("Batch not found: {}", batch_hash.encode_hex()),
+        },
+        BatchCommand::Retry { batch_hash } => {
+            // Resubmitting sends handleOps from a miner account
+            init_miner_pool(config.chain_id(), &config.bundler).await?;
+            let (mut status, submission) = pool::retry_batch(config, batch_hash).await?;
+            // Nothing else keeps this process up while it sends
+            if let Some(submission) = submission {
+                submission.await?;
+                status = store
+                    .find_batch(batch_hash)
+                    .await?
+                    .map_or(BatchStatus::Invalid, |pb| pb.status);
+            }
+            println!("Retried {}, now {:?}", batch_hash.encode_hex(), status);
+        }
+        BatchCommand::Cancel { batch_hash } => {
+            let released = pool::cancel_batch(config, batch_hash).await?;
+            println!(
+                "Cancelled {}, released {} txs",
+                batch_hash.encode_hex(),
+                released
+            );
+        }
+        BatchCommand::Verify { batch_hash } => {
+            let check = pool::verify_batch_commitment(config, batch_hash).await?;
+            println!("{}", serde_json::to_string_pretty(&check)?);
+            if !check.matches
+                || check.proof_matches == Some(false)
+                || check
+                    .contract_commitment
+                    .is_some_and(|c| c != check.recomputed)
+            {
+                anyhow::bail!("Commitment mismatch");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The config of `chain_id`, else of the top-level chain.
+pub fn select_chain(chains: &[Arc<Config>], chain_id: Option<u64>) -> anyhow::Result<Arc<Config>> {
+    match chain_id {
+        Some(chain_id) => chains
+            .iter()
+            .find(|chain| chain.chain_id() == chain_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Chain {} is not configured", chain_id)),
+        None => Ok(chains[0].clone()),
+    }
+}
+
+/// Runs every command except `serve`.
+pub async fn run(config: Arc<Config>, command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Pool(command) => run_pool(&config, command).await?,
+        Command::Batch(command) => run_batch(&config, command).await?,
+        // Loading already validated it
+        Command::Config(ConfigCommand::Check) => println!("Config OK"),
+        Command::Keys(KeysCommand::Address) => {
+            for miner in config.bundler.miner_accounts() {
+                let signer = BundlerSigner::from_config(&miner)?;
+                println!("{}", signer.address().encode_hex());
+                if let Err(err) = signer.check_address(miner.address).await {
+                    println!("warning: {}", err);
+                }
+            }
+        }
+        #[cfg(feature = "embedded")]
+        Command::MigrateMongoToSqlite => {
+            let counts = crate::store::migrate_mongo_to_sqlite(&config.store).await?;
+            println!(
+                "Migrated {} pool_tx, {} pool_batch, {} pool_tx_archive, {} pool_batch_archive, {} ledger",
+                counts.txs,
+                counts.batches,
+                counts.archived_txs,
+                counts.archived_batches,
+                counts.ledger
+            );
+        }
+    }
+
+    Ok(())
+}
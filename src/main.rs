@@ -2,48 +2,132 @@
 // 1. cargo install cargo-watch
 // 2. cargo watch -x run
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
+use clap::Parser;
 use dotenv::dotenv;
 use hyper::Method;
+use jsonrpsee::core::server::rpc_module::Methods;
 use jsonrpsee::server::{AllowHosts, ServerBuilder};
-use mongodb::bson::doc;
 use tower_http::cors::CorsLayer;
+use tower_http::validate_request::ValidateRequestHeaderLayer;
 
+use admin_rpc_server::{AdminRpcServer, AdminRpcServerImpl};
 use open_rpc_server::{OpenRpcServer, OpenRpcServerImpl};
 
-use crate::model::get_database;
-use crate::schedule::start_schedules;
-
+use crate::admin_auth::BearerToken;
+use crate::chain_router::ChainRouterLayer;
+use crate::cli::{Cli, Command};
+use crate::config::Config;
+use crate::miner::init_miner_pool;
+use crate::schedule::{
+    do_check_funds, do_check_upstreams, do_follow_state_roots, do_resume_submissions,
+    start_schedules,
+};
+use crate::service::pool::recover_pool;
+use crate::store::init_pool_store;
+use crate::store::migration::run_migrations;
+use crate::upstream::cache::RpcCache;
+use crate::upstream::init_upstream;
+
+mod admin_auth;
+mod admin_rpc_server;
+mod chain_router;
+mod cli;
+mod config;
+mod miner;
 mod model;
 mod open_rpc_server;
 mod schedule;
 mod service;
+mod signer;
+mod store;
+mod upstream;
+
+#[cfg(test)]
+mod test_util;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // warning: only dev. The formal environment uses real env
     dotenv().ok();
 
-    // Test db
-    get_database()
-        .await
-        .run_command(doc! {"ping": 1}, None)
-        .await?;
+    let cli = Cli::parse();
+
+    // Fail fast on a missing or invalid setting
+    let config = Config::load(cli.config.as_deref())?;
+    let chains: Vec<Arc<Config>> = config.chains().into_iter().map(Arc::new).collect();
+
+    // Shared by the RPC handlers and the jobs, connections are pooled
+    for chain in chains.iter() {
+        init_upstream(chain.chain_id(), &chain.network)?;
+    }
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(chains).await,
+        command => cli::run(cli::select_chain(&chains, cli.chain_id)?, command).await,
+    }
+}
 
-    start_schedules().await;
+// A chain's config with its RPC cache
+type Chain = (Arc<Config>, Arc<RpcCache>);
+
+async fn serve(configs: Vec<Arc<Config>>) -> anyhow::Result<()> {
+    for config in configs.iter() {
+        // Unlock the miner keys, and refuse to start with a wrong one
+        init_miner_pool(config.chain_id(), &config.bundler).await?;
+
+        // Connect store (and test db), then bring its schema up to date
+        let store = init_pool_store(config.chain_id(), &config.store).await?;
+        run_migrations(store.as_ref()).await?;
+
+        // Repair batches a crash left half-sealed
+        recover_pool(config).await?;
+
+        // Rank the upstream nodes, read the miner balances and the state
+        // roots before the first request
+        do_check_upstreams(config.clone()).await;
+        do_check_funds(config.clone()).await;
+        do_follow_state_roots(config.clone()).await;
+
+        // Send the proven batches the last run left
+        do_resume_submissions(config.clone()).await;
+
+        start_schedules(config.clone()).await;
+    }
 
     tracing_subscriber::FmtSubscriber::builder()
         // .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .try_init()
         .expect("setting default subscriber failed");
 
-    run_server().await?;
+    // Shared by both RPC servers, the admin one reports its stats
+    let mut chains: Vec<Chain> = vec![];
+    for config in configs {
+        let cache = Arc::new(RpcCache::new(config.chain_id(), &config.cache));
+        if config.cache.enabled {
+            tokio::spawn(cache.clone().watch_new_blocks());
+        }
+        chains.push((config, cache));
+    }
+
+    if chains[0].0.admin.enabled {
+        run_admin_server(&chains).await?;
+    }
+    run_server(&chains).await?;
 
     Ok(())
 }
 
-async fn run_server() -> anyhow::Result<SocketAddr> {
+// Applied to the top-level chain by jsonrpsee and to `/rpc/{chainId}` by
+// the router. jsonrpsee 0.16 has no batch limit, only the router has one.
+const MAX_REQUEST_BODY_SIZE: u32 = 10 * 1024 * 1024;
+const MAX_BATCH_SIZE: usize = 100;
+
+// The top-level chain is served on every path, each chain on `/rpc/{chainId}`
+async fn run_server(chains: &[Chain]) -> anyhow::Result<SocketAddr> {
     // Add a CORS middleware for handling HTTP requests.
     // This middleware does affect the response, including appropriate
     // headers to satisfy CORS. Because any origins are allowed, the
@@ -54,10 +138,25 @@ async fn run_server() -> anyhow::Result<SocketAddr> {
         // Allow requests from any origin
         .allow_origin(tower_http::cors::Any)
         .allow_headers([hyper::header::CONTENT_TYPE]);
-    let middleware = tower::ServiceBuilder::new().layer(cors);
-
-    let bundler_rpc_host = std::env::var("BUNDLER_RPC_HOST").unwrap_or(String::from("127.0.0.1"));
-    let bundler_rpc_port = std::env::var("BUNDLER_RPC_PORT").unwrap_or(String::from("4337"));
+    let routes: HashMap<u64, Methods> = chains
+        .iter()
+        .map(|(config, cache)| {
+            let rpc = OpenRpcServerImpl {
+                config: config.clone(),
+                cache: cache.clone(),
+            };
+            (config.chain_id(), rpc.into_rpc().into())
+        })
+        .collect();
+    let middleware = tower::ServiceBuilder::new().layer(cors).layer(
+        ChainRouterLayer::new(routes)
+            .max_request_body_size(MAX_REQUEST_BODY_SIZE)
+            .max_batch_size(MAX_BATCH_SIZE),
+    );
+
+    let (config, cache) = chains[0].clone();
+    let bundler_rpc_host = &config.bundler.rpc_host;
+    let bundler_rpc_port = config.bundler.rpc_port;
 
     // The RPC exposes the access control for filtering and the middleware for
     // modifying requests / responses. These features are independent of one another
@@ -65,12 +164,13 @@ async fn run_server() -> anyhow::Result<SocketAddr> {
     // In this example, we use both features.
     let server = ServerBuilder::default()
         .set_host_filtering(AllowHosts::Any)
+        .max_request_body_size(MAX_REQUEST_BODY_SIZE)
         .set_middleware(middleware)
         .build(format!("{}:{}", bundler_rpc_host, bundler_rpc_port).parse::<SocketAddr>()?)
         .await?;
 
     let addr = server.local_addr()?;
-    let handle = server.start(OpenRpcServerImpl.into_rpc())?;
+    let handle = server.start(OpenRpcServerImpl { config, cache }.into_rpc())?;
 
     println!("RpcServer started server on {}", addr);
 
@@ -78,3 +178,47 @@ async fn run_server() -> anyhow::Result<SocketAddr> {
 
     Ok(addr)
 }
+
+// Routed like the public RPC
+async fn run_admin_server(chains: &[Chain]) -> anyhow::Result<SocketAddr> {
+    let routes: HashMap<u64, Methods> = chains
+        .iter()
+        .map(|(config, cache)| {
+            let rpc = AdminRpcServerImpl {
+                config: config.clone(),
+                cache: cache.clone(),
+            };
+            (config.chain_id(), rpc.into_rpc().into())
+        })
+        .collect();
+    let (config, cache) = chains[0].clone();
+
+    // Without a token the admin RPC is only reachable from this host,
+    // `Config::validate` makes sure of that
+    let auth = config
+        .admin
+        .token
+        .as_deref()
+        .map(|token| ValidateRequestHeaderLayer::custom(BearerToken::new(token)));
+    let middleware = tower::ServiceBuilder::new().option_layer(auth).layer(
+        ChainRouterLayer::new(routes)
+            .max_request_body_size(MAX_REQUEST_BODY_SIZE)
+            .max_batch_size(MAX_BATCH_SIZE),
+    );
+
+    let server = ServerBuilder::default()
+        .max_request_body_size(MAX_REQUEST_BODY_SIZE)
+        .set_middleware(middleware)
+        .build(format!("{}:{}", config.admin.host, config.admin.port).parse::<SocketAddr>()?)
+        .await?;
+
+    let addr = server.local_addr()?;
+    let handle = server.start(AdminRpcServerImpl { config, cache }.into_rpc())?;
+
+    println!("AdminRpcServer started server on {}", addr);
+
+    // Runs until the process exits
+    tokio::spawn(handle.stopped());
+
+    Ok(addr)
+}
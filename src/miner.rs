@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use ethers::providers::{Middleware, Provider};
+use ethers::signers::Signer;
+use ethers::types::{BlockNumber, H160, U256};
+use ethers::utils::parse_ether;
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::config::BundlerConfig;
+use crate::signer::BundlerSigner;
+use crate::upstream::UpstreamClient;
+
+lazy_static! {
+    // By chain id
+    static ref MINER_POOLS: RwLock<HashMap<u64, Arc<MinerPool>>> = RwLock::new(HashMap::new());
+}
+
+/// How `MinerPool::assign` picks an account.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Assignment {
+    // Each account in turn
+    RoundRobin,
+    // The one with the fewest handleOps in flight, in turn among equals
+    LeastBusy,
+}
+
+/// One account handleOps is sent from.
+pub struct MinerAccount {
+    signer: BundlerSigner,
+    // Next nonce to send with, `None` until read from the chain. Held
+    // while sending so two batches never get the same one.
+    nonce: Mutex<Option<U256>>,
+    // Batches assigned and not done yet
+    in_flight: AtomicUsize,
+    // handleOps txs the nodes accepted since startup
+    submitted: AtomicU64,
+    // From the last balance check
+    balance: RwLock<Option<U256>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MinerReport {
+    pub address: H160,
+    pub balance: Option<U256>,
+    pub low_balance: bool,
+    pub nonce: Option<U256>,
+    pub in_flight: usize,
+    pub submitted: u64,
+}
+
+impl MinerAccount {
+    pub fn address(&self) -> H160 {
+        self.signer.address()
+    }
+
+    pub fn signer(&self) -> BundlerSigner {
+        self.signer.clone()
+    }
+
+    // Unknown until the first check, which is not low
+    fn is_low(&self, min_balance: U256) -> bool {
+        matches!(*self.balance.read().unwrap(), Some(balance) if balance < min_balance)
+    }
+
+    /// Runs `send` with the account's next nonce. `send` must return once a
+    /// node accepted the tx; after a failure the next send reads the nonce
+    /// from the chain again.
+    pub async fn send_with_nonce<F, Fut, T>(
+        &self,
+        provider: &Provider<UpstreamClient>,
+        send: F,
+    ) -> anyhow::Result<T>
+    where
+        F: FnOnce(U256) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let mut next = self.nonce.lock().await;
+        let nonce = match *next {
+            Some(nonce) => nonce,
+            None => {
+                provider
+                    .get_transaction_count(self.address(), Some(BlockNumber::Pending.into()))
+                    .await?
+            }
+        };
+
+        match send(nonce).await {
+            Ok(result) => {
+                *next = Some(nonce + 1);
+                self.submitted.fetch_add(1, Ordering::SeqCst);
+                Ok(result)
+            }
+            Err(err) => {
+                *next = None;
+                Err(err)
+            }
+        }
+    }
+}
+
+/// An account assigned to one batch. It counts as busy until dropped.
+pub struct MinerLease(Arc<MinerAccount>);
+
+impl Deref for MinerLease {
+    type Target = MinerAccount;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for MinerLease {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The miner accounts of one chain, `bundler.miner_address` then
+/// `bundler.miners`. Each has its own nonce, so batches proven together are
+/// submitted in parallel instead of queueing behind one nonce.
+pub struct MinerPool {
+    accounts: Vec<Arc<MinerAccount>>,
+    assignment: Assignment,
+    min_balance: U256,
+    next: AtomicUsize,
+}
+
+impl MinerPool {
+    /// Opens the signer of every account and checks it signs for the
+    /// account's address.
+    pub async fn new(config: &BundlerConfig) -> anyhow::Result<Self> {
+        let mut accounts = vec![];
+        for miner in config.miner_accounts() {
+            let signer = BundlerSigner::from_config(&miner)?;
+            signer.check_address(miner.address).await?;
+
+            accounts.push(Arc::new(MinerAccount {
+                signer,
+                nonce: Mutex::new(None),
+                in_flight: AtomicUsize::new(0),
+                submitted: AtomicU64::new(0),
+                balance: RwLock::new(None),
+            }));
+        }
+
+        let assignment = match config.miner_assignment.as_str() {
+            "least_busy" => Assignment::LeastBusy,
+            _ => Assignment::RoundRobin,
+        };
+
+        Ok(Self {
+            accounts,
+            assignment,
+            min_balance: parse_ether(&config.miner_min_balance)?,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Picks the account to submit a batch with. Accounts low on funds are
+    /// skipped unless every account is.
+    pub fn assign(&self) -> MinerLease {
+        let mut candidates: Vec<&Arc<MinerAccount>> = self
+            .accounts
+            .iter()
+            .filter(|account| !account.is_low(self.min_balance))
+            .collect();
+        if candidates.is_empty() {
+            println!("Every miner account is low on funds");
+            candidates = self.accounts.iter().collect();
+        }
+
+        let start = self.next.fetch_add(1, Ordering::SeqCst) % candidates.len();
+        let in_turn = (0..candidates.len()).map(|i| candidates[(start + i) % candidates.len()]);
+        let account = match self.assignment {
+            Assignment::RoundRobin => candidates[start],
+            Assignment::LeastBusy => in_turn
+                .min_by_key(|account| account.in_flight.load(Ordering::SeqCst))
+                .unwrap(),
+        };
+
+        account.in_flight.fetch_add(1, Ordering::SeqCst);
+        MinerLease(account.clone())
+    }
+
+    /// The account of `address`, for txs other than handleOps. It counts as
+    /// busy like an assigned one.
+    pub fn lease(&self, address: H160) -> Option<MinerLease> {
+        let account = self
+            .accounts
+            .iter()
+            .find(|account| account.address() == address)?;
+
+        account.in_flight.fetch_add(1, Ordering::SeqCst);
+        Some(MinerLease(account.clone()))
+    }
+
+    /// Like `lease`, only if nothing else holds the account, e.g. a batch
+    /// whose handleOps may still be spending from it.
+    pub fn lease_idle(&self, address: H160) -> Option<MinerLease> {
+        let account = self
+            .accounts
+            .iter()
+            .find(|account| account.address() == address)?;
+
+        account
+            .in_flight
+            .compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+        Some(MinerLease(account.clone()))
+    }
+
+    /// Reads the balance of every account, see `treasury::check_funds` for
+    /// the alerts. Idle accounts also re-read their nonce on the next send,
+    /// in case a tx was replaced or dropped.
+    pub async fn check_balances(&self, provider: &Provider<UpstreamClient>) {
+        let balances = join_all(
+            self.accounts
+                .iter()
+                .map(|account| provider.get_balance(account.address(), None)),
+        )
+        .await;
+
+        for (account, balance) in self.accounts.iter().zip(balances) {
+            match balance {
+                Ok(balance) => *account.balance.write().unwrap() = Some(balance),
+                Err(err) => println!("Balance of miner {:?} failed: {}", account.address(), err),
+            }
+
+            if account.in_flight.load(Ordering::SeqCst) == 0 {
+                if let Ok(mut nonce) = account.nonce.try_lock() {
+                    *nonce = None;
+                }
+            }
+        }
+    }
+
+    /// The state of every account, in config order.
+    pub fn report(&self) -> Vec<MinerReport> {
+        self.accounts
+            .iter()
+            .map(|account| MinerReport {
+                address: account.address(),
+                balance: *account.balance.read().unwrap(),
+                low_balance: account.is_low(self.min_balance),
+                nonce: account.nonce.try_lock().ok().and_then(|nonce| *nonce),
+                in_flight: account.in_flight.load(Ordering::SeqCst),
+                submitted: account.submitted.load(Ordering::SeqCst),
+            })
+            .collect()
+    }
+}
+
+/// Opens the miner accounts of `chain_id`, refusing one whose signer does
+/// not match its address. Called once per chain at startup.
+pub async fn init_miner_pool(chain_id: u64, config: &BundlerConfig) -> anyhow::Result<()> {
+    let pool = MinerPool::new(config).await?;
+
+    let mut pools = MINER_POOLS.write().unwrap();
+    if pools.contains_key(&chain_id) {
+        anyhow::bail!("Miners of chain {} are already initialized", chain_id);
+    }
+    pools.insert(chain_id, Arc::new(pool));
+
+    Ok(())
+}
+
+/// The miner accounts of `chain_id`.
+pub fn get_miner_pool(chain_id: u64) -> Arc<MinerPool> {
+    MINER_POOLS
+        .read()
+        .unwrap()
+        .get(&chain_id)
+        .expect("miner pool is not initialized")
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{miner, with_miners};
+
+    async fn miner_pool(count: u8, assignment: &str) -> MinerPool {
+        let mut config = BundlerConfig {
+            miner_assignment: assignment.to_string(),
+            ..BundlerConfig::default()
+        };
+        with_miners(&mut config, count);
+        MinerPool::new(&config).await.unwrap()
+    }
+
+    fn set_balance(pool: &MinerPool, n: u8, ether: &str) {
+        let account = pool
+            .accounts
+            .iter()
+            .find(|a| a.address() == miner(n).address);
+        *account.unwrap().balance.write().unwrap() = Some(parse_ether(ether).unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_key_of_another_address() {
+        let mut config = BundlerConfig::default();
+        with_miners(&mut config, 2);
+        config.miners[0].address = miner(3).address;
+        assert!(MinerPool::new(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn round_robin_takes_turns() {
+        let pool = miner_pool(3, "round_robin").await;
+        let assigned: Vec<H160> = (0..4).map(|_| pool.assign().address()).collect();
+        assert_eq!(assigned, [1, 2, 3, 1].map(|n| miner(n).address).to_vec());
+    }
+
+    #[tokio::test]
+    async fn least_busy_picks_the_fewest_in_flight() {
+        let pool = miner_pool(3, "least_busy").await;
+        let first = pool.assign();
+        let second = pool.assign();
+        assert_eq!(first.address(), miner(1).address);
+        assert_eq!(second.address(), miner(2).address);
+
+        // Both still held, the idle one goes next whoever's turn it is
+        let third = pool.assign();
+        assert_eq!(third.address(), miner(3).address);
+        drop(second);
+        assert_eq!(pool.assign().address(), miner(2).address);
+
+        let in_flight: Vec<usize> = pool.report().iter().map(|r| r.in_flight).collect();
+        assert_eq!(in_flight, vec![1, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn skips_accounts_low_on_funds() {
+        let pool = miner_pool(3, "round_robin").await;
+        set_balance(&pool, 1, "0.01");
+        set_balance(&pool, 2, "1");
+        // Account 3 wasn't read yet, which isn't low
+        for _ in 0..4 {
+            assert_ne!(pool.assign().address(), miner(1).address);
+        }
+        assert!(pool.report()[0].low_balance);
+        assert!(!pool.report()[2].low_balance);
+
+        // Unless every account is
+        set_balance(&pool, 2, "0.01");
+        set_balance(&pool, 3, "0");
+        let assigned: Vec<H160> = (0..3).map(|_| pool.assign().address()).collect();
+        assert!(assigned.contains(&miner(1).address));
+    }
+
+    #[tokio::test]
+    async fn leases_idle_accounts_only() {
+        let pool = miner_pool(2, "round_robin").await;
+        assert!(pool.lease(miner(3).address).is_none());
+
+        let assigned = pool.assign();
+        assert!(pool.lease_idle(assigned.address()).is_none());
+        let lease = pool.lease(assigned.address()).unwrap();
+        assert_eq!(pool.report()[0].in_flight, 2);
+
+        drop(assigned);
+        drop(lease);
+        let idle = pool.lease_idle(miner(1).address).unwrap();
+        assert_eq!(pool.report()[0].in_flight, 1);
+        drop(idle);
+        assert_eq!(pool.report()[0].in_flight, 0);
+    }
+}
@@ -0,0 +1,22 @@
+use ethers::types::{H160, H256, U256};
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+/// Stored as its number, never reorder or reuse a value.
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum LedgerKind {
+    Sweep = 1, // Hot-wallet excess moved to `treasury.sweep_to`
+}
+
+/// A move of funds out of a miner account made by the bundler itself.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct LedgerEntry {
+    pub tx_hash: H256,
+    pub kind: LedgerKind,
+    pub from: H160,
+    pub to: H160,
+    pub amount: U256,
+    pub created_at: DateTime,
+}
@@ -1,8 +1,37 @@
-use crate::model::get_database;
-use ethers::types::{Bytes, H256, U256};
+use ethers::types::{Bytes, H160, H256, U256};
 use mongodb::bson::DateTime;
-use mongodb::Collection;
 use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+/// Stored as its number, never reorder or reuse a value.
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum BatchStatus {
+    Invalid = 0,
+    Received = 1,   // Waiting for a prover
+    Pending = 2,    // Handed out to a prover
+    Submitting = 3, // Proof received, handleOps in flight
+    Succeed = 4,
+    Failed = 5,
+    Sealing = 6, // Claiming its txs, see `batch_received_txs`
+}
+
+impl TryFrom<u8> for BatchStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Invalid,
+            1 => Self::Received,
+            2 => Self::Pending,
+            3 => Self::Submitting,
+            4 => Self::Succeed,
+            5 => Self::Failed,
+            6 => Self::Sealing,
+            _ => anyhow::bail!("Unknown batch status: {}", value),
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct PoolBatch {
@@ -12,11 +41,39 @@ pub struct PoolBatch {
     pub zk_pub_inputs: Vec<U256>,
     pub send_tx_hash: H256,
     pub created_at: DateTime,
-    pub status: u8, // 0: invalid, 1: received, 2: pending, 3: submitting, 4: succeed, 5: failed
+    pub status: BatchStatus,
+    #[serde(default)]
+    pub entry_point: H160, // Every tx of the batch calls it
+    #[serde(default)]
+    pub miner: H160, // Account that sent handleOps, zero until submitted
+    // `batch_commitment` of its ops, the circuit's public input. Zero for
+    // batches sealed before it was recorded.
+    #[serde(default)]
+    pub commitment: U256,
+    // The root its proof must start from: its parent's post root, or the
+    // EntryPoint `_curStateRoot` when handed to a prover. Zero until then.
+    #[serde(default)]
+    pub pre_state_root: H256,
+    // The root its proof ends at, as the prover reported it. Zero until
+    // proven, or if the prover didn't say.
+    #[serde(default)]
+    pub post_state_root: H256,
+    // The batch of the same EntryPoint it follows, `None` if it starts
+    // from the on-chain root.
+    #[serde(default)]
+    pub parent: Option<H256>,
+    // Id of the circuit its proof must come from, empty for any
+    #[serde(default)]
+    pub circuit_id: String,
+    // When its status last changed, `None` if it never did or it was
+    // stored before this was recorded
+    #[serde(default)]
+    pub updated_at: Option<DateTime>,
 }
 
 impl PoolBatch {
-    pub async fn get_collection() -> Collection<Self> {
-        get_database().await.collection("pool_batch")
+    /// When its status last changed, or it was created.
+    pub fn updated_at(&self) -> DateTime {
+        self.updated_at.unwrap_or(self.created_at)
     }
 }
@@ -1,8 +1,35 @@
-use crate::model::get_database;
 use ethers::types::{Transaction, H160, H256};
 use mongodb::bson::DateTime;
-use mongodb::Collection;
 use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+
+/// Stored as its number, never reorder or reuse a value.
+#[derive(Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum TxStatus {
+    Invalid = 0,
+    Received = 1,
+    Pending = 2, // Claimed by a batch
+    Succeed = 3,
+    Failed = 4,
+    Evicted = 5, // Dropped from the pool before it was batched, see `evict_reason`
+}
+
+impl TryFrom<u8> for TxStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Invalid,
+            1 => Self::Received,
+            2 => Self::Pending,
+            3 => Self::Succeed,
+            4 => Self::Failed,
+            5 => Self::Evicted,
+            _ => anyhow::bail!("Unknown tx status: {}", value),
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct PoolTx {
@@ -10,11 +37,11 @@ pub struct PoolTx {
     pub tx_from: H160,
     pub tx_hash: H256,
     pub created_at: DateTime,
-    pub status: u8, // 0: invalid, 1: received, 2: pending, 3: succeed, 4: failed
-}
-
-impl PoolTx {
-    pub async fn get_collection() -> Collection<Self> {
-        get_database().await.collection("pool_tx")
-    }
+    pub status: TxStatus,
+    #[serde(default)]
+    pub batch_hash: Option<H256>, // Set when a batch claims the tx
+    #[serde(default)]
+    pub evict_reason: Option<String>,
+    #[serde(default)]
+    pub entry_point: H160, // The EntryPoint the tx calls
 }
@@ -1,20 +1,21 @@
-use ethers::providers::{Middleware, Provider};
+use ethers::providers::{Middleware, ProviderError};
 use ethers::types::{
     transaction::eip2718::TypedTransaction, Address, Block, BlockId, Bytes, NameOrAddress, H256,
     U256, U64,
 };
-use ethers::types::{BlockNumber, FeeHistory, Transaction, TransactionReceipt};
+use ethers::types::{BlockNumber, FeeHistory, Filter, Log, Transaction, TransactionReceipt};
 use jsonrpsee::core::{async_trait, RpcResult};
 use jsonrpsee::proc_macros::rpc;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::sync::Arc;
 
+use crate::config::Config;
+use crate::service::circuit::{circuit_registry, CircuitReport};
 use crate::service::pool;
 use crate::service::pool::GetPoolBatchResponse;
-
-fn get_http_provider() -> Provider<ethers::providers::Http> {
-    Provider::<ethers::providers::Http>::try_from(std::env::var("NETWORK_RPC_URL").unwrap())
-        .unwrap()
-}
+use crate::upstream::cache::{Lifetime, RpcCache};
+use crate::upstream::get_upstream;
 
 fn json_to_typed_transaction(json: Value) -> Result<TypedTransaction, serde_json::error::Error> {
     let mut clone_json = json.clone();
@@ -34,6 +35,34 @@ fn json_to_typed_transaction(json: Value) -> Result<TypedTransaction, serde_json
     serde_json::from_value(clone_json)
 }
 
+/// A block with tx hashes, or with full txs when asked for.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum BlockResponse {
+    Full(Block<Transaction>),
+    Hashes(Block<H256>),
+}
+
+async fn get_block(
+    chain_id: u64,
+    block_id: BlockId,
+    full_transactions: bool,
+) -> Result<Option<BlockResponse>, ProviderError> {
+    let provider = get_upstream(chain_id);
+
+    if full_transactions {
+        Ok(provider
+            .get_block_with_txs(block_id)
+            .await?
+            .map(BlockResponse::Full))
+    } else {
+        Ok(provider
+            .get_block(block_id)
+            .await?
+            .map(BlockResponse::Hashes))
+    }
+}
+
 #[rpc(server)]
 pub trait OpenRpc {
     #[method(name = "net_version")]
@@ -49,11 +78,22 @@ pub trait OpenRpc {
     #[method(name = "eth_blockNumber")]
     async fn eth_block_number(&self) -> RpcResult<U64>;
 
+    #[method(name = "web3_clientVersion")]
+    async fn web3_client_version(&self) -> RpcResult<String>;
+
     #[method(name = "eth_getBlockByNumber")]
     async fn eth_get_block_by_number(
         &self,
-        block_id: Option<BlockId>,
-    ) -> RpcResult<Option<Block<H256>>>;
+        block_number: Option<BlockNumber>,
+        full_transactions: Option<bool>,
+    ) -> RpcResult<Option<BlockResponse>>;
+
+    #[method(name = "eth_getBlockByHash")]
+    async fn eth_get_block_by_hash(
+        &self,
+        block_hash: H256,
+        full_transactions: Option<bool>,
+    ) -> RpcResult<Option<BlockResponse>>;
 
     #[method(name = "eth_getCode")]
     async fn eth_get_code(
@@ -62,9 +102,23 @@ pub trait OpenRpc {
         block_id: Option<BlockId>,
     ) -> RpcResult<Bytes>;
 
+    #[method(name = "eth_getStorageAt")]
+    async fn eth_get_storage_at(
+        &self,
+        address: NameOrAddress,
+        slot: U256,
+        block_id: Option<BlockId>,
+    ) -> RpcResult<H256>;
+
+    #[method(name = "eth_getLogs")]
+    async fn eth_get_logs(&self, filter: Filter) -> RpcResult<Vec<Log>>;
+
     #[method(name = "eth_gasPrice")]
     async fn eth_gas_price(&self) -> RpcResult<U256>;
 
+    #[method(name = "eth_maxPriorityFeePerGas")]
+    async fn eth_max_priority_fee_per_gas(&self) -> RpcResult<U256>;
+
     #[method(name = "eth_feeHistory")]
     async fn eth_fee_history(
         &self,
@@ -86,9 +140,18 @@ pub trait OpenRpc {
         block: Option<BlockId>,
     ) -> RpcResult<U256>;
 
+    #[method(name = "eth_supportedEntryPoints")]
+    async fn eth_supported_entry_points(&self) -> RpcResult<Vec<Address>>;
+
     #[method(name = "eth_sendRawTransaction")]
     async fn eth_send_raw_transaction(&self, tx: Bytes) -> RpcResult<H256>;
 
+    #[method(name = "eth_getTransactionByHash")]
+    async fn eth_get_transaction_by_hash(
+        &self,
+        transaction_hash: H256,
+    ) -> RpcResult<Option<Transaction>>;
+
     #[method(name = "eth_getTransactionReceipt")]
     async fn eth_get_transaction_receipt(
         &self,
@@ -96,7 +159,10 @@ pub trait OpenRpc {
     ) -> RpcResult<Option<TransactionReceipt>>;
 
     #[method(name = "zkp_getPoolBatch")]
-    async fn zkp_get_pool_batch(&self) -> RpcResult<Option<GetPoolBatchResponse>>;
+    async fn zkp_get_pool_batch(
+        &self,
+        circuit_ids: Option<Vec<String>>,
+    ) -> RpcResult<Option<GetPoolBatchResponse>>;
 
     #[method(name = "zkp_sendProofAndPublicInput")]
     async fn zkp_send_proof_and_public_inputs(
@@ -104,19 +170,27 @@ pub trait OpenRpc {
         batch_hash: H256,
         zk_proof: Bytes,
         zk_pub_inputs: Vec<U256>,
+        post_state_root: Option<H256>,
+        circuit_id: Option<String>,
     ) -> RpcResult<U64>;
+
+    #[method(name = "zkp_getCircuits")]
+    async fn zkp_get_circuits(&self) -> RpcResult<Vec<CircuitReport>>;
 }
 
-pub struct OpenRpcServerImpl;
+pub struct OpenRpcServerImpl {
+    pub config: Arc<Config>,
+    pub cache: Arc<RpcCache>,
+}
 
 #[async_trait]
 impl OpenRpcServer for OpenRpcServerImpl {
     async fn net_version(&self) -> RpcResult<String> {
-        Ok(std::env::var("BUNDLER_CHAINID").unwrap())
+        Ok(self.config.bundler.chain_id.clone())
     }
 
     async fn eth_chainid(&self) -> RpcResult<String> {
-        Ok(std::env::var("BUNDLER_CHAINID").unwrap())
+        Ok(format!("{:#x}", self.config.chain_id()))
     }
 
     async fn eth_get_balance(
@@ -124,7 +198,7 @@ impl OpenRpcServer for OpenRpcServerImpl {
         address: Address,
         block_id: Option<BlockId>,
     ) -> RpcResult<U256> {
-        let provider = get_http_provider();
+        let provider = get_upstream(self.config.chain_id());
         let result = provider.get_balance(address, block_id).await;
 
         match result {
@@ -134,8 +208,16 @@ impl OpenRpcServer for OpenRpcServerImpl {
     }
 
     async fn eth_block_number(&self) -> RpcResult<U64> {
-        let provider = get_http_provider();
-        let result = provider.get_block_number().await;
+        let provider = get_upstream(self.config.chain_id());
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_blockNumber",
+                (),
+                Lifetime::Block,
+                provider.get_block_number(),
+            )
+            .await;
 
         match result {
             Ok(result) => Ok(result),
@@ -143,12 +225,51 @@ impl OpenRpcServer for OpenRpcServerImpl {
         }
     }
 
+    async fn web3_client_version(&self) -> RpcResult<String> {
+        Ok(format!("zkprover-bundler/v{}", env!("CARGO_PKG_VERSION")))
+    }
+
     async fn eth_get_block_by_number(
         &self,
-        block_id: Option<BlockId>,
-    ) -> RpcResult<Option<Block<H256>>> {
-        let provider = get_http_provider();
-        let result = provider.get_block(block_id.unwrap()).await;
+        block_number: Option<BlockNumber>,
+        full_transactions: Option<bool>,
+    ) -> RpcResult<Option<BlockResponse>> {
+        let block_id = BlockId::Number(block_number.unwrap_or_default());
+        let full_transactions = full_transactions.unwrap_or(false);
+
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_getBlockByNumber",
+                (block_id, full_transactions),
+                self.cache.lifetime_of(Some(block_id)),
+                get_block(self.config.chain_id(), block_id, full_transactions),
+            )
+            .await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn eth_get_block_by_hash(
+        &self,
+        block_hash: H256,
+        full_transactions: Option<bool>,
+    ) -> RpcResult<Option<BlockResponse>> {
+        let block_id = BlockId::Hash(block_hash);
+        let full_transactions = full_transactions.unwrap_or(false);
+
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_getBlockByHash",
+                (block_id, full_transactions),
+                Lifetime::Forever,
+                get_block(self.config.chain_id(), block_id, full_transactions),
+            )
+            .await;
 
         match result {
             Ok(result) => Ok(result),
@@ -157,8 +278,53 @@ impl OpenRpcServer for OpenRpcServerImpl {
     }
 
     async fn eth_get_code(&self, at: NameOrAddress, block_id: Option<BlockId>) -> RpcResult<Bytes> {
-        let provider = get_http_provider();
-        let result = provider.get_code(at, block_id).await;
+        let provider = get_upstream(self.config.chain_id());
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_getCode",
+                (&at, block_id),
+                self.cache.lifetime_of(block_id),
+                provider.get_code(at.clone(), block_id),
+            )
+            .await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn eth_get_storage_at(
+        &self,
+        address: NameOrAddress,
+        slot: U256,
+        block_id: Option<BlockId>,
+    ) -> RpcResult<H256> {
+        let provider = get_upstream(self.config.chain_id());
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_getStorageAt",
+                (&address, slot, block_id),
+                self.cache.lifetime_of(block_id),
+                provider.get_storage_at(
+                    address.clone(),
+                    H256::from(<[u8; 32]>::from(slot)),
+                    block_id,
+                ),
+            )
+            .await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn eth_get_logs(&self, filter: Filter) -> RpcResult<Vec<Log>> {
+        let provider = get_upstream(self.config.chain_id());
+        let result = provider.get_logs(&filter).await;
 
         match result {
             Ok(result) => Ok(result),
@@ -167,8 +333,34 @@ impl OpenRpcServer for OpenRpcServerImpl {
     }
 
     async fn eth_gas_price(&self) -> RpcResult<U256> {
-        let provider = get_http_provider();
-        let result = provider.get_gas_price().await;
+        let provider = get_upstream(self.config.chain_id());
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_gasPrice",
+                (),
+                Lifetime::Block,
+                provider.get_gas_price(),
+            )
+            .await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn eth_max_priority_fee_per_gas(&self) -> RpcResult<U256> {
+        let provider = get_upstream(self.config.chain_id());
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_maxPriorityFeePerGas",
+                (),
+                Lifetime::Block,
+                provider.request::<_, U256>("eth_maxPriorityFeePerGas", ()),
+            )
+            .await;
 
         match result {
             Ok(result) => Ok(result),
@@ -182,9 +374,15 @@ impl OpenRpcServer for OpenRpcServerImpl {
         last_block: BlockNumber,
         reward_percentiles: Vec<f64>,
     ) -> RpcResult<FeeHistory> {
-        let provider = get_http_provider();
-        let result = provider
-            .fee_history(block_count, last_block, &reward_percentiles)
+        let provider = get_upstream(self.config.chain_id());
+        let result = self
+            .cache
+            .get_or_fetch(
+                "eth_feeHistory",
+                (block_count, last_block, &reward_percentiles),
+                self.cache.lifetime_of(Some(last_block.into())),
+                provider.fee_history(block_count, last_block, &reward_percentiles),
+            )
             .await;
 
         match result {
@@ -197,7 +395,7 @@ impl OpenRpcServer for OpenRpcServerImpl {
         // Data cleaning
         let ttx = json_to_typed_transaction(tx)?;
 
-        let provider = get_http_provider();
+        let provider = get_upstream(self.config.chain_id());
         let result = provider.call(&ttx, block).await;
 
         match result {
@@ -206,13 +404,13 @@ impl OpenRpcServer for OpenRpcServerImpl {
         }
     }
 
-    async fn eth_estimate_gas(&self, tx: Value, block: Option<BlockId>) -> RpcResult<U256> {
+    async fn eth_estimate_gas(&self, _tx: Value, _block: Option<BlockId>) -> RpcResult<U256> {
         Ok(U256::from(21000))
 
         // Data cleaning
         // let ttx = json_to_typed_transaction(tx)?;
         //
-        // let provider = get_http_provider();
+        // let provider = get_upstream(self.config.chain_id());
         // let result = provider.estimate_gas(&ttx, block).await;
         //
         // match result {
@@ -226,8 +424,18 @@ impl OpenRpcServer for OpenRpcServerImpl {
         from: NameOrAddress,
         block: Option<BlockId>,
     ) -> RpcResult<U256> {
-        let provider = get_http_provider();
-        let result = provider.get_transaction_count(from, block).await;
+        let provider = get_upstream(self.config.chain_id());
+        let result = provider.get_transaction_count(from.clone(), block).await;
+
+        // Pooled txs are not on chain yet, not even as pending
+        let result = match (result, from, block) {
+            (
+                Ok(nonce),
+                NameOrAddress::Address(address),
+                Some(BlockId::Number(BlockNumber::Pending)),
+            ) => pool::get_pending_nonce(&self.config, address, nonce).await,
+            (result, _, _) => result.map_err(anyhow::Error::from),
+        };
 
         match result {
             Ok(result) => Ok(result),
@@ -235,9 +443,36 @@ impl OpenRpcServer for OpenRpcServerImpl {
         }
     }
 
+    async fn eth_supported_entry_points(&self) -> RpcResult<Vec<Address>> {
+        Ok(self.config.bundler.entry_points())
+    }
+
     async fn eth_send_raw_transaction(&self, raw_tx: Bytes) -> RpcResult<H256> {
-        let tx: Transaction = ethers::utils::rlp::decode(&raw_tx).unwrap();
-        let result = pool::receive_tx(tx).await;
+        let tx: Transaction = ethers::utils::rlp::decode(&raw_tx).map_err(|error| {
+            jsonrpsee::core::Error::Custom(format!("Invalid raw transaction: {}", error))
+        })?;
+        let result = pool::receive_tx(self.config.clone(), tx).await;
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+
+    async fn eth_get_transaction_by_hash(
+        &self,
+        transaction_hash: H256,
+    ) -> RpcResult<Option<Transaction>> {
+        // Txs we pooled never reach the chain under their own hash
+        let pooled = pool::find_pool_tx(&self.config, transaction_hash).await;
+        match pooled {
+            Ok(Some(pool_tx)) => return Ok(Some(pool_tx.tx)),
+            Ok(None) => {}
+            Err(error) => return Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+
+        let provider = get_upstream(self.config.chain_id());
+        let result = provider.get_transaction(transaction_hash).await;
 
         match result {
             Ok(result) => Ok(result),
@@ -249,8 +484,15 @@ impl OpenRpcServer for OpenRpcServerImpl {
         &self,
         transaction_hash: H256,
     ) -> RpcResult<Option<TransactionReceipt>> {
-        let provider = get_http_provider();
-        let result = provider.get_transaction_receipt(transaction_hash).await;
+        // A pooled tx lands inside our handleOps tx, answer with its receipt
+        let result = match pool::find_pool_tx(&self.config, transaction_hash).await {
+            Ok(Some(pool_tx)) => pool::get_pool_tx_receipt(&self.config, &pool_tx).await,
+            Ok(None) => get_upstream(self.config.chain_id())
+                .get_transaction_receipt(transaction_hash)
+                .await
+                .map_err(anyhow::Error::from),
+            Err(error) => Err(error),
+        };
 
         match result {
             Ok(result) => Ok(result),
@@ -258,8 +500,11 @@ impl OpenRpcServer for OpenRpcServerImpl {
         }
     }
 
-    async fn zkp_get_pool_batch(&self) -> RpcResult<Option<GetPoolBatchResponse>> {
-        let result = pool::get_pool_batch().await;
+    async fn zkp_get_pool_batch(
+        &self,
+        circuit_ids: Option<Vec<String>>,
+    ) -> RpcResult<Option<GetPoolBatchResponse>> {
+        let result = pool::get_pool_batch(&self.config, circuit_ids).await;
 
         match result {
             Ok(result) => Ok(result),
@@ -272,13 +517,196 @@ impl OpenRpcServer for OpenRpcServerImpl {
         batch_hash: H256,
         zk_proof: Bytes,
         zk_pub_inputs: Vec<U256>,
+        post_state_root: Option<H256>,
+        circuit_id: Option<String>,
     ) -> RpcResult<U64> {
-        let result =
-            pool::receive_proof_and_public_input(batch_hash, zk_proof, zk_pub_inputs).await;
+        let result = pool::receive_proof_and_public_input(
+            self.config.clone(),
+            batch_hash,
+            zk_proof,
+            zk_pub_inputs,
+            post_state_root,
+            circuit_id,
+        )
+        .await;
 
         match result {
             Ok(result) => Ok(result),
             Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
         }
     }
+
+    async fn zkp_get_circuits(&self) -> RpcResult<Vec<CircuitReport>> {
+        let result = circuit_registry(&self.config);
+
+        match result {
+            Ok(result) => Ok(result),
+            Err(error) => Err(jsonrpsee::core::Error::Custom(error.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::H160;
+    use jsonrpsee::RpcModule;
+    use mongodb::bson::DateTime;
+
+    use super::*;
+    use crate::model::pool_batch::BatchStatus;
+    use crate::store::PoolStore;
+    use crate::test_util::{pool_batch, pool_tx, start_node, test_config, TestNode};
+
+    async fn open_rpc(
+        chain_id: u64,
+    ) -> (
+        RpcModule<OpenRpcServerImpl>,
+        Arc<TestNode>,
+        Arc<dyn PoolStore>,
+    ) {
+        let (config, store) = test_config(chain_id).await;
+        let node = start_node(chain_id).await;
+        let rpc = OpenRpcServerImpl {
+            cache: Arc::new(RpcCache::new(chain_id, &config.cache)),
+            config: Arc::new(config),
+        };
+        (rpc.into_rpc(), node, store)
+    }
+
+    #[tokio::test]
+    async fn answers_pooled_txs_from_the_pool() {
+        let (rpc, node, store) = open_rpc(5001).await;
+        let pt = pool_tx(1, 0, DateTime::now());
+        store.insert_tx(pt.clone()).await.unwrap();
+
+        let tx: Option<Transaction> = rpc
+            .call("eth_getTransactionByHash", [pt.tx_hash])
+            .await
+            .unwrap();
+        assert_eq!(tx, Some(pt.tx));
+        assert!(node.calls("eth_getTransactionByHash").is_empty());
+
+        let unknown = H256::repeat_byte(9);
+        let tx: Option<Transaction> = rpc
+            .call("eth_getTransactionByHash", [unknown])
+            .await
+            .unwrap();
+        assert_eq!(tx, None);
+        assert_eq!(
+            node.calls("eth_getTransactionByHash"),
+            vec![json!([unknown])]
+        );
+    }
+
+    #[tokio::test]
+    async fn fills_in_optional_params() {
+        let (rpc, node, _) = open_rpc(5002).await;
+
+        let block: Option<BlockResponse> = rpc
+            .call("eth_getBlockByNumber", Vec::<Value>::new())
+            .await
+            .unwrap();
+        assert!(block.is_none());
+        let block: Option<BlockResponse> = rpc.call("eth_getBlockByNumber", ["0x1"]).await.unwrap();
+        assert!(block.is_none());
+        assert_eq!(
+            node.calls("eth_getBlockByNumber"),
+            vec![json!(["latest", false]), json!(["0x1", false])]
+        );
+
+        let block_hash = H256::repeat_byte(1);
+        let _: Option<BlockResponse> = rpc
+            .call("eth_getBlockByHash", (block_hash, true))
+            .await
+            .unwrap();
+        let _: Option<BlockResponse> = rpc.call("eth_getBlockByHash", [block_hash]).await.unwrap();
+        assert_eq!(
+            node.calls("eth_getBlockByHash"),
+            vec![json!([block_hash, true]), json!([block_hash, false])]
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_nonce_counts_pooled_txs() {
+        let (rpc, node, store) = open_rpc(5003).await;
+        node.answer("eth_getTransactionCount", U256::from(3));
+        let sender = H160::repeat_byte(1);
+        for nonce in [3, 4] {
+            let mut pt = pool_tx(1, nonce, DateTime::now());
+            pt.tx.nonce = U256::from(nonce);
+            store.insert_tx(pt).await.unwrap();
+        }
+
+        let nonce: U256 = rpc
+            .call("eth_getTransactionCount", (sender, "pending"))
+            .await
+            .unwrap();
+        assert_eq!(nonce, U256::from(5));
+        let nonce: U256 = rpc
+            .call("eth_getTransactionCount", (H160::repeat_byte(2), "pending"))
+            .await
+            .unwrap();
+        assert_eq!(nonce, U256::from(3));
+
+        // Without a block, or at any other, only the chain counts
+        let nonce: U256 = rpc.call("eth_getTransactionCount", [sender]).await.unwrap();
+        assert_eq!(nonce, U256::from(3));
+        let nonce: U256 = rpc
+            .call("eth_getTransactionCount", (sender, "latest"))
+            .await
+            .unwrap();
+        assert_eq!(nonce, U256::from(3));
+        assert_eq!(
+            node.calls("eth_getTransactionCount")[2],
+            json!([sender, "latest"])
+        );
+    }
+
+    #[tokio::test]
+    async fn receipts_of_pooled_txs_are_their_handle_ops_receipts() {
+        let (rpc, node, store) = open_rpc(5004).await;
+        let send_tx_hash = H256::repeat_byte(0x55);
+        node.answer(
+            "eth_getTransactionReceipt",
+            TransactionReceipt {
+                transaction_hash: send_tx_hash,
+                ..TransactionReceipt::default()
+            },
+        );
+        let mut unsent = pool_batch(1, BatchStatus::Pending, None);
+        let mut sent = pool_batch(2, BatchStatus::Submitting, None);
+        sent.send_tx_hash = send_tx_hash;
+        let loose = pool_tx(1, 0, DateTime::now());
+        let mut in_unsent = pool_tx(2, 0, DateTime::now());
+        in_unsent.batch_hash = Some(unsent.batch_hash);
+        let mut in_sent = pool_tx(3, 0, DateTime::now());
+        in_sent.batch_hash = Some(sent.batch_hash);
+        unsent.tx_hash_list = vec![in_unsent.tx_hash];
+        sent.tx_hash_list = vec![in_sent.tx_hash];
+        for pt in [loose.clone(), in_unsent.clone(), in_sent.clone()] {
+            store.insert_tx(pt).await.unwrap();
+        }
+        for pb in [unsent, sent] {
+            store.insert_batch(pb).await.unwrap();
+        }
+
+        for pt in [loose, in_unsent] {
+            let receipt: Option<TransactionReceipt> = rpc
+                .call("eth_getTransactionReceipt", [pt.tx_hash])
+                .await
+                .unwrap();
+            assert_eq!(receipt, None);
+        }
+        assert!(node.calls("eth_getTransactionReceipt").is_empty());
+
+        let receipt: Option<TransactionReceipt> = rpc
+            .call("eth_getTransactionReceipt", [in_sent.tx_hash])
+            .await
+            .unwrap();
+        assert_eq!(receipt.unwrap().transaction_hash, send_tx_hash);
+        assert_eq!(
+            node.calls("eth_getTransactionReceipt"),
+            vec![json!([send_tx_hash])]
+        );
+    }
 }
@@ -1,31 +1,264 @@
-use crate::service::pool::batch_received_txs;
+use crate::config::Config;
+use crate::service::admin::{bundling_mode, BundlingMode};
+use crate::service::archive::archive_finished;
+use crate::service::pool::{
+    batch_received_txs, evict_expired_txs, recover_pool, resume_submissions, seal_batches,
+};
+use crate::service::state_root::follow_state_roots;
+use crate::service::treasury::{check_funds, sweep_excess};
+use crate::upstream::get_upstream;
+use ethers::types::H256;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio_cron_scheduler::{Job, JobScheduler};
 use tracing::error;
 
 lazy_static::lazy_static! {
-    static ref DO_BATCH_RECEIVED_TXS_LOCK: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+    // By chain id
+    static ref DO_BATCH_RECEIVED_TXS_LOCKS: std::sync::Mutex<HashMap<u64, Arc<Mutex<()>>>> =
+        std::sync::Mutex::new(HashMap::new());
 }
 
-pub async fn do_batch_received_txs() {
-    let _lock_guard = DO_BATCH_RECEIVED_TXS_LOCK.lock().await;
+fn batch_received_txs_lock(chain_id: u64) -> Arc<Mutex<()>> {
+    DO_BATCH_RECEIVED_TXS_LOCKS
+        .lock()
+        .unwrap()
+        .entry(chain_id)
+        .or_default()
+        .clone()
+}
+
+pub async fn do_batch_received_txs(config: Arc<Config>) {
+    if bundling_mode(config.chain_id()) == BundlingMode::Manual {
+        return;
+    }
+
+    let lock = batch_received_txs_lock(config.chain_id());
+    let _lock_guard = lock.lock().await;
+
+    let result = batch_received_txs(&config).await;
+    if let Err(err) = result {
+        error!(
+            "Job batch_received_txs failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
+    }
+}
+
+/// Seals the received txs of every EntryPoint now, whatever their number and
+/// the bundling mode. Serialized with the batch_received_txs job.
+pub async fn force_seal_batch(config: &Config) -> anyhow::Result<Vec<H256>, anyhow::Error> {
+    let lock = batch_received_txs_lock(config.chain_id());
+    let _lock_guard = lock.lock().await;
+
+    seal_batches(config, 1).await
+}
+
+pub async fn do_evict_expired_txs(config: Arc<Config>) {
+    let result = evict_expired_txs(&config).await;
+    if let Err(err) = result {
+        error!(
+            "Job evict_expired_txs failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
+    }
+}
+
+pub async fn do_archive_finished(config: Arc<Config>) {
+    let result = archive_finished(&config).await;
+    if let Err(err) = result {
+        error!(
+            "Job archive_finished failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
+    }
+}
+
+pub async fn do_recover_pool(config: Arc<Config>) {
+    let result = recover_pool(&config).await;
+    if let Err(err) = result {
+        error!(
+            "Job recover_pool failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
+    }
+}
+
+pub async fn do_resume_submissions(config: Arc<Config>) {
+    let result = resume_submissions(config.clone()).await;
+    if let Err(err) = result {
+        error!(
+            "Job resume_submissions failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
+    }
+}
+
+pub async fn do_check_upstreams(config: Arc<Config>) {
+    get_upstream(config.chain_id())
+        .as_ref()
+        .check_health()
+        .await;
+}
+
+pub async fn do_check_funds(config: Arc<Config>) {
+    let result = check_funds(&config).await;
+    if let Err(err) = result {
+        error!(
+            "Job check_funds failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
+    }
+}
+
+pub async fn do_follow_state_roots(config: Arc<Config>) {
+    let result = follow_state_roots(&config).await;
+    if let Err(err) = result {
+        error!(
+            "Job follow_state_roots failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
+    }
+}
 
-    let result = batch_received_txs().await;
-    match result {
-        Err(err) => error!("Job batch_received_txs failed: {}", err),
-        _ => {}
+pub async fn do_sweep_excess(config: Arc<Config>) {
+    let result = sweep_excess(&config).await;
+    if let Err(err) = result {
+        error!(
+            "Job sweep_excess failed on chain {}: {}",
+            config.chain_id(),
+            err
+        );
     }
 }
 
-pub async fn start_schedules() {
+/// Starts the jobs of one chain.
+pub async fn start_schedules(config: Arc<Config>) {
     let sched = JobScheduler::new().await.unwrap();
 
     // Job batch_received_txs
+    let job_config = config.clone();
     sched
-        .add(Job::new_async("1/5 * * * * *", |_, _| Box::pin(do_batch_received_txs())).unwrap())
+        .add(
+            Job::new_async("1/5 * * * * *", move |_, _| {
+                Box::pin(do_batch_received_txs(job_config.clone()))
+            })
+            .unwrap(),
+        )
         .await
         .unwrap();
 
+    // Job evict_expired_txs, every minute
+    let job_config = config.clone();
+    sched
+        .add(
+            Job::new_async("0 * * * * *", move |_, _| {
+                Box::pin(do_evict_expired_txs(job_config.clone()))
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Job recover_pool, every minute, for batches another bundler on the
+    // same store left half-sealed
+    let job_config = config.clone();
+    sched
+        .add(
+            Job::new_async("15 * * * * *", move |_, _| {
+                Box::pin(do_recover_pool(job_config.clone()))
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Job resume_submissions, every minute
+    let job_config = config.clone();
+    sched
+        .add(
+            Job::new_async("30 * * * * *", move |_, _| {
+                Box::pin(do_resume_submissions(job_config.clone()))
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Job archive_finished, every hour
+    let job_config = config.clone();
+    sched
+        .add(
+            Job::new_async("0 0 * * * *", move |_, _| {
+                Box::pin(do_archive_finished(job_config.clone()))
+            })
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Job check_upstreams, every network.health_check_secs
+    let job_config = config.clone();
+    sched
+        .add(
+            Job::new_repeated_async(
+                Duration::from_secs(config.network.health_check_secs),
+                move |_, _| Box::pin(do_check_upstreams(job_config.clone())),
+            )
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Job check_funds, every bundler.miner_balance_check_secs
+    let job_config = config.clone();
+    sched
+        .add(
+            Job::new_repeated_async(
+                Duration::from_secs(config.bundler.miner_balance_check_secs),
+                move |_, _| Box::pin(do_check_funds(job_config.clone())),
+            )
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Job follow_state_roots, every bundler.state_root_poll_secs
+    let job_config = config.clone();
+    sched
+        .add(
+            Job::new_repeated_async(
+                Duration::from_secs(config.bundler.state_root_poll_secs),
+                move |_, _| Box::pin(do_follow_state_roots(job_config.clone())),
+            )
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // Job sweep_excess, every treasury.sweep_secs when treasury.sweep_to is set
+    if !config.treasury.sweep_to.is_zero() {
+        let job_config = config.clone();
+        sched
+            .add(
+                Job::new_repeated_async(
+                    Duration::from_secs(config.treasury.sweep_secs),
+                    move |_, _| Box::pin(do_sweep_excess(job_config.clone())),
+                )
+                .unwrap(),
+            )
+            .await
+            .unwrap();
+    }
+
     sched.start().await.unwrap();
 }
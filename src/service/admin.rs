@@ -0,0 +1,150 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use ethers::types::H256;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::store::get_pool_store;
+
+// Runtime switches set through the admin RPC, per chain. They are not
+// persisted, a restart brings the bundler back to intake on, submission on,
+// auto.
+lazy_static! {
+    // Chain ids
+    static ref INTAKE_PAUSED: RwLock<HashSet<u64>> = RwLock::new(HashSet::new());
+    static ref MANUAL_BUNDLING: RwLock<HashSet<u64>> = RwLock::new(HashSet::new());
+    // By chain id, a watch so held submissions can wait for the resume
+    static ref SUBMISSION_PAUSED: RwLock<HashMap<u64, watch::Sender<bool>>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum BundlingMode {
+    // Seal a batch whenever `batch_tx_total` txs are received
+    Auto,
+    // Seal only on `admin_sealBatch`
+    Manual,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BundlerStatus {
+    pub intake_paused: bool,
+    pub submission_paused: bool,
+    pub bundling_mode: BundlingMode,
+}
+
+pub fn bundler_status(chain_id: u64) -> BundlerStatus {
+    BundlerStatus {
+        intake_paused: is_intake_paused(chain_id),
+        submission_paused: is_submission_paused(chain_id),
+        bundling_mode: bundling_mode(chain_id),
+    }
+}
+
+// Adds or removes `chain_id`
+fn set_flag(flags: &RwLock<HashSet<u64>>, chain_id: u64, on: bool) {
+    let mut flags = flags.write().unwrap();
+    if on {
+        flags.insert(chain_id);
+    } else {
+        flags.remove(&chain_id);
+    }
+}
+
+pub fn is_intake_paused(chain_id: u64) -> bool {
+    INTAKE_PAUSED.read().unwrap().contains(&chain_id)
+}
+
+pub fn set_intake_paused(chain_id: u64, paused: bool) {
+    set_flag(&INTAKE_PAUSED, chain_id, paused);
+    println!(
+        "Intake {} on chain {}",
+        if paused { "paused" } else { "resumed" },
+        chain_id
+    );
+}
+
+fn submission_paused(chain_id: u64) -> watch::Sender<bool> {
+    SUBMISSION_PAUSED
+        .write()
+        .unwrap()
+        .entry(chain_id)
+        .or_insert_with(|| watch::channel(false).0)
+        .clone()
+}
+
+pub fn is_submission_paused(chain_id: u64) -> bool {
+    *submission_paused(chain_id).borrow()
+}
+
+pub fn set_submission_paused(chain_id: u64, paused: bool) {
+    submission_paused(chain_id).send_replace(paused);
+    println!(
+        "Submission {} on chain {}",
+        if paused { "paused" } else { "resumed" },
+        chain_id
+    );
+}
+
+/// Returns once submission on `chain_id` is not paused.
+pub async fn wait_submission_resumed(chain_id: u64) {
+    let mut paused = submission_paused(chain_id).subscribe();
+    while *paused.borrow_and_update() {
+        // The sender lives in a static, so this never errors
+        let _ = paused.changed().await;
+    }
+}
+
+pub fn bundling_mode(chain_id: u64) -> BundlingMode {
+    if MANUAL_BUNDLING.read().unwrap().contains(&chain_id) {
+        BundlingMode::Manual
+    } else {
+        BundlingMode::Auto
+    }
+}
+
+pub fn set_bundling_mode(chain_id: u64, mode: BundlingMode) {
+    set_flag(&MANUAL_BUNDLING, chain_id, mode == BundlingMode::Manual);
+    println!("Bundling mode on chain {}: {:?}", chain_id, mode);
+}
+
+/// Live pool txs, oldest first, with `status` only if given.
+pub async fn dump_mempool(
+    config: &Config,
+    status: Option<TxStatus>,
+) -> anyhow::Result<Vec<PoolTx>, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    let txs = store.list_txs().await?;
+    Ok(txs
+        .into_iter()
+        .filter(|pt| status.is_none() || status == Some(pt.status))
+        .collect())
+}
+
+/// Evicts every received tx. Txs already in a batch stay with it. Returns
+/// how many were evicted.
+pub async fn clear_mempool(config: &Config) -> anyhow::Result<usize, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    let received: Vec<H256> = store
+        .list_txs()
+        .await?
+        .iter()
+        .filter(|pt| pt.status == TxStatus::Received)
+        .map(|pt| pt.tx_hash)
+        .collect();
+    if received.is_empty() {
+        return Ok(0);
+    }
+
+    let evicted = store.evict_txs(&received, "cleared by admin").await?;
+    println!("Cleared mempool, evicted {} txs", evicted);
+
+    Ok(evicted)
+}
@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use ethers::abi::AbiEncode;
+use ethers::types::H256;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use mongodb::bson::DateTime;
+use serde::Serialize;
+use tokio::task;
+
+use crate::config::Config;
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::store::get_pool_store;
+
+// Batches archived per run, the job picks up the rest next time
+const ARCHIVE_BATCH_LIMIT: usize = 256;
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ArchiveRecord<'a> {
+    Batch {
+        batch: &'a PoolBatch,
+        txs: &'a [PoolTx],
+    },
+    Tx {
+        tx: &'a PoolTx,
+    },
+}
+
+// Written next to `path` and renamed over it, so a crash never leaves a
+// partial file behind
+fn write_jsonl_gz(path: PathBuf, lines: Vec<String>) -> anyhow::Result<(), anyhow::Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut encoder = GzEncoder::new(File::create(&tmp_path)?, Compression::default());
+    for line in lines {
+        encoder.write_all(line.as_bytes())?;
+        encoder.write_all(b"\n")?;
+    }
+    // Only drop records from the store once they are on disk
+    encoder.finish()?.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Moves batches finished (succeed/failed) more than
+/// `pool.archive_after_secs` ago, and evicted txs older than that, out of
+/// the live pool.
+///
+/// They go to the `*_archive` collections, or, when
+/// `pool.archive_export_dir` is set, to a `batch-<hash>.jsonl.gz` or
+/// `tx-<hash>.jsonl.gz` file each there. A run interrupted before it
+/// deletes them rewrites the same files. Returns the number of batches
+/// and txs archived.
+pub async fn archive_finished(config: &Config) -> anyhow::Result<usize, anyhow::Error> {
+    let bundler_archive_after_secs = config.pool.archive_after_secs;
+    let export_dir = config.pool.archive_export_dir.clone();
+
+    let store = get_pool_store(config.chain_id());
+    let now = DateTime::now().timestamp_millis();
+    let before = DateTime::from_millis(now - bundler_archive_after_secs * 1000);
+
+    let batches = store
+        .find_batches_updated_before(
+            &[BatchStatus::Succeed, BatchStatus::Failed],
+            before,
+            ARCHIVE_BATCH_LIMIT,
+        )
+        .await?;
+    let evicted_txs = store
+        .find_txs_created_before(TxStatus::Evicted, before, ARCHIVE_BATCH_LIMIT)
+        .await?;
+    let evicted_hashes: Vec<H256> = evicted_txs.iter().map(|pt| pt.tx_hash).collect();
+
+    if batches.is_empty() && evicted_txs.is_empty() {
+        return Ok(0);
+    }
+
+    match export_dir {
+        Some(dir) => {
+            let dir = PathBuf::from(dir);
+            let mut files = vec![];
+            for pb in batches.iter() {
+                let txs = store.find_txs(&pb.tx_hash_list).await?;
+                let line = serde_json::to_string(&ArchiveRecord::Batch {
+                    batch: pb,
+                    txs: &txs,
+                })?;
+                let name = format!("batch-{}.jsonl.gz", pb.batch_hash.encode_hex());
+                files.push((dir.join(name), vec![line]));
+            }
+            for pt in evicted_txs.iter() {
+                let line = serde_json::to_string(&ArchiveRecord::Tx { tx: pt })?;
+                let name = format!("tx-{}.jsonl.gz", pt.tx_hash.encode_hex());
+                files.push((dir.join(name), vec![line]));
+            }
+
+            println!("Export archive: {} files to {}", files.len(), dir.display());
+            task::spawn_blocking(move || {
+                files
+                    .into_iter()
+                    .try_for_each(|(path, lines)| write_jsonl_gz(path, lines))
+            })
+            .await??;
+
+            for pb in batches.iter() {
+                store.delete_txs(&pb.tx_hash_list).await?;
+                store.delete_batch(pb.batch_hash).await?;
+            }
+            store.delete_txs(&evicted_hashes).await?;
+        }
+        None => {
+            for pb in batches.iter() {
+                store.archive_batch(pb.batch_hash).await?;
+            }
+            store.archive_txs(&evicted_hashes).await?;
+        }
+    }
+
+    println!(
+        "Archived {} batches, {} evicted txs",
+        batches.len(),
+        evicted_txs.len()
+    );
+
+    Ok(batches.len() + evicted_txs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::test_util::{pool_batch, pool_tx, test_config};
+
+    fn minutes_ago(minutes: i64) -> DateTime {
+        DateTime::from_millis(DateTime::now().timestamp_millis() - minutes * 60_000)
+    }
+
+    // Finished long ago, finished just now, and an evicted tx
+    async fn insert_finished(config: &Config) -> (PoolBatch, PoolBatch, PoolTx) {
+        let store = get_pool_store(config.chain_id());
+
+        let mut old_tx = pool_tx(1, 0, minutes_ago(10));
+        old_tx.status = TxStatus::Succeed;
+        let mut old = pool_batch(1, BatchStatus::Succeed, None);
+        old.created_at = minutes_ago(10);
+        old.updated_at = Some(minutes_ago(5));
+        old.tx_hash_list = vec![old_tx.tx_hash];
+
+        // Created long ago, but only just failed
+        let mut recent = pool_batch(2, BatchStatus::Failed, None);
+        recent.created_at = minutes_ago(10);
+        recent.updated_at = Some(DateTime::now());
+
+        let mut evicted = pool_tx(2, 0, minutes_ago(5));
+        evicted.status = TxStatus::Evicted;
+
+        store.insert_tx(old_tx).await.unwrap();
+        store.insert_tx(evicted.clone()).await.unwrap();
+        store.insert_batch(old.clone()).await.unwrap();
+        store.insert_batch(recent.clone()).await.unwrap();
+        (old, recent, evicted)
+    }
+
+    #[tokio::test]
+    async fn archive_finished_picks_batches_by_when_they_finished() {
+        let (mut config, store) = test_config(4001).await;
+        config.pool.archive_after_secs = 60;
+        config.pool.archive_export_dir = None;
+        let (old, recent, evicted) = insert_finished(&config).await;
+
+        assert_eq!(archive_finished(&config).await.unwrap(), 2);
+
+        assert!(store.find_batch(old.batch_hash).await.unwrap().is_none());
+        assert!(store.find_tx(old.tx_hash_list[0]).await.unwrap().is_none());
+        assert!(store.find_tx(evicted.tx_hash).await.unwrap().is_none());
+        assert!(store.find_batch(recent.batch_hash).await.unwrap().is_some());
+
+        assert_eq!(archive_finished(&config).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn archive_finished_exports_a_file_per_record() {
+        let dir = std::env::temp_dir().join(format!("bundler-archive-{}", std::process::id()));
+        let (mut config, store) = test_config(4002).await;
+        config.pool.archive_after_secs = 60;
+        config.pool.archive_export_dir = Some(dir.display().to_string());
+        let (old, recent, evicted) = insert_finished(&config).await;
+        let old_txs = store.find_txs(&old.tx_hash_list).await.unwrap();
+
+        assert_eq!(archive_finished(&config).await.unwrap(), 2);
+        assert!(store.find_batch(old.batch_hash).await.unwrap().is_none());
+        assert!(store.find_batch(recent.batch_hash).await.unwrap().is_some());
+
+        // A run interrupted before it deleted them exports them again
+        store.insert_batch(old.clone()).await.unwrap();
+        for pt in old_txs.into_iter().chain([evicted.clone()]) {
+            store.insert_tx(pt).await.unwrap();
+        }
+        assert_eq!(archive_finished(&config).await.unwrap(), 2);
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                format!("batch-{}.jsonl.gz", old.batch_hash.encode_hex()),
+                format!("tx-{}.jsonl.gz", evicted.tx_hash.encode_hex()),
+            ]
+        );
+
+        let mut lines = String::new();
+        GzDecoder::new(File::open(dir.join(&names[0])).unwrap())
+            .read_to_string(&mut lines)
+            .unwrap();
+        let records: Vec<serde_json::Value> = lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["kind"], "batch");
+        assert_eq!(records[0]["txs"].as_array().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+use ethers::abi::AbiEncode;
+use ethers::types::{H160, H256};
+use mongodb::bson::DateTime;
+
+use crate::config::Config;
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::service::state_root::{current_state_root, refresh_state_roots};
+use crate::store::get_pool_store;
+
+// Batches of an EntryPoint form a chain: each one's proof starts from the
+// root its parent's proof ends at. A child can be proven as soon as its
+// parent's post root is known, but is only submitted once the parent is
+// on chain.
+
+/// Whether a batch in `status` is done with its chain, so its children no
+/// longer wait on it.
+pub fn is_settled(status: BatchStatus) -> bool {
+    !matches!(
+        status,
+        BatchStatus::Sealing
+            | BatchStatus::Received
+            | BatchStatus::Pending
+            | BatchStatus::Submitting
+    )
+}
+
+// Batches of `entry_point` not settled yet, oldest first
+async fn open_batches(
+    config: &Config,
+    entry_point: H160,
+) -> anyhow::Result<Vec<PoolBatch>, anyhow::Error> {
+    Ok(get_pool_store(config.chain_id())
+        .find_batches_created_before(
+            &[
+                BatchStatus::Sealing,
+                BatchStatus::Received,
+                BatchStatus::Pending,
+                BatchStatus::Submitting,
+            ],
+            DateTime::MAX,
+            config.pool.max_txs,
+        )
+        .await?
+        .into_iter()
+        .filter(|pb| pb.entry_point == entry_point)
+        .collect())
+}
+
+/// The batch a new batch of `entry_point` follows: the newest one not
+/// settled yet, `None` if it starts from the on-chain root.
+pub async fn chain_tip(
+    config: &Config,
+    entry_point: H160,
+) -> anyhow::Result<Option<H256>, anyhow::Error> {
+    Ok(open_batches(config, entry_point)
+        .await?
+        .last()
+        .map(|pb| pb.batch_hash))
+}
+
+// The parent of `pb` while it is not settled
+async fn open_parent(
+    config: &Config,
+    pb: &PoolBatch,
+) -> anyhow::Result<Option<PoolBatch>, anyhow::Error> {
+    let parent = match pb.parent {
+        Some(parent) => get_pool_store(config.chain_id()).find_batch(parent).await?,
+        None => None,
+    };
+    Ok(parent.filter(|parent| !is_settled(parent.status)))
+}
+
+/// Whether the parent of `pb` is settled, so `pb` may be submitted.
+pub async fn parent_landed(config: &Config, pb: &PoolBatch) -> anyhow::Result<bool, anyhow::Error> {
+    Ok(open_parent(config, pb).await?.is_none())
+}
+
+/// The root a proof of `pb` must start from: the post root of its parent
+/// while the parent is not on chain, else `cur_state_root`. `None` while
+/// the parent's post root is unknown.
+pub async fn chained_pre_state_root(
+    config: &Config,
+    pb: &PoolBatch,
+    cur_state_root: H256,
+) -> anyhow::Result<Option<H256>, anyhow::Error> {
+    match open_parent(config, pb).await? {
+        Some(parent) => Ok(Some(parent.post_state_root).filter(|root| !root.is_zero())),
+        None => Ok(Some(cur_state_root)),
+    }
+}
+
+/// `chained_pre_state_root` against the EntryPoint's current root.
+pub async fn expected_pre_state_root(
+    config: &Config,
+    pb: &PoolBatch,
+) -> anyhow::Result<Option<H256>, anyhow::Error> {
+    let cur_state_root = match current_state_root(config.chain_id(), pb.entry_point) {
+        Some(root) => root,
+        None => {
+            refresh_state_roots(config, pb.entry_point)
+                .await?
+                .cur_state_root
+        }
+    };
+    chained_pre_state_root(config, pb, cur_state_root).await
+}
+
+/// The oldest received batch whose pre root is known, with that root. With
+/// `circuit_ids`, only batches of those circuits or of any.
+pub async fn next_provable(
+    config: &Config,
+    circuit_ids: Option<&[String]>,
+) -> anyhow::Result<Option<(PoolBatch, H256)>, anyhow::Error> {
+    let received = get_pool_store(config.chain_id())
+        .find_batches_created_before(&[BatchStatus::Received], DateTime::MAX, config.pool.max_txs)
+        .await?;
+    for pb in received {
+        if let Some(circuit_ids) = circuit_ids {
+            if !pb.circuit_id.is_empty() && !circuit_ids.contains(&pb.circuit_id) {
+                continue;
+            }
+        }
+        if let Some(root) = expected_pre_state_root(config, &pb).await? {
+            return Ok(Some((pb, root)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The proven child of `pb` waiting for it to land, if any.
+pub async fn ready_child(
+    config: &Config,
+    pb: &PoolBatch,
+) -> anyhow::Result<Option<H256>, anyhow::Error> {
+    Ok(open_batches(config, pb.entry_point)
+        .await?
+        .iter()
+        .find(|child| {
+            child.parent == Some(pb.batch_hash)
+                && child.status == BatchStatus::Submitting
+                && child.zk_proof.is_some()
+        })
+        .map(|child| child.batch_hash))
+}
+
+/// Hands `pb` out to the provers again, with the batches following it:
+/// their proofs start from roots that won't exist. Returns their hashes.
+pub async fn requeue_chain(
+    config: &Config,
+    pb: &PoolBatch,
+) -> anyhow::Result<Vec<H256>, anyhow::Error> {
+    requeue(config, pb, true).await
+}
+
+/// Like `requeue_chain`, for the batches following `pb` only, once `pb`
+/// failed or was cancelled.
+pub async fn requeue_children(
+    config: &Config,
+    pb: &PoolBatch,
+) -> anyhow::Result<Vec<H256>, anyhow::Error> {
+    requeue(config, pb, false).await
+}
+
+async fn requeue(
+    config: &Config,
+    pb: &PoolBatch,
+    with_pb: bool,
+) -> anyhow::Result<Vec<H256>, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+    let open = open_batches(config, pb.entry_point).await?;
+
+    let mut requeued = vec![];
+    let mut stale = vec![pb.batch_hash];
+    while let Some(batch_hash) = stale.pop() {
+        if with_pb || batch_hash != pb.batch_hash {
+            store
+                .update_batch_state_roots(batch_hash, H256::zero(), H256::zero())
+                .await?;
+            store
+                .update_batch_status(batch_hash, BatchStatus::Received)
+                .await?;
+            requeued.push(batch_hash);
+        }
+        // Sealing ones become received by themselves
+        stale.extend(
+            open.iter()
+                .filter(|child| {
+                    child.parent == Some(batch_hash) && child.status != BatchStatus::Sealing
+                })
+                .map(|child| child.batch_hash),
+        );
+    }
+
+    if !requeued.is_empty() {
+        println!(
+            "Batches handed out again after {}: {}",
+            pb.batch_hash.encode_hex(),
+            requeued
+                .iter()
+                .map(|batch_hash| batch_hash.encode_hex())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(requeued)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Bytes;
+
+    use crate::test_util::{pool_batch, test_config, ENTRY_POINT};
+
+    // Batches of `ENTRY_POINT` in `statuses`, each following the one before
+    async fn insert_chain(config: &Config, statuses: &[BatchStatus]) -> Vec<PoolBatch> {
+        let store = get_pool_store(config.chain_id());
+        let mut chain: Vec<PoolBatch> = vec![];
+        for (i, status) in statuses.iter().enumerate() {
+            let parent = chain.last().map(|pb| pb.batch_hash);
+            let mut pb = pool_batch(i as u8 + 1, *status, parent);
+            pb.created_at = DateTime::from_millis(pb.created_at.timestamp_millis() + i as i64);
+            pb.pre_state_root = H256::repeat_byte(0x10 + i as u8);
+            pb.post_state_root = H256::repeat_byte(0x11 + i as u8);
+            store.insert_batch(pb.clone()).await.unwrap();
+            chain.push(pb);
+        }
+        chain
+    }
+
+    #[tokio::test]
+    async fn chain_tip_is_the_newest_open_batch() {
+        let (config, store) = test_config(2001).await;
+        assert_eq!(chain_tip(&config, ENTRY_POINT).await.unwrap(), None);
+
+        let chain = insert_chain(&config, &[BatchStatus::Pending, BatchStatus::Received]).await;
+        let mut other = pool_batch(9, BatchStatus::Received, None);
+        other.entry_point = H160::repeat_byte(0x99);
+        store.insert_batch(other).await.unwrap();
+        assert_eq!(
+            chain_tip(&config, ENTRY_POINT).await.unwrap(),
+            Some(chain[1].batch_hash)
+        );
+
+        store
+            .update_batch_status(chain[1].batch_hash, BatchStatus::Succeed)
+            .await
+            .unwrap();
+        assert_eq!(
+            chain_tip(&config, ENTRY_POINT).await.unwrap(),
+            Some(chain[0].batch_hash)
+        );
+    }
+
+    #[tokio::test]
+    async fn pre_state_root_follows_the_open_parent() {
+        let (config, store) = test_config(2002).await;
+        let chain = insert_chain(&config, &[BatchStatus::Submitting, BatchStatus::Received]).await;
+        let cur_state_root = H256::repeat_byte(0xaa);
+
+        assert!(!parent_landed(&config, &chain[1]).await.unwrap());
+        assert_eq!(
+            chained_pre_state_root(&config, &chain[1], cur_state_root)
+                .await
+                .unwrap(),
+            Some(chain[0].post_state_root)
+        );
+        assert_eq!(
+            chained_pre_state_root(&config, &chain[0], cur_state_root)
+                .await
+                .unwrap(),
+            Some(cur_state_root)
+        );
+
+        // Unknown until the parent is proven
+        store
+            .update_batch_state_roots(chain[0].batch_hash, H256::zero(), H256::zero())
+            .await
+            .unwrap();
+        assert_eq!(
+            chained_pre_state_root(&config, &chain[1], cur_state_root)
+                .await
+                .unwrap(),
+            None
+        );
+
+        store
+            .update_batch_status(chain[0].batch_hash, BatchStatus::Succeed)
+            .await
+            .unwrap();
+        assert!(parent_landed(&config, &chain[1]).await.unwrap());
+        assert_eq!(
+            chained_pre_state_root(&config, &chain[1], cur_state_root)
+                .await
+                .unwrap(),
+            Some(cur_state_root)
+        );
+    }
+
+    #[tokio::test]
+    async fn ready_child_needs_a_proof() {
+        let (config, store) = test_config(2003).await;
+        let chain =
+            insert_chain(&config, &[BatchStatus::Submitting, BatchStatus::Submitting]).await;
+        assert_eq!(ready_child(&config, &chain[0]).await.unwrap(), None);
+
+        store
+            .update_batch_proof(
+                chain[1].batch_hash,
+                Bytes::from(vec![1; 32]),
+                vec![],
+                BatchStatus::Submitting,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            ready_child(&config, &chain[0]).await.unwrap(),
+            Some(chain[1].batch_hash)
+        );
+        assert_eq!(ready_child(&config, &chain[1]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn requeue_resets_the_batches_that_follow() {
+        let (config, store) = test_config(2004).await;
+        let chain = insert_chain(
+            &config,
+            &[
+                BatchStatus::Submitting,
+                BatchStatus::Submitting,
+                BatchStatus::Pending,
+                BatchStatus::Sealing,
+            ],
+        )
+        .await;
+
+        let requeued = requeue_children(&config, &chain[0]).await.unwrap();
+        assert_eq!(requeued, vec![chain[1].batch_hash, chain[2].batch_hash]);
+        let first = store
+            .find_batch(chain[0].batch_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.status, BatchStatus::Submitting);
+        for pb in &chain[1..3] {
+            let pb = store.find_batch(pb.batch_hash).await.unwrap().unwrap();
+            assert_eq!(pb.status, BatchStatus::Received);
+            assert_eq!(pb.pre_state_root, H256::zero());
+            assert_eq!(pb.post_state_root, H256::zero());
+        }
+        let sealing = store
+            .find_batch(chain[3].batch_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sealing.status, BatchStatus::Sealing);
+
+        let requeued = requeue_chain(&config, &chain[0]).await.unwrap();
+        assert_eq!(requeued[0], chain[0].batch_hash);
+        let first = store
+            .find_batch(chain[0].batch_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.status, BatchStatus::Received);
+    }
+}
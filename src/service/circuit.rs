@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use ethers::contract::abigen;
+use ethers::types::{Bytes, H160, H256, U256};
+use ethers::utils::keccak256;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{CircuitConfig, Config};
+use crate::model::pool_batch::PoolBatch;
+use crate::service::pool::entry_point_verifier;
+use crate::upstream::get_upstream;
+
+// The verifier interface snarkjs generates for PLONK
+abigen!(
+    VerifierContract,
+    r#"[
+        function verifyProof(bytes proof, uint256[] pubSignals) external view returns (bool)
+    ]"#
+);
+
+// handleOps takes the public inputs as `uint256[1]`, so every circuit has
+// exactly one: the batch commitment
+const PUB_INPUTS: usize = 1;
+
+// The registry is `bundler.circuits`. A batch records the id of the circuit
+// it was sealed for, so batches of an old circuit keep their format while a
+// new one rolls out. An empty id takes any proof, as before circuits.
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CircuitReport {
+    pub id: String,
+    pub proof_len: usize,
+    pub pub_inputs: usize,
+    pub verifier: H160,
+    // keccak256 of the verification key file, `None` without one
+    pub verification_key_hash: Option<H256>,
+    pub entry_points: Vec<H160>,
+    // Required by new batches of the EntryPoints no circuit claims
+    pub default: bool,
+}
+
+/// Every circuit of `bundler.circuits`.
+pub fn circuit_registry(config: &Config) -> anyhow::Result<Vec<CircuitReport>, anyhow::Error> {
+    let mut report = vec![];
+    for circuit in config.bundler.circuits.iter() {
+        let verification_key_hash = match circuit.verification_key.as_str() {
+            "" => None,
+            path => Some(H256::from(keccak256(std::fs::read(path)?))),
+        };
+        report.push(CircuitReport {
+            id: circuit.id.clone(),
+            proof_len: circuit.proof_len,
+            pub_inputs: PUB_INPUTS,
+            verifier: circuit.verifier,
+            verification_key_hash,
+            entry_points: circuit.entry_points.clone(),
+            default: circuit.id == config.bundler.circuit,
+        });
+    }
+
+    Ok(report)
+}
+
+fn find_circuit<'a>(config: &'a Config, circuit_id: &str) -> Option<&'a CircuitConfig> {
+    config
+        .bundler
+        .circuits
+        .iter()
+        .find(|circuit| circuit.id == circuit_id)
+}
+
+/// The id of the circuit new batches of `entry_point` require.
+pub fn batch_circuit(config: &Config, entry_point: H160) -> String {
+    config
+        .bundler
+        .circuits
+        .iter()
+        .find(|circuit| circuit.entry_points.contains(&entry_point))
+        .map_or_else(
+            || config.bundler.circuit.clone(),
+            |circuit| circuit.id.clone(),
+        )
+}
+
+/// Checks a proof of `pb` tagged `circuit_id` against the circuit the batch
+/// requires: the tag, the proof length and public input count, then the
+/// circuit's verifier, or the one of the batch's EntryPoint.
+pub async fn check_proof(
+    config: &Config,
+    pb: &PoolBatch,
+    circuit_id: Option<&str>,
+    zk_proof: &Bytes,
+    zk_pub_inputs: &[U256],
+) -> anyhow::Result<(), anyhow::Error> {
+    let circuit_id = match (pb.circuit_id.as_str(), circuit_id) {
+        ("", None) => return Ok(()),
+        ("", Some(tagged)) => tagged,
+        (required, Some(tagged)) if tagged != required => anyhow::bail!(
+            "Batch requires a proof of circuit {}, got {}",
+            required,
+            tagged
+        ),
+        (required, None) => anyhow::bail!(
+            "Batch requires a proof of circuit {}, tag the submission with it",
+            required
+        ),
+        (required, Some(_)) => required,
+    };
+    let circuit = find_circuit(config, circuit_id)
+        .ok_or_else(|| anyhow!("Unknown circuit: {}", circuit_id))?;
+
+    if circuit.proof_len != 0 && zk_proof.len() != circuit.proof_len {
+        anyhow::bail!(
+            "Circuit {} proofs are {} bytes, got {}",
+            circuit.id,
+            circuit.proof_len,
+            zk_proof.len()
+        );
+    }
+    if zk_pub_inputs.len() != PUB_INPUTS {
+        anyhow::bail!(
+            "Circuit {} has {} public inputs, got {}",
+            circuit.id,
+            PUB_INPUTS,
+            zk_pub_inputs.len()
+        );
+    }
+
+    let verifier = match circuit.verifier {
+        verifier if verifier.is_zero() => entry_point_verifier(config, pb.entry_point).await?,
+        verifier => verifier,
+    };
+    let verified = VerifierContract::new(verifier, Arc::new(get_upstream(config.chain_id())))
+        .verify_proof(zk_proof.clone(), zk_pub_inputs.to_vec())
+        .call()
+        .await
+        .map_err(|err| anyhow!("Verifier {:?} call failed: {}", verifier, err))?;
+    if !verified {
+        anyhow::bail!("Proof does not verify against circuit {}", circuit.id);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::pool_batch::BatchStatus;
+    use crate::test_util::{pool_batch, start_node, test_config};
+
+    async fn circuit_config(chain_id: u64) -> Config {
+        let (mut config, _) = test_config(chain_id).await;
+        config.bundler.circuits = vec![CircuitConfig {
+            id: String::from("plonk-v1"),
+            proof_len: 4,
+            verifier: H160::repeat_byte(0x77),
+            ..CircuitConfig::default()
+        }];
+        config
+    }
+
+    fn circuit_batch(circuit_id: &str) -> PoolBatch {
+        let mut pb = pool_batch(1, BatchStatus::Pending, None);
+        pb.circuit_id = String::from(circuit_id);
+        pb
+    }
+
+    #[tokio::test]
+    async fn check_proof_requires_the_batch_circuit() {
+        let config = circuit_config(3001).await;
+        let proof = Bytes::from(vec![1; 4]);
+        let inputs = [U256::one()];
+
+        // Batches sealed before circuits take any proof
+        check_proof(&config, &circuit_batch(""), None, &proof, &inputs)
+            .await
+            .unwrap();
+
+        let pb = circuit_batch("plonk-v1");
+        let err = check_proof(&config, &pb, None, &proof, &inputs)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("tag the submission"));
+        let err = check_proof(&config, &pb, Some("plonk-v0"), &proof, &inputs)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("got plonk-v0"));
+
+        let err = check_proof(
+            &config,
+            &circuit_batch(""),
+            Some("groth16"),
+            &proof,
+            &inputs,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Unknown circuit: groth16");
+    }
+
+    #[tokio::test]
+    async fn check_proof_checks_the_proof_shape() {
+        let config = circuit_config(3002).await;
+        let pb = circuit_batch("plonk-v1");
+
+        let err = check_proof(
+            &config,
+            &pb,
+            Some("plonk-v1"),
+            &Bytes::from(vec![1; 3]),
+            &[U256::one()],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Circuit plonk-v1 proofs are 4 bytes, got 3"
+        );
+
+        let err = check_proof(
+            &config,
+            &pb,
+            Some("plonk-v1"),
+            &Bytes::from(vec![1; 4]),
+            &[U256::one(), U256::one()],
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Circuit plonk-v1 has 1 public inputs, got 2"
+        );
+    }
+
+    #[tokio::test]
+    async fn check_proof_asks_the_verifier() {
+        let config = circuit_config(3003).await;
+        let node = start_node(config.chain_id()).await;
+        let pb = circuit_batch("plonk-v1");
+        let proof = Bytes::from(vec![1; 4]);
+        let inputs = [U256::one()];
+
+        node.answer("eth_call", H256::from_low_u64_be(1));
+        check_proof(&config, &pb, Some("plonk-v1"), &proof, &inputs)
+            .await
+            .unwrap();
+
+        node.answer("eth_call", H256::zero());
+        let err = check_proof(&config, &pb, Some("plonk-v1"), &proof, &inputs)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Proof does not verify against circuit plonk-v1"
+        );
+    }
+}
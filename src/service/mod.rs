@@ -1 +1,8 @@
+pub mod admin;
+pub mod archive;
+pub mod batch_chain;
+pub mod circuit;
+pub mod paymaster;
 pub mod pool;
+pub mod state_root;
+pub mod treasury;
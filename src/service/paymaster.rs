@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use ethers::contract::abigen;
+use ethers::types::{Bytes, H160, U256};
+use ethers::utils::{format_ether, WEI_IN_ETHER};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::config::{Config, Erc20PaymasterConfig};
+use crate::model::pool_tx::TxStatus;
+use crate::service::pool::{decode_handle_ops, EntryPointContract, UserOperation};
+use crate::store::get_pool_store;
+use crate::upstream::get_upstream;
+
+abigen!(ERC20Contract, "./src/config/contracts/ERC20.json");
+
+lazy_static! {
+    // Held from the paymaster checks of a tx until it is stored, so two txs
+    // can't both count on the same funds
+    static ref PAYMASTER_CHECK: Mutex<()> = Mutex::new(());
+}
+
+/// The paymaster of `op`, `None` if the sender pays for itself.
+pub fn op_paymaster(op: &UserOperation) -> anyhow::Result<Option<H160>, anyhow::Error> {
+    match op.paymaster_and_data.len() {
+        0 => Ok(None),
+        len if len < 20 => anyhow::bail!("Invalid paymasterAndData of {:?}", op.sender),
+        _ => Ok(Some(H160::from_slice(&op.paymaster_and_data[..20]))),
+    }
+}
+
+/// The most the EntryPoint takes from whoever pays for `op`: its gas limits
+/// at `max_fee_per_gas`. A paymaster's postOp may use the verification gas
+/// twice more. Fails if that overflows, which no op can pay for.
+pub fn op_max_cost(op: &UserOperation) -> anyhow::Result<U256, anyhow::Error> {
+    let mul = if op.paymaster_and_data.is_empty() {
+        1
+    } else {
+        3
+    };
+    op.verification_gas_limit
+        .checked_mul(U256::from(mul))
+        .and_then(|gas| gas.checked_add(op.call_gas_limit))
+        .and_then(|gas| gas.checked_add(op.pre_verification_gas))
+        .and_then(|gas| gas.checked_mul(op.max_fee_per_gas))
+        .ok_or_else(|| anyhow!("Gas limits and fees of {:?} overflow", op.sender))
+}
+
+// What an ERC-20 paymaster charges for `cost` wei of gas
+fn token_cost(paymaster: &Erc20PaymasterConfig, cost: U256) -> anyhow::Result<U256, anyhow::Error> {
+    let rate = U256::from_dec_str(&paymaster.exchange_rate)?;
+    let tokens = cost
+        .checked_mul(rate)
+        .ok_or_else(|| anyhow!("Token cost of {} wei overflows", cost))?;
+    Ok(tokens / WEI_IN_ETHER)
+}
+
+// Ops stored before their cost was checked count as unpayable
+fn pending_cost(op: &UserOperation) -> U256 {
+    op_max_cost(op).unwrap_or(U256::MAX)
+}
+
+// The ops of received and batched txs of `entry_point`, which the
+// EntryPoint has not charged for yet
+async fn pending_ops(
+    config: &Config,
+    entry_point: H160,
+) -> anyhow::Result<Vec<UserOperation>, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    let mut ops = vec![];
+    for status in [TxStatus::Received, TxStatus::Pending] {
+        for pool_tx in store
+            .find_txs_by_entry_point(entry_point, status, config.pool.max_txs)
+            .await?
+        {
+            // Stored before paymaster checks, or not a handleOps call
+            if let Ok(tx_ops) = decode_handle_ops(&pool_tx.tx.input) {
+                ops.extend(tx_ops);
+            }
+        }
+    }
+
+    Ok(ops)
+}
+
+/// Checks that the paymaster of every op of a handleOps call to
+/// `entry_point` can cover it on top of its pending ops: its EntryPoint
+/// deposit, and for `bundler.erc20_paymasters` the sender's token balance
+/// and allowance to the paymaster. Returns a guard to hold until the tx is
+/// stored if any op has a paymaster.
+pub async fn check_paymasters(
+    config: &Config,
+    entry_point: H160,
+    input: &Bytes,
+) -> anyhow::Result<Option<MutexGuard<'static, ()>>, anyhow::Error> {
+    if !config.bundler.check_paymasters {
+        return Ok(None);
+    }
+
+    let ops = decode_handle_ops(input)?;
+    let mut paymasters = vec![];
+    for op in ops.iter() {
+        if let Some(paymaster) = op_paymaster(op)? {
+            paymasters.push((paymaster, op));
+        }
+    }
+    if paymasters.is_empty() {
+        return Ok(None);
+    }
+
+    let guard = PAYMASTER_CHECK.lock().await;
+    let pending = pending_ops(config, entry_point).await?;
+    let provider = Arc::new(get_upstream(config.chain_id()));
+    let entry_point_contract = EntryPointContract::new(entry_point, provider.clone());
+
+    // By paymaster, the new ops' cost then the pending ones'
+    let mut costs: HashMap<H160, (U256, U256)> = HashMap::new();
+    for (paymaster, op) in paymasters.iter() {
+        let cost = &mut costs.entry(*paymaster).or_default().0;
+        *cost = cost
+            .checked_add(op_max_cost(op)?)
+            .ok_or_else(|| anyhow!("Cost of the ops of paymaster {:?} overflows", paymaster))?;
+    }
+    for op in pending.iter() {
+        if let Some(paymaster) = op_paymaster(op).ok().flatten() {
+            if let Some(cost) = costs.get_mut(&paymaster) {
+                cost.1 = cost.1.saturating_add(pending_cost(op));
+            }
+        }
+    }
+
+    for (paymaster, (cost, exposure)) in costs.iter() {
+        let deposit = entry_point_contract.balance_of(*paymaster).call().await?;
+        if deposit < cost.saturating_add(*exposure) {
+            anyhow::bail!(
+                "Paymaster {:?} deposit of {} ether can't cover {} ether of ops on top of {} ether pending",
+                paymaster,
+                format_ether(deposit),
+                format_ether(*cost),
+                format_ether(*exposure)
+            );
+        }
+    }
+
+    let mut checked = vec![];
+    for (paymaster, op) in paymasters.iter() {
+        if checked.contains(&(*paymaster, op.sender)) {
+            continue;
+        }
+        checked.push((*paymaster, op.sender));
+
+        let erc20 = match config
+            .bundler
+            .erc20_paymasters
+            .iter()
+            .find(|erc20| erc20.address == *paymaster)
+        {
+            Some(erc20) => erc20,
+            None => continue,
+        };
+
+        // Every op of the sender through this paymaster, new and pending
+        let cost = ops
+            .iter()
+            .chain(pending.iter())
+            .filter(|other| other.sender == op.sender)
+            .filter(|other| matches!(op_paymaster(other), Ok(Some(p)) if p == *paymaster))
+            .fold(U256::zero(), |sum, other| {
+                sum.saturating_add(pending_cost(other))
+            });
+        let tokens = token_cost(erc20, cost)?;
+
+        let token = ERC20Contract::new(erc20.token, provider.clone());
+        let balance = token.balance_of(op.sender).call().await?;
+        if balance < tokens {
+            anyhow::bail!(
+                "Sender {:?} has {} of token {:?}, paymaster {:?} needs {}",
+                op.sender,
+                balance,
+                erc20.token,
+                paymaster,
+                tokens
+            );
+        }
+        let allowance = token.allowance(op.sender, *paymaster).call().await?;
+        if allowance < tokens {
+            anyhow::bail!(
+                "Sender {:?} allows paymaster {:?} {} of token {:?}, it needs {}",
+                op.sender,
+                paymaster,
+                allowance,
+                erc20.token,
+                tokens
+            );
+        }
+    }
+
+    Ok(Some(guard))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymasterExposure {
+    pub entry_point: H160,
+    pub paymaster: H160,
+    pub pending_ops: usize,
+    // Most the pending ops can take from the deposit, in wei
+    pub exposure: U256,
+    pub deposit: U256,
+}
+
+/// The pending ops of every paymaster, on every EntryPoint.
+pub async fn paymaster_exposure(
+    config: &Config,
+) -> anyhow::Result<Vec<PaymasterExposure>, anyhow::Error> {
+    let provider = Arc::new(get_upstream(config.chain_id()));
+
+    let mut report = vec![];
+    for entry_point in config.bundler.entry_points() {
+        let mut exposures: Vec<PaymasterExposure> = vec![];
+        for op in pending_ops(config, entry_point).await? {
+            let paymaster = match op_paymaster(&op) {
+                Ok(Some(paymaster)) => paymaster,
+                _ => continue,
+            };
+            let i = match exposures.iter().position(|e| e.paymaster == paymaster) {
+                Some(i) => i,
+                None => {
+                    exposures.push(PaymasterExposure {
+                        entry_point,
+                        paymaster,
+                        pending_ops: 0,
+                        exposure: U256::zero(),
+                        deposit: U256::zero(),
+                    });
+                    exposures.len() - 1
+                }
+            };
+            exposures[i].pending_ops += 1;
+            exposures[i].exposure = exposures[i].exposure.saturating_add(pending_cost(&op));
+        }
+
+        let contract = EntryPointContract::new(entry_point, provider.clone());
+        for exposure in exposures.iter_mut() {
+            exposure.deposit = contract.balance_of(exposure.paymaster).call().await?;
+        }
+        report.extend(exposures);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::user_op;
+
+    fn paymaster_op() -> UserOperation {
+        let mut op = user_op(1, 0);
+        op.paymaster_and_data = Bytes::from([[0x50; 20].as_slice(), &[1, 2]].concat());
+        op
+    }
+
+    #[test]
+    fn op_paymaster_reads_the_address_prefix() {
+        assert_eq!(op_paymaster(&user_op(1, 0)).unwrap(), None);
+        assert_eq!(
+            op_paymaster(&paymaster_op()).unwrap(),
+            Some(H160::repeat_byte(0x50))
+        );
+
+        let mut op = user_op(1, 0);
+        op.paymaster_and_data = Bytes::from(vec![0x50; 19]);
+        assert!(op_paymaster(&op).is_err());
+    }
+
+    #[test]
+    fn op_max_cost_counts_postop_for_paymasters() {
+        // (200k + 100k + 50k) gas at 1 gwei
+        assert_eq!(
+            op_max_cost(&user_op(1, 0)).unwrap(),
+            U256::from(350_000_000_000_000u64)
+        );
+        // (3 * 200k + 100k + 50k) gas at 1 gwei
+        assert_eq!(
+            op_max_cost(&paymaster_op()).unwrap(),
+            U256::from(750_000_000_000_000u64)
+        );
+    }
+
+    #[test]
+    fn op_max_cost_rejects_overflow() {
+        let mut op = user_op(1, 0);
+        op.max_fee_per_gas = U256::one() << 255;
+        assert!(op_max_cost(&op).is_err());
+        assert_eq!(pending_cost(&op), U256::MAX);
+
+        let mut op = paymaster_op();
+        op.verification_gas_limit = U256::MAX / 2;
+        op.max_fee_per_gas = U256::one();
+        assert!(op_max_cost(&op).is_err());
+    }
+
+    #[test]
+    fn token_cost_converts_at_the_exchange_rate() {
+        let paymaster = Erc20PaymasterConfig {
+            exchange_rate: String::from("2000000000"),
+            ..Erc20PaymasterConfig::default()
+        };
+        // 0.5 ether of gas
+        assert_eq!(
+            token_cost(&paymaster, WEI_IN_ETHER / 2).unwrap(),
+            U256::from(1_000_000_000u64)
+        );
+        assert!(token_cost(&paymaster, U256::MAX / 2).is_err());
+
+        let paymaster = Erc20PaymasterConfig {
+            exchange_rate: String::from("not a number"),
+            ..Erc20PaymasterConfig::default()
+        };
+        assert!(token_cost(&paymaster, WEI_IN_ETHER).is_err());
+    }
+}
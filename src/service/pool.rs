@@ -1,218 +1,587 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
 
+use anyhow::anyhow;
 use ethers::abi;
-use ethers::abi::{AbiEncode, ParamType};
+use ethers::abi::{AbiEncode, ParamType, Token};
 use ethers::contract::abigen;
 use ethers::middleware::SignerMiddleware;
-use ethers::providers::Provider;
-use ethers::signers::LocalWallet;
-use ethers::types::{Bytes, Transaction, H160, H256, U256, U64};
-use ethers::utils::keccak256;
-use futures::TryStreamExt;
-use mongodb::bson::{doc, to_bson, DateTime};
-use mongodb::options::FindOptions;
+use ethers::providers::Middleware;
+use ethers::types::{Bytes, Transaction, TransactionReceipt, H160, H256, U256, U64};
+use ethers::utils::{id, keccak256};
+use lazy_static::lazy_static;
+use mongodb::bson::DateTime;
 use serde::{Deserialize, Serialize};
 use tokio::task;
+use tokio::task::JoinHandle;
 
-use crate::model::pool_batch::PoolBatch;
-use crate::model::pool_tx::PoolTx;
+use crate::config::Config;
+use crate::miner::get_miner_pool;
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::model::pool_tx::{PoolTx, TxStatus};
 use crate::schedule::do_batch_received_txs;
+use crate::service::admin::{is_intake_paused, is_submission_paused, wait_submission_resumed};
+use crate::service::batch_chain::{
+    chain_tip, expected_pre_state_root, next_provable, parent_landed, ready_child, requeue_chain,
+    requeue_children,
+};
+use crate::service::circuit::{batch_circuit, check_proof};
+use crate::service::paymaster::check_paymasters;
+use crate::service::state_root::refresh_state_roots;
+use crate::service::treasury::{is_funds_low, next_beneficiary, wait_funds_restored};
+use crate::store::get_pool_store;
+use crate::upstream::get_upstream;
 
 abigen!(EntryPointContract, "./src/config/contracts/EntryPoint.json");
 
-async fn handle_ops(pb: PoolBatch) -> anyhow::Result<H256, anyhow::Error> {
-    let co_pool_tx = PoolTx::get_collection().await;
-    let co_pool_batch = PoolBatch::get_collection().await;
+lazy_static! {
+    // By chain id and batch hash, batches whose handleOps this process is
+    // sending
+    static ref IN_FLIGHT: Mutex<HashSet<(u64, H256)>> = Mutex::new(HashSet::new());
+    // By chain id and EntryPoint, its `verifier()` read last
+    static ref VERIFIERS: RwLock<HashMap<(u64, H160), H160>> = RwLock::new(HashMap::new());
+}
 
-    println!("Do handle_ops: {}", pb.batch_hash.encode_hex());
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryPointReport {
+    pub entry_point: H160,
+    pub verifier: H160, // Checks the proofs of its handleOps
+}
+
+/// The proof verifier of `entry_point`, from its `verifier()`. Read once,
+/// `read_entry_point_verifiers` reads them again.
+pub async fn entry_point_verifier(
+    config: &Config,
+    entry_point: H160,
+) -> anyhow::Result<H160, anyhow::Error> {
+    let known = VERIFIERS
+        .read()
+        .unwrap()
+        .get(&(config.chain_id(), entry_point))
+        .copied();
+    match known {
+        Some(verifier) => Ok(verifier),
+        None => read_entry_point_verifier(config, entry_point).await,
+    }
+}
+
+async fn read_entry_point_verifier(
+    config: &Config,
+    entry_point: H160,
+) -> anyhow::Result<H160, anyhow::Error> {
+    let verifier = EntryPointContract::new(entry_point, Arc::new(get_upstream(config.chain_id())))
+        .verifier()
+        .call()
+        .await?;
+    VERIFIERS
+        .write()
+        .unwrap()
+        .insert((config.chain_id(), entry_point), verifier);
+    Ok(verifier)
+}
+
+/// Every EntryPoint with its verifier, read from the chain now.
+pub async fn read_entry_point_verifiers(
+    config: &Config,
+) -> anyhow::Result<Vec<EntryPointReport>, anyhow::Error> {
+    let mut report = vec![];
+    for entry_point in config.bundler.entry_points() {
+        report.push(EntryPointReport {
+            entry_point,
+            verifier: read_entry_point_verifier(config, entry_point).await?,
+        });
+    }
+
+    Ok(report)
+}
+
+// How long a batch may stay in sealing before `recover_pool` treats it
+// as abandoned
+const SEALING_TIMEOUT_MS: i64 = 60_000;
+
+// How long a submitting batch may go unchanged before `resume_submissions`
+// sends it again, or looks up the handleOps it sent
+const SUBMISSION_TIMEOUT_MS: i64 = 600_000;
+
+// ERC-4337 `handleAggregatedOps`. Our EntryPoint has no aggregated-ops
+// path (no `handleAggregatedOps`, no `ValidationResultWithAggregation`), and
+// handleOps takes one proof for the whole batch, so signature aggregators
+// can't be supported until the contract and the circuit do.
+const HANDLE_AGGREGATED_OPS: &str = "handleAggregatedOps(((address,uint256,bytes,bytes,uint256,uint256,uint256,uint256,uint256,bytes,bytes)[],address,bytes)[],address)";
+
+/// The UserOperations of a handleOps call.
+pub fn decode_handle_ops(input: &Bytes) -> anyhow::Result<Vec<UserOperation>, anyhow::Error> {
+    if input.len() < 4 {
+        anyhow::bail!("Not a handleOps call");
+    }
+    let (_, decoded_input_data) = input.split_at(4);
+
+    let tokens = abi::decode(
+        &[
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Uint(256),
+                ParamType::Bytes,
+                ParamType::Bytes,
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+                ParamType::Bytes,
+                ParamType::Bytes,
+            ]))),
+            ParamType::Bytes,
+            ParamType::FixedArray(Box::new(ParamType::Uint(256)), 1),
+            ParamType::Address,
+        ],
+        decoded_input_data,
+    )?;
+
+    let mut ops = vec![];
+    let tuple_arr = tokens[0].clone().into_array().unwrap();
+    for tuple in tuple_arr.iter() {
+        let t = tuple.clone().into_tuple().unwrap();
+        ops.push(UserOperation {
+            sender: t[0].clone().into_address().unwrap(),
+            nonce: t[1].clone().into_uint().unwrap(),
+            init_code: Bytes::from(t[2].clone().into_bytes().unwrap()),
+            call_data: Bytes::from(t[3].clone().into_bytes().unwrap()),
+            call_gas_limit: t[4].clone().into_uint().unwrap(),
+            verification_gas_limit: t[5].clone().into_uint().unwrap(),
+            pre_verification_gas: t[6].clone().into_uint().unwrap(),
+            max_fee_per_gas: t[7].clone().into_uint().unwrap(),
+            max_priority_fee_per_gas: t[8].clone().into_uint().unwrap(),
+            paymaster_and_data: Bytes::from(t[9].clone().into_bytes().unwrap()),
+            signature: Bytes::from(t[10].clone().into_bytes().unwrap()),
+        });
+    }
+
+    Ok(ops)
+}
+
+// The ops of the txs of `tx_hash_list`, in order
+async fn batch_ops(
+    config: &Config,
+    tx_hash_list: &[H256],
+) -> anyhow::Result<Vec<UserOperation>, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
 
     let mut ops: Vec<UserOperation> = vec![];
-    for h in pb.tx_hash_list.iter() {
-        let one = co_pool_tx
-            .find_one(doc! {"tx_hash": h.encode_hex()}, None)
-            .await?;
+    for h in tx_hash_list.iter() {
+        let one = store.find_tx(*h).await?;
 
         if one.is_none() {
             continue;
         }
 
         let pool_tx = one.unwrap();
-        let input_data = pool_tx.tx.input.clone();
-        let (_, decoded_input_data) = input_data.split_at(4);
-
-        let tokens = abi::decode(
-            &[
-                ParamType::Array(Box::new(ParamType::Tuple(vec![
-                    ParamType::Address,
-                    ParamType::Uint(256),
-                    ParamType::Bytes,
-                    ParamType::Bytes,
-                    ParamType::Uint(256),
-                    ParamType::Uint(256),
-                    ParamType::Uint(256),
-                    ParamType::Uint(256),
-                    ParamType::Uint(256),
-                    ParamType::Bytes,
-                    ParamType::Bytes,
-                ]))),
-                ParamType::Bytes,
-                ParamType::FixedArray(Box::new(ParamType::Uint(256)), 1),
-                ParamType::Address,
-            ],
-            &decoded_input_data,
-        )
-        .unwrap();
-
-        let tuple_arr = tokens[0].clone().into_array().unwrap();
-        for tuple in tuple_arr.iter() {
-            let t = tuple.clone().into_tuple().unwrap();
-            ops.push(UserOperation {
-                sender: t[0].clone().into_address().unwrap(),
-                nonce: t[1].clone().into_uint().unwrap(),
-                init_code: Bytes::from(t[2].clone().into_bytes().unwrap()),
-                call_data: Bytes::from(t[3].clone().into_bytes().unwrap()),
-                call_gas_limit: t[4].clone().into_uint().unwrap(),
-                verification_gas_limit: t[5].clone().into_uint().unwrap(),
-                pre_verification_gas: t[6].clone().into_uint().unwrap(),
-                max_fee_per_gas: t[7].clone().into_uint().unwrap(),
-                max_priority_fee_per_gas: t[8].clone().into_uint().unwrap(),
-                paymaster_and_data: Bytes::from(t[9].clone().into_bytes().unwrap()),
-                signature: Bytes::from(t[10].clone().into_bytes().unwrap()),
-            });
-        }
+        ops.extend(decode_handle_ops(&pool_tx.tx.input)?);
     }
 
-    let miner_private_key = std::env::var("BUNDLER_MINER_PRIVATE_KEY").unwrap();
-    let miner_address: H160 = std::env::var("BUNDLER_MINER_ADDRESS").unwrap().parse()?;
-    let entry_point_address: H160 = std::env::var("BUNDLER_ENTRY_POINT_ADDRESS")
-        .unwrap()
-        .parse()?;
+    Ok(ops)
+}
 
-    let wallet: LocalWallet = miner_private_key.parse::<LocalWallet>()?;
+// `UserOperationLib.pack` of EntryPoint v0.6: the op without its
+// signature, its bytes fields replaced by their keccak256
+fn pack_op(op: &UserOperation) -> Vec<u8> {
+    abi::encode(&[
+        Token::Address(op.sender),
+        Token::Uint(op.nonce),
+        Token::FixedBytes(keccak256(&op.init_code).to_vec()),
+        Token::FixedBytes(keccak256(&op.call_data).to_vec()),
+        Token::Uint(op.call_gas_limit),
+        Token::Uint(op.verification_gas_limit),
+        Token::Uint(op.pre_verification_gas),
+        Token::Uint(op.max_fee_per_gas),
+        Token::Uint(op.max_priority_fee_per_gas),
+        Token::FixedBytes(keccak256(&op.paymaster_and_data).to_vec()),
+    ])
+}
 
-    let provider =
-        Provider::<ethers::providers::Http>::try_from(std::env::var("NETWORK_RPC_URL").unwrap())
-            .unwrap();
+/// The batch commitment of `ops`, the public input handleOps checks the
+/// proof against. The EntryPoint's `calculateUserOpHashesSum`, computed
+/// here:
+///
+/// ```text
+/// commitment = sum(uint256(keccak256(pack(op))) for op in ops) mod 2^256
+/// ```
+///
+/// with `pack` the ABI encoding of the op's fields but its signature, and
+/// keccak256 for initCode, callData and paymasterAndData. A sum, so it
+/// doesn't depend on the ops' order. The state roots are not part of it,
+/// `PoolBatch` records them next to it.
+pub fn batch_commitment(ops: &[UserOperation]) -> U256 {
+    ops.iter().fold(U256::zero(), |sum, op| {
+        let op_hash = U256::from_big_endian(&keccak256(pack_op(op)));
+        sum.overflowing_add(op_hash).0
+    })
+}
+
+// `calculateUserOpHashesSum(ops)` on `entry_point`, to cross-check
+// `batch_commitment`
+async fn contract_commitment(
+    config: &Config,
+    entry_point: H160,
+    ops: Vec<UserOperation>,
+) -> anyhow::Result<U256, anyhow::Error> {
+    let provider = Arc::new(get_upstream(config.chain_id()));
+
+    Ok(EntryPointContract::new(entry_point, provider)
+        .calculate_user_op_hashes_sum(ops)
+        .call()
+        .await?)
+}
+
+async fn handle_ops(config: &Config, pb: PoolBatch) -> anyhow::Result<H256, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    println!("Do handle_ops: {}", pb.batch_hash.encode_hex());
+
+    let (proof, pub_signal) = match (pb.zk_proof.clone(), pb.zk_pub_inputs.first()) {
+        (Some(proof), Some(pub_signal)) => (proof, *pub_signal),
+        _ => {
+            store
+                .update_batch_status(pb.batch_hash, BatchStatus::Failed)
+                .await?;
+            fail_batch(config, &pb).await?;
+            anyhow::bail!(
+                "Batch {} has no proof and public input to submit",
+                pb.batch_hash.encode_hex()
+            );
+        }
+    };
+    let ops = batch_ops(config, &pb.tx_hash_list).await?;
+
+    let miner = get_miner_pool(config.chain_id()).assign();
+    let miner_address = miner.address();
+    let beneficiary = next_beneficiary(config, miner_address);
+    let entry_point_address = pb.entry_point;
+    store
+        .update_batch_miner(pb.batch_hash, miner_address)
+        .await?;
+
+    let provider = get_upstream(config.chain_id());
 
     let client =
-        SignerMiddleware::new_with_provider_chain(provider.clone(), wallet.clone()).await?;
+        SignerMiddleware::new_with_provider_chain(provider.clone(), miner.signer()).await?;
 
-    let entry_point =
-        EntryPointContract::new(entry_point_address.clone(), Arc::new(client.clone()));
+    let entry_point = EntryPointContract::new(entry_point_address, Arc::new(client.clone()));
 
-    // let proof = pb.zk_proof.unwrap_or("0x00".parse().unwrap());
-    let proof = "0x0bd25a842f34fbdb111a96c6a27cf6bd29a917915d70807f59c22ca17b32d60b02dbeaf4b49cf47989c526b142401585d4369acabae337ec828ef0d202e93ddb13041248ff33e20bdb0aeb53b9202d2a57c24ce4545d7280cea041506f2c887120f7eeb1c6876810364caa21108fcbdb8c0adae0255423cb6f6e3891d7cad7b909058994926a6a0c776c264e0e1590278a158140e452a59851eef753576106d029990ca0e49f5e2e327ad2c797bbc8ba4b3551741b7417964ecae8495ba4c1be2915b39125efa2ce148e5a92c9101fd3a321656017343f3f3be2b061b5b5cfe812d4ceec3d4f53d863b80d5caca99375c6b54bf46fa91a8761f75e20e06ac7d41f1fbcc8f1b470be0ceaf408ce73f7d1f17c5d6f4f534c24f741d0a510c085b126041332b3b6262595e4b064e97874703bbd40f3cd7aa31af1bc19ed294969b4184e6b705c4cf411016036e8f51ce8848ab419ce3c63006091970b1bcdd03c611df617ba23c70af6df78d4cf26c4103aeef33d826cbe0a63738a0e879cc64a4c0701c8dda4f0cc5156bb2e592abc9b62aae0ca86eeff207686ab0268b869387e18ead49e65976f8239c61ac2cb1f5fb743cf707e6f49f10a6e26a2022dbd81f510b91b47890dfa23be5d8cba526e3bbc9efe3bb126c36bbf3113e8fd72b75b792edb90eda6fcea504906b55a7dda7bcc8543a806a38ed37c65ac933fb2cd98a0200cfb6d46def7096c452262286cf3771d6e95c784c80734e4f6e2440914ef5b11713ea681b4856ece02d478fb340a70c7e97ed1540a5113b9f39f28cbf7d7dd14e634613867782602890c7c6e9cdf0b74ad55e65c69170f4a29ed9d2088915c19a3f987615dd78ef36d1a27215d70f19213f588136dbd58c8a2b1b46813ad611b16a64c0f47b974d26517e8954aaafbc946ce2150f518d5939e1ba932ab62a32a061ddde12d4afe906c68238bbb184794f8633b40d2c61a5b9766d115df2fbd15355a8affe474874a4082cb9db0903af6d814e5cc8905d25ed1b1b75a8229130552ae3789943969fb7f7f3de44f1dc30c91bde5e8f19c891f121a621733220e1c4da3bae02bbaa191111370d57da4f8edf14bd8d4488402ff50ac84520c28f41a0011183889c29807daf60ca9ef982aaa22ff855603916233caff1ae86a718111bea0a12ca6c6d3fbdc38a23d7c322baff97ce4c95e339705ff3e9696cf90b609830f08deec3b9bded2bc6ebf4225d7d2e3b67e3137c9d1bc94aba431716219277cd8544a2040b44a392ef1ec090b79b40fac486b8818de8c7c89aefff42cf52972823f1e82a78d1940d666ef4b50f782188c03832b828d13ada74e942a2e9a253fecda3850a978cf7559b797c5ac67ffc3a2d03f55b38d4548be4b8cd55a210c08f9941c8dd52bb802f35b9f368ff7d3ce92283de8cde6e77b9107cfb85b7117a787905a857339d1f74c50d85d412fd55341a8e7042d36ce78f6c4dfec3e410ffc065422f563659de15dbbd62be0abb4d71753ac27c0a80c3b48cf395539c2049530e954cafb7622ec8f853770878850a2b1d09be5eaf58d78c57cac6b96c10837057be2b551f80e0a79914fa9cd820683ed6d838e4d8a2f7ec1c4817524621d206dfb7e0c58a46bbed69f6d75967797f64b5b7c40abc791018091deffae6e0b8829cf362e7393cc512876ebc781c62e95660aabbb0e868201c0d9b0feda33234714a55c5f87b4cdb77c91839ca45b77ca3820d1a4b973c8ba696fd1ee4f201297af1d35c87e6ecbdd1d3e9ffe3a345c56c8b4d47a2a07943212f88c29e29f22d7558723df6ba871a6b6af09529ca5bf1b4467e0e4f4c8cb30a016a687dfd313940cd3b2da338f4343895b396f25f2ba3be2d51d2677b3d663a043c2b55ae9045ebcf595b672edcd1967751237b7b200d283764acacf97122222b0842b91cb11892d919307e40f6cb9d6d2a9dc5c408f7e67c6b40ddbfce8efa891cddc08882c9da7a008592a006f287d1318482f3ef8f05bc1df9bd929c1cae7fa1e8eaba70d2de3179b52e644015e2ff7d1da43aa167cf113c2ad01f148639dc9803750ed".parse::<Bytes>().unwrap_or("0x00".parse().unwrap());
-    let pub_signals: Vec<U256> = pb.zk_pub_inputs.clone();
-    println!("proof:{}", proof.clone().encode_hex());
-    println!("pub_signals:{:#?}", pub_signals.clone());
+    tracing::debug!("proof: {}", proof.clone().encode_hex());
+    tracing::debug!("pub_signal: {}", pub_signal);
 
-    let transaction_receipt = entry_point
-        .handle_ops(ops, proof, [pub_signals[0]], miner_address)
-        .gas(2000000)
-        .send()
-        .await?
+    let call = entry_point
+        .handle_ops(ops, proof, [pub_signal], beneficiary)
+        .gas(config.bundler.handle_ops_gas);
+    let pending_tx = miner
+        .send_with_nonce(&provider, |nonce| {
+            let mut tx = call.tx.clone();
+            tx.set_nonce(nonce);
+            let client = &client;
+            async move { Ok(client.send_transaction(tx, None).await?) }
+        })
         .await?;
+    // Lets `resume_submissions` look it up if this process stops waiting
+    store
+        .update_batch_send_tx_hash(pb.batch_hash, pending_tx.tx_hash(), BatchStatus::Submitting)
+        .await?;
+    let transaction_receipt = pending_tx.await?;
+
+    record_receipt(config, &pb, transaction_receipt).await
+}
+
+// Settles a submitted batch by the receipt of its handleOps, `None` if it
+// never got mined. Returns the tx hash if it landed.
+async fn record_receipt(
+    config: &Config,
+    pb: &PoolBatch,
+    transaction_receipt: Option<TransactionReceipt>,
+) -> anyhow::Result<H256, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
 
     match transaction_receipt {
-        Some(tr) => {
+        Some(tr) if tr.status == Some(U64::from(1)) => {
             let send_tx_hash = tr.transaction_hash.encode_hex();
             println!("send_tx_hash: {}", send_tx_hash);
 
-            co_pool_batch
-                .update_one(
-                    doc! {"batch_hash": &pb.batch_hash.encode_hex()},
-                    doc! {"$set": {"status": 4, "send_tx_hash": send_tx_hash }},
-                    None,
-                )
+            store
+                .update_batch_send_tx_hash(pb.batch_hash, tr.transaction_hash, BatchStatus::Succeed)
+                .await?;
+            store
+                .update_txs_status(&pb.tx_hash_list, TxStatus::Succeed)
                 .await?;
+            // The next batch starts from the root this one left
+            if let Err(err) = refresh_state_roots(config, pb.entry_point).await {
+                println!("Reading the state roots failed: {}", err);
+            }
             Ok(tr.transaction_hash)
         }
-        _ => {
-            co_pool_batch
-                .update_one(
-                    doc! {"batch_hash": &pb.batch_hash.encode_hex()},
-                    doc! {"$set": {"status": 5}},
-                    None,
-                )
+        Some(tr) => {
+            // Mined but reverted, e.g. on a stale root or a bad proof
+            println!(
+                "handleOps of batch {} reverted: {}",
+                pb.batch_hash.encode_hex(),
+                tr.transaction_hash.encode_hex()
+            );
+            store
+                .update_batch_send_tx_hash(pb.batch_hash, tr.transaction_hash, BatchStatus::Failed)
+                .await?;
+            fail_batch(config, pb).await?;
+            Ok(H256::zero())
+        }
+        None => {
+            store
+                .update_batch_status(pb.batch_hash, BatchStatus::Failed)
                 .await?;
+            fail_batch(config, pb).await?;
             Ok(H256::zero())
         }
     }
 }
 
-pub async fn receive_tx(mut tx: Transaction) -> anyhow::Result<H256, anyhow::Error> {
+// Marks the txs of a failed batch, and hands its children out again
+async fn fail_batch(config: &Config, pb: &PoolBatch) -> anyhow::Result<(), anyhow::Error> {
+    get_pool_store(config.chain_id())
+        .update_txs_status(&pb.tx_hash_list, TxStatus::Failed)
+        .await?;
+    // Their proofs start from the root this one would have left
+    requeue_children(config, pb).await?;
+    Ok(())
+}
+
+// Rejects a new tx when the pool, or its sender's share of it, is full
+async fn check_pool_limits(config: &Config, tx_from: H160) -> anyhow::Result<(), anyhow::Error> {
+    let bundler_pool_max_txs = config.pool.max_txs;
+    let bundler_pool_max_txs_per_sender = config.pool.max_txs_per_sender;
+
+    let store = get_pool_store(config.chain_id());
+
+    if store.count_txs(TxStatus::Received, None).await? >= bundler_pool_max_txs {
+        anyhow::bail!("Pool is full ({} txs)", bundler_pool_max_txs);
+    }
+    if store.count_txs(TxStatus::Received, Some(tx_from)).await? >= bundler_pool_max_txs_per_sender
+    {
+        anyhow::bail!(
+            "Too many pending txs from {} (max {})",
+            tx_from.encode_hex(),
+            bundler_pool_max_txs_per_sender
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn receive_tx(
+    config: Arc<Config>,
+    mut tx: Transaction,
+) -> anyhow::Result<H256, anyhow::Error> {
+    if is_intake_paused(config.chain_id()) {
+        anyhow::bail!("Bundler is not accepting txs right now");
+    }
+
     tx.from = tx.recover_from()?;
     tx.hash = tx.hash();
 
-    let collection = PoolTx::get_collection().await;
+    let entry_point = tx.to.unwrap_or_default();
+    if !config.bundler.entry_points().contains(&entry_point) {
+        anyhow::bail!("Unsupported EntryPoint: {}", entry_point.encode_hex());
+    }
+    if tx.input.starts_with(&id(HANDLE_AGGREGATED_OPS)) {
+        anyhow::bail!("handleAggregatedOps is not supported by this EntryPoint");
+    }
 
-    let one = collection
-        .find_one(doc! {"tx_hash": tx.hash.encode_hex()}, None)
-        .await?;
+    let store = get_pool_store(config.chain_id());
+
+    let one = store.find_tx(tx.hash).await?;
 
     if one.is_none() {
+        check_pool_limits(&config, tx.from).await?;
+        // Held until the tx is stored, so a concurrent check counts it among
+        // the paymaster's pending ops
+        let paymaster_check = check_paymasters(&config, entry_point, &tx.input).await?;
+
         let pool_tx = PoolTx {
             tx: tx.clone(),
             tx_from: tx.from,
             tx_hash: tx.hash,
             created_at: DateTime::from(SystemTime::now()),
-            status: 1,
+            status: TxStatus::Received,
+            batch_hash: None,
+            evict_reason: None,
+            entry_point,
         };
-        collection.insert_one(pool_tx, None).await?;
+        store.insert_tx(pool_tx).await?;
+        drop(paymaster_check);
     }
 
-    tokio::spawn(do_batch_received_txs());
+    tokio::spawn(do_batch_received_txs(config));
 
     Ok(tx.hash)
 }
 
-pub async fn batch_received_txs() -> anyhow::Result<usize, anyhow::Error> {
-    let bundler_batch_tx_total: usize = std::env::var("BUNDLER_BATCH_TX_TOTAL")
-        .unwrap_or(String::from("128"))
-        .parse()
-        .unwrap();
-
-    let co_pool_tx = PoolTx::get_collection().await;
-    let find_options = FindOptions::builder()
-        .sort(doc! {"created_at": 1})
-        .limit(bundler_batch_tx_total as i64)
-        .build();
-    let mut pt_cursor = co_pool_tx.find(doc! {"status": 1}, find_options).await?;
+pub async fn batch_received_txs(config: &Config) -> anyhow::Result<Vec<H256>, anyhow::Error> {
+    // When received tx length >= bundler_batch_tx_total, new a batch
+    seal_batches(config, config.bundler.batch_tx_total).await
+}
 
-    let mut tx_hash_list: Vec<H256> = vec![];
-    while let Some(tx) = pt_cursor.try_next().await? {
-        tx_hash_list.push(tx.tx_hash);
+/// Seals a batch for every EntryPoint with at least `min_txs` received txs.
+/// Returns the new batches' hashes.
+pub async fn seal_batches(
+    config: &Config,
+    min_txs: usize,
+) -> anyhow::Result<Vec<H256>, anyhow::Error> {
+    let mut batch_hashes = vec![];
+    for entry_point in config.bundler.entry_points() {
+        if let Some(batch_hash) = seal_batch(config, entry_point, min_txs).await? {
+            batch_hashes.push(batch_hash);
+        }
     }
 
-    // When received tx length >= bundler_batch_tx_total, new a batch
-    if tx_hash_list.len() >= bundler_batch_tx_total {
-        // Lock txs
-        co_pool_tx
-            .update_many(
-                doc! {"tx_hash": {"$in": to_bson(&tx_hash_list)?}},
-                doc! {"$set": {"status": 2}},
-                None,
-            )
-            .await?;
+    Ok(batch_hashes)
+}
 
-        let batch_hash = keccak256(ethers::utils::rlp::encode_list(&tx_hash_list));
+/// Seals up to `batch_tx_total` received txs of `entry_point` into a batch
+/// if there are at least `min_txs` of them. Returns the new batch's hash.
+pub async fn seal_batch(
+    config: &Config,
+    entry_point: H160,
+    min_txs: usize,
+) -> anyhow::Result<Option<H256>, anyhow::Error> {
+    let bundler_batch_tx_total = config.bundler.batch_tx_total;
 
-        let co_pool_batch = PoolBatch::get_collection().await;
+    let store = get_pool_store(config.chain_id());
+    let tx_hash_list: Vec<H256> = store
+        .find_txs_by_entry_point(entry_point, TxStatus::Received, bundler_batch_tx_total)
+        .await?
+        .iter()
+        .map(|pt| pt.tx_hash)
+        .collect();
 
+    if !tx_hash_list.is_empty() && tx_hash_list.len() >= min_txs {
+        let batch_hash = H256::from(keccak256(ethers::utils::rlp::encode_list(&tx_hash_list)));
+
+        // Seal by claim-by-update: the batch is written first as sealing and
+        // acts as the lock, then the txs are claimed under its
+        // hash, and only when every tx was claimed does it become received.
+        // A crash in between is repaired by `recover_pool`.
+        if store.find_batch(batch_hash).await?.is_some() {
+            return Ok(None);
+        }
+        let commitment = batch_commitment(&batch_ops(config, &tx_hash_list).await?);
+        let parent = chain_tip(config, entry_point).await?;
         let pool_batch = PoolBatch {
-            batch_hash: H256::from(batch_hash),
+            batch_hash,
             tx_hash_list: tx_hash_list.clone(),
             zk_proof: None,
             zk_pub_inputs: vec![],
             send_tx_hash: H256::zero(),
             created_at: DateTime::from(SystemTime::now()),
-            status: 1,
+            status: BatchStatus::Sealing,
+            entry_point,
+            miner: H160::zero(),
+            commitment,
+            pre_state_root: H256::zero(),
+            post_state_root: H256::zero(),
+            parent,
+            circuit_id: batch_circuit(config, entry_point),
+            updated_at: None,
         };
-        co_pool_batch.insert_one(pool_batch, None).await?;
+        store.insert_batch(pool_batch).await?;
+
+        let claimed = store.claim_txs(&tx_hash_list, batch_hash).await?;
+        if claimed < tx_hash_list.len() {
+            // Another bundler sealed some of these txs first
+            store.release_txs(batch_hash).await?;
+            store.delete_batch(batch_hash).await?;
+            return Ok(None);
+        }
+        store
+            .update_batch_status(batch_hash, BatchStatus::Received)
+            .await?;
+
+        return Ok(Some(batch_hash));
+    }
+
+    Ok(None)
+}
+
+/// Evicts received txs older than `pool.tx_ttl_secs` that no batch picked
+/// up. Returns how many were evicted.
+pub async fn evict_expired_txs(config: &Config) -> anyhow::Result<usize, anyhow::Error> {
+    let bundler_pool_tx_ttl_secs = config.pool.tx_ttl_secs;
+
+    let store = get_pool_store(config.chain_id());
+    let before =
+        DateTime::from_millis(DateTime::now().timestamp_millis() - bundler_pool_tx_ttl_secs * 1000);
+
+    let expired: Vec<H256> = store
+        .find_txs_created_before(TxStatus::Received, before, 1024)
+        .await?
+        .iter()
+        .map(|pt| pt.tx_hash)
+        .collect();
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    let reason = format!("not batched within {}s", bundler_pool_tx_ttl_secs);
+    let evicted = store.evict_txs(&expired, &reason).await?;
+    println!("Evicted {} expired txs", evicted);
+
+    Ok(evicted)
+}
+
+/// Repairs batches left half-written by a crash during `batch_received_txs`:
+/// sealing batches older than `SEALING_TIMEOUT_MS` are dropped and their
+/// txs released, and pending txs whose batch does not exist go back to
+/// received.
+pub async fn recover_pool(config: &Config) -> anyhow::Result<(), anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+    let now = DateTime::now().timestamp_millis();
+
+    let batches = store.list_batches().await?;
+    for pb in batches.iter() {
+        // Younger ones may still be in progress on another bundler
+        if pb.status == BatchStatus::Sealing
+            && now - pb.created_at.timestamp_millis() > SEALING_TIMEOUT_MS
+        {
+            let released = store.release_txs(pb.batch_hash).await?;
+            store.delete_batch(pb.batch_hash).await?;
+            println!(
+                "Recovered sealing batch: {}, released {} txs",
+                pb.batch_hash.encode_hex(),
+                released
+            );
+        }
     }
 
-    Ok(tx_hash_list.len())
+    let batched: HashSet<H256> = batches
+        .iter()
+        .filter(|pb| pb.status != BatchStatus::Sealing)
+        .flat_map(|pb| pb.tx_hash_list.iter().copied())
+        .collect();
+    let orphans: Vec<H256> = store
+        .list_txs()
+        .await?
+        .iter()
+        .filter(|pt| {
+            pt.status == TxStatus::Pending
+                && pt.batch_hash.is_none()
+                && !batched.contains(&pt.tx_hash)
+        })
+        .map(|pt| pt.tx_hash)
+        .collect();
+    if !orphans.is_empty() {
+        store
+            .update_txs_status(&orphans, TxStatus::Received)
+            .await?;
+        println!("Recovered {} pending txs without a batch", orphans.len());
+    }
+
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -220,87 +589,787 @@ pub async fn batch_received_txs() -> anyhow::Result<usize, anyhow::Error> {
 pub struct GetPoolBatchResponse {
     batch_hash: H256,
     tx_list: Vec<Transaction>,
-    status: u8,
+    status: BatchStatus,
+    entry_point: H160,
+    commitment: U256, // The public input the proof must have
+    pre_state_root: H256,
+    parent: Option<H256>, // Submitted before this one
+    circuit_id: String,   // Empty for any
 }
 
-pub async fn get_pool_batch() -> anyhow::Result<Option<GetPoolBatchResponse>, anyhow::Error> {
-    let co_pool_tx = PoolTx::get_collection().await;
-    let co_pool_batch = PoolBatch::get_collection().await;
+/// Hands the oldest received batch whose pre root is known to a prover. A
+/// batch whose parent is proven but not on chain yet starts from the
+/// parent's post root, so several batches can be proven at once. A prover
+/// that gives `circuit_ids` only gets batches it can prove.
+pub async fn get_pool_batch(
+    config: &Config,
+    circuit_ids: Option<Vec<String>>,
+) -> anyhow::Result<Option<GetPoolBatchResponse>, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
 
-    let pool_batch = co_pool_batch.find_one(doc! {"status": 1}, None).await?;
+    let pool_batch = next_provable(config, circuit_ids.as_deref()).await?;
     match pool_batch {
-        Some(pb) => {
-            let mut pt_cursor = co_pool_tx
-                .find(
-                    doc! {"tx_hash": {"$in": to_bson(&pb.tx_hash_list).unwrap()}},
-                    None,
-                )
-                .await?;
+        Some((pb, pre_state_root)) => {
+            let tx_list: Vec<Transaction> = store
+                .find_txs(&pb.tx_hash_list)
+                .await?
+                .into_iter()
+                .map(|pt| pt.tx)
+                .collect();
 
-            let mut tx_list: Vec<Transaction> = vec![];
-            while let Some(pt) = pt_cursor.try_next().await? {
-                tx_list.push(pt.tx);
-            }
-
-            // Update batch status=2
-            co_pool_batch
-                .update_one(
-                    doc! {"batch_hash": pb.batch_hash.encode_hex()},
-                    doc! {"$set": {"status": 2}},
-                    None,
-                )
+            store
+                .update_batch_state_roots(pb.batch_hash, pre_state_root, H256::zero())
+                .await?;
+            store
+                .update_batch_status(pb.batch_hash, BatchStatus::Pending)
                 .await?;
 
             Ok(Some(GetPoolBatchResponse {
                 batch_hash: pb.batch_hash,
                 tx_list,
                 status: pb.status,
+                entry_point: pb.entry_point,
+                commitment: pb.commitment,
+                pre_state_root,
+                parent: pb.parent,
+                circuit_id: pb.circuit_id,
             }))
         }
         _ => Ok(None),
     }
 }
 
+// Holds a batch in `IN_FLIGHT` while this process sends its handleOps, and
+// lets it go however that ends
+struct InFlight((u64, H256));
+
+impl InFlight {
+    fn claim(chain_id: u64, batch_hash: H256) -> Option<Self> {
+        let key = (chain_id, batch_hash);
+        // Built only once claimed, dropping it releases the key
+        let claimed = IN_FLIGHT.lock().unwrap().insert(key);
+        claimed.then(|| Self(key))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.0);
+    }
+}
+
+// Sends handleOps for a submitting batch unless it is already in flight.
+// Returns the batch if it landed.
+async fn submit_batch(
+    config: &Config,
+    batch_hash: H256,
+) -> anyhow::Result<Option<PoolBatch>, anyhow::Error> {
+    // The proof and the parent landing can both start it
+    let _in_flight = match InFlight::claim(config.chain_id(), batch_hash) {
+        Some(in_flight) => in_flight,
+        None => return Ok(None),
+    };
+
+    let pool_batch = get_pool_store(config.chain_id())
+        .find_batch(batch_hash)
+        .await?
+        .filter(|pb| pb.status == BatchStatus::Submitting);
+    let pb = match pool_batch {
+        Some(pb) => pb,
+        None => return Ok(None),
+    };
+
+    // Proven against the post root its parent's prover gave
+    if !pb.pre_state_root.is_zero()
+        && expected_pre_state_root(config, &pb).await? != Some(pb.pre_state_root)
+    {
+        requeue_chain(config, &pb).await?;
+        return Ok(None);
+    }
+
+    let send_tx_hash = handle_ops(config, pb.clone()).await?;
+    Ok((!send_tx_hash.is_zero()).then_some(pb))
+}
+
+// Sends handleOps for `batch_hash` once submission may go on, then for the
+// proven children that waited for it, in chain order. A batch whose send
+// errors stays submitting, for `resume_submissions` to send again.
+fn spawn_submission(config: Arc<Config>, batch_hash: H256) -> JoinHandle<()> {
+    task::spawn(async move {
+        let mut next = Some(batch_hash);
+        while let Some(batch_hash) = next.take() {
+            // Held here while an operator has submission paused, or
+            // the miners can't pay for it
+            wait_submission_resumed(config.chain_id()).await;
+            wait_funds_restored(config.chain_id()).await;
+
+            let landed = match submit_batch(&config, batch_hash).await {
+                Ok(landed) => landed,
+                Err(err) => {
+                    println!(
+                        "Submission of batch {} failed: {}",
+                        batch_hash.encode_hex(),
+                        err
+                    );
+                    None
+                }
+            };
+            if let Some(pb) = landed {
+                next = match ready_child(&config, &pb).await {
+                    Ok(child) => child,
+                    Err(err) => {
+                        println!(
+                            "Finding the child of batch {} failed: {}",
+                            batch_hash.encode_hex(),
+                            err
+                        );
+                        None
+                    }
+                };
+            }
+        }
+    })
+}
+
+/// Picks up the proven batches no task is submitting: children whose
+/// parent landed while nothing waited for it, e.g. after a restart or a
+/// retry, and batches whose send errored or whose handleOps was left
+/// unconfirmed for `SUBMISSION_TIMEOUT_MS`. Returns how many it picked up.
+pub async fn resume_submissions(config: Arc<Config>) -> anyhow::Result<usize, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+    let now = DateTime::now().timestamp_millis();
+
+    let submitting = store
+        .find_batches_created_before(
+            &[BatchStatus::Submitting],
+            DateTime::MAX,
+            config.pool.max_txs,
+        )
+        .await?;
+    let mut resumed = 0;
+    for pb in submitting {
+        let key = (config.chain_id(), pb.batch_hash);
+        if pb.zk_proof.is_none() || IN_FLIGHT.lock().unwrap().contains(&key) {
+            continue;
+        }
+        // Never sent, or sent by a bundler that may still be waiting
+        let timed_out = now - pb.updated_at().timestamp_millis() > SUBMISSION_TIMEOUT_MS;
+        if !(pb.miner.is_zero() || timed_out) || !parent_landed(&config, &pb).await? {
+            continue;
+        }
+
+        if !pb.send_tx_hash.is_zero() {
+            let receipt = get_upstream(config.chain_id())
+                .get_transaction_receipt(pb.send_tx_hash)
+                .await?;
+            if receipt.is_some() {
+                record_receipt(&config, &pb, receipt).await?;
+                resumed += 1;
+                continue;
+            }
+        }
+        println!("Resume submission of batch: {}", pb.batch_hash.encode_hex());
+        spawn_submission(config.clone(), pb.batch_hash);
+        resumed += 1;
+    }
+
+    Ok(resumed)
+}
+
+/// Takes a prover's proof of a handed out batch, made by circuit
+/// `circuit_id`. `post_state_root` is the root the proof ends at; without
+/// it the batch's children wait until it is on chain. The batch is
+/// submitted once its parent is.
 pub async fn receive_proof_and_public_input(
+    config: Arc<Config>,
     batch_hash: H256,
     zk_proof: Bytes,
     zk_pub_inputs: Vec<U256>,
+    post_state_root: Option<H256>,
+    circuit_id: Option<String>,
 ) -> anyhow::Result<U64, anyhow::Error> {
-    let co_pool_batch = PoolBatch::get_collection().await;
-    let hash_encode_hex = batch_hash.encode_hex();
+    let store = get_pool_store(config.chain_id());
 
-    let pool_batch = co_pool_batch
-        .find_one(
-            doc! {"batch_hash": hash_encode_hex.as_str(), "status": {"$in": [1, 2]}},
-            None,
-        )
-        .await?;
+    let pool_batch = store
+        .find_batch(batch_hash)
+        .await?
+        .filter(|pb| pb.status == BatchStatus::Received || pb.status == BatchStatus::Pending);
 
     match pool_batch {
-        Some(_) => {
-            co_pool_batch
-                .update_one(
-                    doc! {"batch_hash": hash_encode_hex.as_str()},
-                    doc! {"$set": {"zk_proof": zk_proof.encode_hex(), "zk_pub_inputs": to_bson(&zk_pub_inputs).unwrap(), "status": 3}},
-                    None,
+        Some(pb) => {
+            check_proof(
+                &config,
+                &pb,
+                circuit_id.as_deref(),
+                &zk_proof,
+                &zk_pub_inputs,
+            )
+            .await?;
+            // handleOps would revert on any other public input
+            if !pb.commitment.is_zero() && zk_pub_inputs.first() != Some(&pb.commitment) {
+                anyhow::bail!(
+                    "Public input does not match the batch commitment {}",
+                    pb.commitment
+                );
+            }
+            if !pb.pre_state_root.is_zero()
+                && expected_pre_state_root(&config, &pb).await? != Some(pb.pre_state_root)
+            {
+                requeue_chain(&config, &pb).await?;
+                anyhow::bail!(
+                    "Batch {} was proven against a stale state root, it is handed out again",
+                    batch_hash.encode_hex()
+                );
+            }
+
+            store
+                .update_batch_state_roots(
+                    batch_hash,
+                    pb.pre_state_root,
+                    post_state_root.unwrap_or_default(),
                 )
                 .await?;
+            store
+                .update_batch_proof(batch_hash, zk_proof, zk_pub_inputs, BatchStatus::Submitting)
+                .await?;
 
-            task::spawn(async move {
-                let pool_batch = co_pool_batch
-                    .find_one(doc! {"batch_hash": hash_encode_hex.as_str()}, None)
-                    .await
-                    .unwrap();
-                match pool_batch {
-                    Some(pool_batch) => {
-                        handle_ops(pool_batch).await.unwrap();
-                    }
-                    _ => {}
-                }
-            });
+            // Otherwise the parent's submission picks it up once it lands
+            if parent_landed(&config, &pb).await? {
+                spawn_submission(config, batch_hash);
+            }
 
             Ok(U64::from(1))
         }
         _ => Ok(U64::from(0)),
     }
 }
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CommitmentCheck {
+    pub batch_hash: H256,
+    pub commitment: U256, // As stored
+    pub recomputed: U256,
+    pub matches: bool,
+    // `calculateUserOpHashesSum` of the ops on the EntryPoint, `None` if
+    // the call failed
+    pub contract_commitment: Option<U256>,
+    // The roots its proof goes from and to, zero until known
+    pub pre_state_root: H256,
+    pub post_state_root: H256,
+    // Whether the proof's public input is the recomputed commitment, `None`
+    // until a proof is received
+    pub proof_matches: Option<bool>,
+}
+
+/// Recomputes the commitment of a batch from its stored txs and checks it
+/// against the stored one, the public input of its proof and the
+/// EntryPoint's own computation.
+pub async fn verify_batch_commitment(
+    config: &Config,
+    batch_hash: H256,
+) -> anyhow::Result<CommitmentCheck, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    let pb = store
+        .find_batch(batch_hash)
+        .await?
+        .ok_or_else(|| anyhow!("Batch not found: {}", batch_hash.encode_hex()))?;
+    let ops = batch_ops(config, &pb.tx_hash_list).await?;
+    let recomputed = batch_commitment(&ops);
+    let contract_commitment = match contract_commitment(config, pb.entry_point, ops).await {
+        Ok(commitment) => Some(commitment),
+        Err(err) => {
+            println!("calculateUserOpHashesSum failed: {}", err);
+            None
+        }
+    };
+
+    Ok(CommitmentCheck {
+        batch_hash,
+        commitment: pb.commitment,
+        recomputed,
+        matches: pb.commitment == recomputed,
+        contract_commitment,
+        pre_state_root: pb.pre_state_root,
+        post_state_root: pb.post_state_root,
+        proof_matches: pb
+            .zk_proof
+            .as_ref()
+            .map(|_| pb.zk_pub_inputs.first() == Some(&recomputed)),
+    })
+}
+
+pub async fn find_pool_tx(
+    config: &Config,
+    tx_hash: H256,
+) -> anyhow::Result<Option<PoolTx>, anyhow::Error> {
+    get_pool_store(config.chain_id()).find_tx(tx_hash).await
+}
+
+/// The receipt of the `handleOps` tx that carried a pooled tx, `None` until
+/// its batch is sent.
+pub async fn get_pool_tx_receipt(
+    config: &Config,
+    pool_tx: &PoolTx,
+) -> anyhow::Result<Option<TransactionReceipt>, anyhow::Error> {
+    let batch_hash = match pool_tx.batch_hash {
+        Some(batch_hash) => batch_hash,
+        None => return Ok(None),
+    };
+
+    let pool_batch = get_pool_store(config.chain_id())
+        .find_batch(batch_hash)
+        .await?;
+    match pool_batch {
+        Some(pb) if !pb.send_tx_hash.is_zero() => Ok(get_upstream(config.chain_id())
+            .get_transaction_receipt(pb.send_tx_hash)
+            .await?),
+        _ => Ok(None),
+    }
+}
+
+/// The next nonce of `tx_from` counting the txs it still has in the pool,
+/// `chain_nonce` if it has none.
+pub async fn get_pending_nonce(
+    config: &Config,
+    tx_from: H160,
+    chain_nonce: U256,
+) -> anyhow::Result<U256, anyhow::Error> {
+    let pooled = get_pool_store(config.chain_id())
+        .find_txs_from(tx_from, &[TxStatus::Received, TxStatus::Pending])
+        .await?;
+
+    Ok(pooled
+        .iter()
+        .map(|pt| pt.tx.nonce + 1)
+        .fold(chain_nonce, U256::max))
+}
+
+/// Removes a tx from the pool. Txs claimed by a batch can only leave with
+/// the batch, see `cancel_batch`.
+pub async fn drop_tx(config: &Config, tx_hash: H256) -> anyhow::Result<(), anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    let pool_tx = store
+        .find_tx(tx_hash)
+        .await?
+        .ok_or_else(|| anyhow!("Tx not found: {}", tx_hash.encode_hex()))?;
+    if pool_tx.status == TxStatus::Pending {
+        anyhow::bail!(
+            "Tx {} belongs to a batch, cancel the batch first",
+            tx_hash.encode_hex()
+        );
+    }
+
+    store.delete_txs(&[tx_hash]).await
+}
+
+/// Puts a failed batch back to work. A batch with a proof goes back to
+/// submitting and a submission task is started for it, unless it is
+/// already being submitted. One without goes back to the provers, as does
+/// a pending one stuck with a prover. Returns the batch's new status and
+/// the submission task, if one was started.
+pub async fn retry_batch(
+    config: &Config,
+    batch_hash: H256,
+) -> anyhow::Result<(BatchStatus, Option<JoinHandle<()>>), anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    let pb = store
+        .find_batch(batch_hash)
+        .await?
+        .ok_or_else(|| anyhow!("Batch not found: {}", batch_hash.encode_hex()))?;
+
+    let submission = match (pb.status, pb.zk_proof.is_some()) {
+        (BatchStatus::Failed, true) => {
+            if is_submission_paused(config.chain_id()) {
+                anyhow::bail!("Submission is paused");
+            }
+            if is_funds_low(config.chain_id()) {
+                anyhow::bail!("Submission is paused, the miner accounts are low on funds");
+            }
+            if !parent_landed(config, &pb).await? {
+                anyhow::bail!(
+                    "Batch {} waits for its parent to land",
+                    batch_hash.encode_hex()
+                );
+            }
+            // Released before the task starts, it claims the batch itself
+            let in_flight = InFlight::claim(config.chain_id(), batch_hash)
+                .ok_or_else(|| anyhow!("Batch {} is being submitted", batch_hash.encode_hex()))?;
+            store
+                .update_batch_status(batch_hash, BatchStatus::Submitting)
+                .await?;
+            store
+                .update_txs_status(&pb.tx_hash_list, TxStatus::Pending)
+                .await?;
+            drop(in_flight);
+            Some(spawn_submission(Arc::new(config.clone()), batch_hash))
+        }
+        (BatchStatus::Failed | BatchStatus::Pending, false) => {
+            store
+                .update_batch_status(batch_hash, BatchStatus::Received)
+                .await?;
+            store
+                .update_txs_status(&pb.tx_hash_list, TxStatus::Pending)
+                .await?;
+            None
+        }
+        (status, _) => anyhow::bail!(
+            "Batch {} can't be retried in status {:?}",
+            batch_hash.encode_hex(),
+            status
+        ),
+    };
+
+    let pb = store.find_batch(batch_hash).await?;
+    Ok((pb.map_or(BatchStatus::Invalid, |pb| pb.status), submission))
+}
+
+/// Cancels a batch that has not been submitted and returns its txs to the
+/// pool. The batch is deleted, as sealing the same txs again gives the
+/// same hash. Returns the number of txs released.
+pub async fn cancel_batch(
+    config: &Config,
+    batch_hash: H256,
+) -> anyhow::Result<usize, anyhow::Error> {
+    let store = get_pool_store(config.chain_id());
+
+    let pb = store
+        .find_batch(batch_hash)
+        .await?
+        .ok_or_else(|| anyhow!("Batch not found: {}", batch_hash.encode_hex()))?;
+    match pb.status {
+        BatchStatus::Received | BatchStatus::Pending | BatchStatus::Failed => {}
+        status => anyhow::bail!(
+            "Batch {} can't be cancelled in status {:?}",
+            batch_hash.encode_hex(),
+            status
+        ),
+    }
+
+    let mut released = store.release_txs(batch_hash).await?;
+
+    // Txs batched before they recorded their batch_hash
+    let unlinked: Vec<H256> = store
+        .find_txs(&pb.tx_hash_list)
+        .await?
+        .iter()
+        .filter(|pt| {
+            pt.batch_hash.is_none()
+                && (pt.status == TxStatus::Pending || pt.status == TxStatus::Failed)
+        })
+        .map(|pt| pt.tx_hash)
+        .collect();
+    if !unlinked.is_empty() {
+        store
+            .update_txs_status(&unlinked, TxStatus::Received)
+            .await?;
+        released += unlinked.len();
+    }
+    requeue_children(config, &pb).await?;
+    store.delete_batch(batch_hash).await?;
+
+    Ok(released)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::admin::set_submission_paused;
+    use crate::test_util::{pool_batch, pool_tx, test_config, user_op, ENTRY_POINT};
+
+    fn minutes_ago(minutes: i64) -> DateTime {
+        DateTime::from_millis(DateTime::now().timestamp_millis() - minutes * 60_000)
+    }
+
+    #[tokio::test]
+    async fn seal_batch_claims_received_txs() {
+        let (config, store) = test_config(1001).await;
+        let first = pool_tx(1, 0, minutes_ago(2));
+        let second = pool_tx(2, 0, minutes_ago(1));
+        let mut other = pool_tx(3, 0, minutes_ago(1));
+        other.entry_point = H160::repeat_byte(0x99);
+        for pt in [first.clone(), second.clone(), other.clone()] {
+            store.insert_tx(pt).await.unwrap();
+        }
+
+        assert_eq!(seal_batch(&config, ENTRY_POINT, 3).await.unwrap(), None);
+
+        let batch_hash = seal_batch(&config, ENTRY_POINT, 2).await.unwrap().unwrap();
+        let pb = store.find_batch(batch_hash).await.unwrap().unwrap();
+        assert_eq!(pb.status, BatchStatus::Received);
+        assert_eq!(pb.tx_hash_list, vec![first.tx_hash, second.tx_hash]);
+        assert_eq!(pb.parent, None);
+        assert_eq!(
+            pb.commitment,
+            batch_commitment(&[user_op(1, 0), user_op(2, 0)])
+        );
+        for pt in store.find_txs(&pb.tx_hash_list).await.unwrap() {
+            assert_eq!(pt.status, TxStatus::Pending);
+            assert_eq!(pt.batch_hash, Some(batch_hash));
+        }
+        let other = store.find_tx(other.tx_hash).await.unwrap().unwrap();
+        assert_eq!(other.status, TxStatus::Received);
+
+        // Nothing left to seal
+        assert_eq!(seal_batch(&config, ENTRY_POINT, 1).await.unwrap(), None);
+
+        // The next batch follows the open one
+        store
+            .insert_tx(pool_tx(4, 0, minutes_ago(0)))
+            .await
+            .unwrap();
+        let child_hash = seal_batch(&config, ENTRY_POINT, 1).await.unwrap().unwrap();
+        let child = store.find_batch(child_hash).await.unwrap().unwrap();
+        assert_eq!(child.parent, Some(batch_hash));
+    }
+
+    #[tokio::test]
+    async fn seal_batch_backs_off_when_txs_are_claimed() {
+        let (config, store) = test_config(1002).await;
+        let pt = pool_tx(1, 0, minutes_ago(1));
+        store.insert_tx(pt.clone()).await.unwrap();
+
+        // Another bundler sealed the same txs first
+        let batch_hash = H256::from(keccak256(ethers::utils::rlp::encode_list(&[pt.tx_hash])));
+        let mut sealed = pool_batch(0, BatchStatus::Sealing, None);
+        sealed.batch_hash = batch_hash;
+        sealed.tx_hash_list = vec![pt.tx_hash];
+        store.insert_batch(sealed.clone()).await.unwrap();
+
+        assert_eq!(seal_batch(&config, ENTRY_POINT, 1).await.unwrap(), None);
+        let found = store.find_batch(batch_hash).await.unwrap().unwrap();
+        assert_eq!(found.status, BatchStatus::Sealing);
+        let found = store.find_tx(pt.tx_hash).await.unwrap().unwrap();
+        assert_eq!(found.status, TxStatus::Received);
+    }
+
+    #[tokio::test]
+    async fn recover_pool_repairs_interrupted_seals() {
+        let (config, store) = test_config(1003).await;
+
+        // Left sealing by a crash, its tx claimed
+        let mut stale = pool_batch(1, BatchStatus::Sealing, None);
+        stale.created_at = minutes_ago(2);
+        let mut claimed = pool_tx(1, 0, minutes_ago(3));
+        claimed.status = TxStatus::Pending;
+        claimed.batch_hash = Some(stale.batch_hash);
+        stale.tx_hash_list = vec![claimed.tx_hash];
+
+        // Still being sealed
+        let mut sealing = pool_batch(2, BatchStatus::Sealing, None);
+        let mut sealing_tx = pool_tx(2, 0, minutes_ago(1));
+        sealing_tx.status = TxStatus::Pending;
+        sealing_tx.batch_hash = Some(sealing.batch_hash);
+        sealing.tx_hash_list = vec![sealing_tx.tx_hash];
+
+        // Batched before txs recorded their batch
+        let mut received = pool_batch(3, BatchStatus::Received, None);
+        let mut unlinked = pool_tx(3, 0, minutes_ago(1));
+        unlinked.status = TxStatus::Pending;
+        received.tx_hash_list = vec![unlinked.tx_hash];
+
+        let mut orphan = pool_tx(4, 0, minutes_ago(1));
+        orphan.status = TxStatus::Pending;
+
+        for pb in [stale.clone(), sealing.clone(), received.clone()] {
+            store.insert_batch(pb).await.unwrap();
+        }
+        for pt in [
+            claimed.clone(),
+            sealing_tx.clone(),
+            unlinked.clone(),
+            orphan.clone(),
+        ] {
+            store.insert_tx(pt).await.unwrap();
+        }
+
+        recover_pool(&config).await.unwrap();
+
+        assert!(store.find_batch(stale.batch_hash).await.unwrap().is_none());
+        assert!(store
+            .find_batch(sealing.batch_hash)
+            .await
+            .unwrap()
+            .is_some());
+        let status = |pt: Option<PoolTx>| pt.unwrap().status;
+        assert_eq!(
+            status(store.find_tx(claimed.tx_hash).await.unwrap()),
+            TxStatus::Received
+        );
+        assert_eq!(
+            status(store.find_tx(sealing_tx.tx_hash).await.unwrap()),
+            TxStatus::Pending
+        );
+        assert_eq!(
+            status(store.find_tx(unlinked.tx_hash).await.unwrap()),
+            TxStatus::Pending
+        );
+        assert_eq!(
+            status(store.find_tx(orphan.tx_hash).await.unwrap()),
+            TxStatus::Received
+        );
+    }
+
+    #[tokio::test]
+    async fn evict_expired_txs_only_takes_old_received_ones() {
+        let (mut config, store) = test_config(1004).await;
+        config.pool.tx_ttl_secs = 60;
+        let expired = pool_tx(1, 0, minutes_ago(2));
+        let fresh = pool_tx(2, 0, minutes_ago(0));
+        let mut batched = pool_tx(3, 0, minutes_ago(2));
+        batched.status = TxStatus::Pending;
+        for pt in [expired.clone(), fresh.clone(), batched.clone()] {
+            store.insert_tx(pt).await.unwrap();
+        }
+
+        assert_eq!(evict_expired_txs(&config).await.unwrap(), 1);
+
+        let evicted = store.find_tx(expired.tx_hash).await.unwrap().unwrap();
+        assert_eq!(evicted.status, TxStatus::Evicted);
+        assert_eq!(
+            evicted.evict_reason.as_deref(),
+            Some("not batched within 60s")
+        );
+        let fresh = store.find_tx(fresh.tx_hash).await.unwrap().unwrap();
+        assert_eq!(fresh.status, TxStatus::Received);
+        let batched = store.find_tx(batched.tx_hash).await.unwrap().unwrap();
+        assert_eq!(batched.status, TxStatus::Pending);
+
+        assert_eq!(evict_expired_txs(&config).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn retry_batch_hands_unproven_batches_back() {
+        let (config, store) = test_config(1005).await;
+        let mut failed_tx = pool_tx(1, 0, minutes_ago(1));
+        failed_tx.status = TxStatus::Failed;
+        let mut failed = pool_batch(1, BatchStatus::Failed, None);
+        failed.tx_hash_list = vec![failed_tx.tx_hash];
+        let stuck = pool_batch(2, BatchStatus::Pending, None);
+        let submitting = pool_batch(3, BatchStatus::Submitting, None);
+        store.insert_tx(failed_tx.clone()).await.unwrap();
+        for pb in [failed.clone(), stuck.clone(), submitting.clone()] {
+            store.insert_batch(pb).await.unwrap();
+        }
+
+        assert_eq!(
+            retry_batch(&config, failed.batch_hash).await.unwrap().0,
+            BatchStatus::Received
+        );
+        let pt = store.find_tx(failed_tx.tx_hash).await.unwrap().unwrap();
+        assert_eq!(pt.status, TxStatus::Pending);
+        assert_eq!(
+            retry_batch(&config, stuck.batch_hash).await.unwrap().0,
+            BatchStatus::Received
+        );
+
+        assert!(retry_batch(&config, submitting.batch_hash).await.is_err());
+        assert!(retry_batch(&config, H256::repeat_byte(9)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn retry_batch_refuses_to_resubmit_while_held() {
+        let (config, store) = test_config(1006).await;
+        let mut proven = pool_batch(1, BatchStatus::Failed, None);
+        proven.zk_proof = Some(Bytes::from(vec![1; 32]));
+        store.insert_batch(proven.clone()).await.unwrap();
+
+        set_submission_paused(config.chain_id(), true);
+        let err = retry_batch(&config, proven.batch_hash).await.unwrap_err();
+        assert_eq!(err.to_string(), "Submission is paused");
+        set_submission_paused(config.chain_id(), false);
+
+        let in_flight = InFlight::claim(config.chain_id(), proven.batch_hash).unwrap();
+        assert!(InFlight::claim(config.chain_id(), proven.batch_hash).is_none());
+        let err = retry_batch(&config, proven.batch_hash).await.unwrap_err();
+        assert!(err.to_string().contains("is being submitted"));
+        drop(in_flight);
+        assert!(InFlight::claim(config.chain_id(), proven.batch_hash).is_some());
+
+        let pb = store.find_batch(proven.batch_hash).await.unwrap().unwrap();
+        assert_eq!(pb.status, BatchStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn retry_batch_hands_proven_batches_to_a_submission_task() {
+        let (config, store) = test_config(1009).await;
+        let mut failed_tx = pool_tx(1, 0, minutes_ago(1));
+        failed_tx.status = TxStatus::Failed;
+        let mut proven = pool_batch(1, BatchStatus::Failed, None);
+        proven.zk_proof = Some(Bytes::from(vec![1; 32]));
+        proven.tx_hash_list = vec![failed_tx.tx_hash];
+        store.insert_tx(failed_tx.clone()).await.unwrap();
+        store.insert_batch(proven.clone()).await.unwrap();
+
+        let (status, submission) = retry_batch(&config, proven.batch_hash).await.unwrap();
+        // Holds the task before it sends, the test runtime has not run it yet
+        set_submission_paused(config.chain_id(), true);
+        assert_eq!(status, BatchStatus::Submitting);
+        let submission = submission.unwrap();
+        let pt = store.find_tx(failed_tx.tx_hash).await.unwrap().unwrap();
+        assert_eq!(pt.status, TxStatus::Pending);
+        // Not held by the caller, the task claims it when it sends
+        assert!(InFlight::claim(config.chain_id(), proven.batch_hash).is_some());
+
+        task::yield_now().await;
+        assert!(!submission.is_finished());
+        submission.abort();
+        set_submission_paused(config.chain_id(), false);
+    }
+
+    #[tokio::test]
+    async fn cancel_batch_releases_txs_and_requeues_children() {
+        let (config, store) = test_config(1007).await;
+        let mut batch = pool_batch(1, BatchStatus::Pending, None);
+        let mut linked = pool_tx(1, 0, minutes_ago(2));
+        linked.status = TxStatus::Pending;
+        linked.batch_hash = Some(batch.batch_hash);
+        let mut unlinked = pool_tx(2, 0, minutes_ago(2));
+        unlinked.status = TxStatus::Pending;
+        batch.tx_hash_list = vec![linked.tx_hash, unlinked.tx_hash];
+        let mut child = pool_batch(2, BatchStatus::Pending, Some(batch.batch_hash));
+        child.pre_state_root = H256::repeat_byte(7);
+        let submitting = pool_batch(3, BatchStatus::Submitting, None);
+        for pt in [linked.clone(), unlinked.clone()] {
+            store.insert_tx(pt).await.unwrap();
+        }
+        for pb in [batch.clone(), child.clone(), submitting.clone()] {
+            store.insert_batch(pb).await.unwrap();
+        }
+
+        assert_eq!(cancel_batch(&config, batch.batch_hash).await.unwrap(), 2);
+
+        assert_eq!(store.find_batch(batch.batch_hash).await.unwrap(), None);
+        for pt in store.find_txs(&batch.tx_hash_list).await.unwrap() {
+            assert_eq!(pt.status, TxStatus::Received);
+            assert_eq!(pt.batch_hash, None);
+        }
+        let child = store.find_batch(child.batch_hash).await.unwrap().unwrap();
+        assert_eq!(child.status, BatchStatus::Received);
+        assert_eq!(child.pre_state_root, H256::zero());
+
+        assert!(cancel_batch(&config, submitting.batch_hash).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cancelled_batch_can_be_sealed_again() {
+        let (config, store) = test_config(1008).await;
+        for (sender, minutes) in [(1, 2), (2, 1)] {
+            store
+                .insert_tx(pool_tx(sender, 0, minutes_ago(minutes)))
+                .await
+                .unwrap();
+        }
+
+        let batch_hash = seal_batch(&config, ENTRY_POINT, 2).await.unwrap().unwrap();
+        assert_eq!(cancel_batch(&config, batch_hash).await.unwrap(), 2);
+
+        let sealed = seal_batch(&config, ENTRY_POINT, 2).await.unwrap();
+        assert_eq!(sealed, Some(batch_hash));
+        let pb = store.find_batch(batch_hash).await.unwrap().unwrap();
+        assert_eq!(pb.status, BatchStatus::Received);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use ethers::abi::AbiEncode;
+use ethers::types::{H160, H256};
+use lazy_static::lazy_static;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::model::pool_batch::BatchStatus;
+use crate::service::batch_chain::{chained_pre_state_root, requeue_chain};
+use crate::service::pool::EntryPointContract;
+use crate::service::treasury::send_entry_point_call;
+use crate::store::get_pool_store;
+use crate::upstream::get_upstream;
+
+lazy_static! {
+    // By chain id and EntryPoint, the roots read last
+    static ref STATE_ROOTS: RwLock<HashMap<(u64, H160), StateRoots>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StateRoots {
+    pub entry_point: H160,
+    pub old_state_root: H256,
+    pub cur_state_root: H256,
+}
+
+/// The `_curStateRoot` of `entry_point` read last, `None` before the first
+/// read.
+pub fn current_state_root(chain_id: u64, entry_point: H160) -> Option<H256> {
+    STATE_ROOTS
+        .read()
+        .unwrap()
+        .get(&(chain_id, entry_point))
+        .map(|roots| roots.cur_state_root)
+}
+
+/// Reads the roots of `entry_point` from the chain and keeps them. When the
+/// current root moved, batches handed to a prover against the old one are
+/// handed out again, and a move no batch of ours explains is reported.
+pub async fn refresh_state_roots(
+    config: &Config,
+    entry_point: H160,
+) -> anyhow::Result<StateRoots, anyhow::Error> {
+    let chain_id = config.chain_id();
+    let contract = EntryPointContract::new(entry_point, Arc::new(get_upstream(chain_id)));
+    let roots = StateRoots {
+        entry_point,
+        old_state_root: H256::from(contract.old_state_root().call().await?),
+        cur_state_root: H256::from(contract.cur_state_root().call().await?),
+    };
+
+    let previous = STATE_ROOTS
+        .write()
+        .unwrap()
+        .insert((chain_id, entry_point), roots);
+    let previous = match previous {
+        Some(previous) if previous.cur_state_root != roots.cur_state_root => previous,
+        _ => return Ok(roots),
+    };
+
+    let store = get_pool_store(chain_id);
+    let batches = store
+        .find_batches_created_before(
+            &[BatchStatus::Pending, BatchStatus::Submitting],
+            DateTime::now(),
+            config.pool.max_txs,
+        )
+        .await?;
+
+    // Our handleOps moves the root while its batch is submitting
+    if !batches
+        .iter()
+        .any(|pb| pb.entry_point == entry_point && pb.status == BatchStatus::Submitting)
+    {
+        println!(
+            "State root of EntryPoint {:?} on chain {} moved from {} to {} by another submitter",
+            entry_point,
+            chain_id,
+            previous.cur_state_root.encode_hex(),
+            roots.cur_state_root.encode_hex()
+        );
+    }
+
+    for pb in batches
+        .iter()
+        .filter(|pb| pb.entry_point == entry_point && pb.status == BatchStatus::Pending)
+    {
+        // Requeued with the chain of an earlier one
+        let pb = match store.find_batch(pb.batch_hash).await? {
+            Some(pb) if pb.status == BatchStatus::Pending => pb,
+            _ => continue,
+        };
+        if chained_pre_state_root(config, &pb, roots.cur_state_root).await?
+            != Some(pb.pre_state_root)
+        {
+            println!(
+                "Batch {} is proven against a stale state root, handing it out again",
+                pb.batch_hash.encode_hex()
+            );
+            requeue_chain(config, &pb).await?;
+        }
+    }
+
+    Ok(roots)
+}
+
+/// Follows the roots of every EntryPoint, see `refresh_state_roots`.
+pub async fn follow_state_roots(config: &Config) -> anyhow::Result<Vec<StateRoots>, anyhow::Error> {
+    let mut roots = vec![];
+    for entry_point in config.bundler.entry_points() {
+        roots.push(refresh_state_roots(config, entry_point).await?);
+    }
+
+    Ok(roots)
+}
+
+/// Calls `transferStateRoot(old_state_root, new_state_root)`, then reads the
+/// roots again.
+pub async fn transfer_state_root(
+    config: &Config,
+    old_state_root: H256,
+    new_state_root: H256,
+    entry_point: Option<H160>,
+) -> anyhow::Result<H256, anyhow::Error> {
+    let tx_hash = send_entry_point_call(config, entry_point, |ep, _| {
+        ep.transfer_state_root(old_state_root.into(), new_state_root.into())
+    })
+    .await?;
+
+    refresh_state_roots(
+        config,
+        entry_point.unwrap_or(config.bundler.entry_point_address),
+    )
+    .await?;
+
+    Ok(tx_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::Bytes;
+
+    use super::*;
+    use crate::test_util::{pool_batch, start_node, test_config, TestNode, ENTRY_POINT};
+
+    // Both `_oldStateRoot` and `_curStateRoot` read as `root`
+    fn set_roots(node: &TestNode, root: H256) {
+        node.answer("eth_call", Bytes::from(root.as_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn requeues_batches_proven_against_a_moved_root() {
+        let chain_id = 7001;
+        let node = start_node(chain_id).await;
+        let (config, store) = test_config(chain_id).await;
+        let (first, second) = (H256::repeat_byte(0xa1), H256::repeat_byte(0xa2));
+
+        assert_eq!(current_state_root(chain_id, ENTRY_POINT), None);
+        set_roots(&node, first);
+        let roots = follow_state_roots(&config).await.unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].cur_state_root, first);
+        assert_eq!(current_state_root(chain_id, ENTRY_POINT), Some(first));
+
+        // A stale chain of two, and one proven against the new root
+        let mut stale = pool_batch(1, BatchStatus::Pending, None);
+        stale.pre_state_root = first;
+        stale.post_state_root = H256::repeat_byte(0xb1);
+        let mut child = pool_batch(2, BatchStatus::Pending, Some(stale.batch_hash));
+        child.pre_state_root = stale.post_state_root;
+        child.post_state_root = H256::repeat_byte(0xb2);
+        let mut fresh = pool_batch(3, BatchStatus::Pending, None);
+        fresh.pre_state_root = second;
+        for pb in [&stale, &child, &fresh] {
+            store.insert_batch(pb.clone()).await.unwrap();
+        }
+
+        // Nothing moved yet
+        refresh_state_roots(&config, ENTRY_POINT).await.unwrap();
+        let pb = store.find_batch(stale.batch_hash).await.unwrap().unwrap();
+        assert_eq!(pb.status, BatchStatus::Pending);
+
+        set_roots(&node, second);
+        refresh_state_roots(&config, ENTRY_POINT).await.unwrap();
+        assert_eq!(current_state_root(chain_id, ENTRY_POINT), Some(second));
+        for pb in [&stale, &child] {
+            let pb = store.find_batch(pb.batch_hash).await.unwrap().unwrap();
+            assert_eq!(pb.status, BatchStatus::Received);
+            assert!(pb.pre_state_root.is_zero() && pb.post_state_root.is_zero());
+        }
+        let fresh = store.find_batch(fresh.batch_hash).await.unwrap().unwrap();
+        assert_eq!(fresh.status, BatchStatus::Pending);
+        assert_eq!(fresh.pre_state_root, second);
+    }
+}
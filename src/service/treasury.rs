@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+use ethers::abi::{AbiEncode, Detokenize};
+use ethers::contract::builders::ContractCall;
+use ethers::middleware::SignerMiddleware;
+use ethers::providers::{Middleware, Provider};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{BlockNumber, TransactionRequest, H160, H256, U256, U64};
+use ethers::utils::{format_ether, parse_ether};
+use lazy_static::lazy_static;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::config::Config;
+use crate::miner::{get_miner_pool, MinerLease, MinerReport};
+use crate::model::ledger_entry::{LedgerEntry, LedgerKind};
+use crate::service::pool::EntryPointContract;
+use crate::store::get_pool_store;
+use crate::upstream::{get_upstream, UpstreamClient};
+
+lazy_static! {
+    // By chain id, whether submission is held for lack of funds. Unlike the
+    // admin pause it lifts by itself once a miner account is topped up.
+    static ref FUNDS_LOW: RwLock<HashMap<u64, watch::Sender<bool>>> = RwLock::new(HashMap::new());
+    // By chain id, the turn in `treasury.beneficiaries`
+    static ref BENEFICIARY_TURN: RwLock<HashMap<u64, usize>> = RwLock::new(HashMap::new());
+}
+
+/// Who gets the fees of the next handleOps sent by `miner`: the next of
+/// `treasury.beneficiaries` in turn, or `miner` if none are set.
+pub fn next_beneficiary(config: &Config, miner: H160) -> H160 {
+    let beneficiaries = &config.treasury.beneficiaries;
+    if beneficiaries.is_empty() {
+        return miner;
+    }
+
+    let mut turns = BENEFICIARY_TURN.write().unwrap();
+    let turn = turns.entry(config.chain_id()).or_insert(0);
+    let beneficiary = beneficiaries[*turn % beneficiaries.len()];
+    *turn = turn.wrapping_add(1);
+    beneficiary
+}
+
+fn funds_low(chain_id: u64) -> watch::Sender<bool> {
+    FUNDS_LOW
+        .write()
+        .unwrap()
+        .entry(chain_id)
+        .or_insert_with(|| watch::channel(false).0)
+        .clone()
+}
+
+pub fn is_funds_low(chain_id: u64) -> bool {
+    *funds_low(chain_id).borrow()
+}
+
+/// Returns once a miner account of `chain_id` has enough funds to submit.
+pub async fn wait_funds_restored(chain_id: u64) {
+    let mut low = funds_low(chain_id).subscribe();
+    while *low.borrow_and_update() {
+        // The sender lives in a static, so this never errors
+        let _ = low.changed().await;
+    }
+}
+
+/// Reads the balance of every miner account, alerting on those below
+/// `treasury.alert_balance`. Submission is paused while no account has
+/// `treasury.pause_balance`, and resumes once one has.
+pub async fn check_funds(config: &Config) -> anyhow::Result<(), anyhow::Error> {
+    let chain_id = config.chain_id();
+    let miners = get_miner_pool(chain_id);
+    miners.check_balances(&get_upstream(chain_id)).await;
+
+    let alert_balance = parse_ether(&config.treasury.alert_balance)?;
+    let pause_balance = parse_ether(&config.treasury.pause_balance)?;
+
+    let report = miners.report();
+    for miner in report.iter() {
+        if let Some(balance) = miner.balance.filter(|balance| *balance < alert_balance) {
+            println!(
+                "Treasury alert on chain {}: miner {:?} has {} ether, below {}",
+                chain_id,
+                miner.address,
+                format_ether(balance),
+                config.treasury.alert_balance
+            );
+        }
+    }
+
+    // An unknown balance is not a low one
+    let low = report
+        .iter()
+        .all(|miner| matches!(miner.balance, Some(balance) if balance < pause_balance));
+    let was_low = funds_low(chain_id).send_replace(low);
+    if low != was_low {
+        println!(
+            "Submission {} on chain {}: miner funds {} {} ether",
+            if low { "paused" } else { "resumed" },
+            chain_id,
+            if low { "below" } else { "back above" },
+            config.treasury.pause_balance
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DepositReport {
+    pub entry_point: H160,
+    pub deposit: U256,
+    pub staked: bool,
+    pub stake: U256,
+    pub unstake_delay_sec: u32,
+    pub withdraw_time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TreasuryReport {
+    pub funds_low: bool,
+    pub miners: Vec<MinerReport>,
+    // Of `bundler.miner_address`, on every EntryPoint
+    pub deposits: Vec<DepositReport>,
+}
+
+/// Miner balances, and the deposit and stake of `bundler.miner_address` on
+/// every EntryPoint.
+pub async fn treasury_report(config: &Config) -> anyhow::Result<TreasuryReport, anyhow::Error> {
+    let provider = Arc::new(get_upstream(config.chain_id()));
+
+    let mut deposits = vec![];
+    for entry_point in config.bundler.entry_points() {
+        let info = EntryPointContract::new(entry_point, provider.clone())
+            .get_deposit_info(config.bundler.miner_address)
+            .call()
+            .await?;
+        deposits.push(DepositReport {
+            entry_point,
+            deposit: U256::from(info.deposit),
+            staked: info.staked,
+            stake: U256::from(info.stake),
+            unstake_delay_sec: info.unstake_delay_sec,
+            withdraw_time: info.withdraw_time,
+        });
+    }
+
+    Ok(TreasuryReport {
+        funds_low: is_funds_low(config.chain_id()),
+        miners: get_miner_pool(config.chain_id()).report(),
+        deposits,
+    })
+}
+
+/// Sends an EntryPoint call from `bundler.miner_address` and waits for it
+/// to be mined. `entry_point` defaults to `bundler.entry_point_address`.
+pub async fn send_entry_point_call<F, D>(
+    config: &Config,
+    entry_point: Option<H160>,
+    build: F,
+) -> anyhow::Result<H256, anyhow::Error>
+where
+    F: FnOnce(
+        &EntryPointContract<Provider<UpstreamClient>>,
+        H160,
+    ) -> ContractCall<Provider<UpstreamClient>, D>,
+    D: Detokenize,
+{
+    let entry_point = entry_point.unwrap_or(config.bundler.entry_point_address);
+    if !config.bundler.entry_points().contains(&entry_point) {
+        anyhow::bail!("Unsupported EntryPoint: {:?}", entry_point);
+    }
+
+    let miner = get_miner_pool(config.chain_id())
+        .lease(config.bundler.miner_address)
+        .ok_or_else(|| anyhow!("No miner account for bundler.miner_address"))?;
+    let provider = Arc::new(get_upstream(config.chain_id()));
+
+    let call = build(
+        &EntryPointContract::new(entry_point, provider),
+        miner.address(),
+    );
+    send_from_miner(config, &miner, call.tx).await
+}
+
+// Sends `tx` from `miner` and waits for it to be mined.
+async fn send_from_miner(
+    config: &Config,
+    miner: &MinerLease,
+    tx: TypedTransaction,
+) -> anyhow::Result<H256, anyhow::Error> {
+    let provider = get_upstream(config.chain_id());
+    let client =
+        SignerMiddleware::new_with_provider_chain(provider.clone(), miner.signer()).await?;
+
+    let pending_tx = miner
+        .send_with_nonce(&provider, |nonce| {
+            let mut tx = tx.clone();
+            tx.set_nonce(nonce);
+            let client = &client;
+            async move { Ok(client.send_transaction(tx, None).await?) }
+        })
+        .await?;
+
+    let receipt = pending_tx
+        .await?
+        .ok_or_else(|| anyhow!("Tx was dropped from the mempool"))?;
+    if receipt.status != Some(U64::from(1)) {
+        anyhow::bail!("Tx {} reverted", receipt.transaction_hash.encode_hex());
+    }
+    println!(
+        "Treasury tx on chain {}: {}",
+        config.chain_id(),
+        receipt.transaction_hash.encode_hex()
+    );
+
+    Ok(receipt.transaction_hash)
+}
+
+/// Tops up the EntryPoint deposit of `bundler.miner_address` by `amount`
+/// ether.
+pub async fn deposit_to(
+    config: &Config,
+    amount: &str,
+    entry_point: Option<H160>,
+) -> anyhow::Result<H256, anyhow::Error> {
+    let amount = parse_ether(amount)?;
+    send_entry_point_call(config, entry_point, |ep, miner| {
+        ep.deposit_to(miner).value(amount)
+    })
+    .await
+}
+
+/// Stakes `amount` more ether, locked for `unstake_delay_sec` once unlocked.
+pub async fn add_stake(
+    config: &Config,
+    unstake_delay_sec: u32,
+    amount: &str,
+    entry_point: Option<H160>,
+) -> anyhow::Result<H256, anyhow::Error> {
+    let amount = parse_ether(amount)?;
+    send_entry_point_call(config, entry_point, |ep, _| {
+        ep.add_stake(unstake_delay_sec).value(amount)
+    })
+    .await
+}
+
+/// Starts the unstake delay, after which `withdraw_stake` can run.
+pub async fn unlock_stake(
+    config: &Config,
+    entry_point: Option<H160>,
+) -> anyhow::Result<H256, anyhow::Error> {
+    send_entry_point_call(config, entry_point, |ep, _| ep.unlock_stake()).await
+}
+
+/// Withdraws the whole unlocked stake to `to`, `bundler.miner_address` if
+/// not given.
+pub async fn withdraw_stake(
+    config: &Config,
+    to: Option<H160>,
+    entry_point: Option<H160>,
+) -> anyhow::Result<H256, anyhow::Error> {
+    send_entry_point_call(config, entry_point, |ep, miner| {
+        ep.withdraw_stake(to.unwrap_or(miner))
+    })
+    .await
+}
+
+/// Withdraws `amount` ether of the deposit to `to`, `bundler.miner_address`
+/// if not given.
+pub async fn withdraw_to(
+    config: &Config,
+    amount: &str,
+    to: Option<H160>,
+    entry_point: Option<H160>,
+) -> anyhow::Result<H256, anyhow::Error> {
+    let amount = parse_ether(amount)?;
+    send_entry_point_call(config, entry_point, |ep, miner| {
+        ep.withdraw_to(to.unwrap_or(miner), amount)
+    })
+    .await
+}
+
+// Gas of a plain ether transfer
+const TRANSFER_GAS: u64 = 21000;
+
+/// Moves the balance of every miner account above
+/// `treasury.sweep_threshold` to `treasury.sweep_to`, net of gas, and
+/// records each move in the ledger.
+pub async fn sweep_excess(config: &Config) -> anyhow::Result<Vec<LedgerEntry>, anyhow::Error> {
+    let treasury = &config.treasury;
+    if treasury.sweep_to.is_zero() {
+        anyhow::bail!("treasury.sweep_to is not set");
+    }
+
+    let chain_id = config.chain_id();
+    let threshold = parse_ether(&treasury.sweep_threshold)?;
+    let provider = get_upstream(chain_id);
+    let miners = get_miner_pool(chain_id);
+
+    let mut entries = vec![];
+    for report in miners.report() {
+        // Left for the next run while it submits a batch
+        let miner = match miners.lease_idle(report.address) {
+            Some(miner) => miner,
+            None => continue,
+        };
+
+        // Fresh, the last check may be an hour old, and net of its txs
+        // not mined yet
+        let balance = provider
+            .get_balance(miner.address(), Some(BlockNumber::Pending.into()))
+            .await?;
+        let gas_price = provider.get_gas_price().await?;
+        let fee = gas_price * TRANSFER_GAS;
+        if balance <= threshold + fee {
+            continue;
+        }
+        let amount = balance - threshold - fee;
+
+        // Legacy, so the fee is exactly the one left out of `amount`
+        let tx = TransactionRequest::new()
+            .to(treasury.sweep_to)
+            .value(amount)
+            .gas(TRANSFER_GAS)
+            .gas_price(gas_price);
+        let tx_hash = match send_from_miner(config, &miner, tx.into()).await {
+            Ok(tx_hash) => tx_hash,
+            Err(err) => {
+                println!("Sweep of miner {:?} failed: {}", miner.address(), err);
+                continue;
+            }
+        };
+
+        let entry = LedgerEntry {
+            tx_hash,
+            kind: LedgerKind::Sweep,
+            from: miner.address(),
+            to: treasury.sweep_to,
+            amount,
+            created_at: DateTime::now(),
+        };
+        get_pool_store(chain_id)
+            .insert_ledger_entry(entry.clone())
+            .await?;
+        println!(
+            "Swept {} ether from miner {:?} to {:?} on chain {}",
+            format_ether(amount),
+            entry.from,
+            entry.to,
+            chain_id
+        );
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use ethers::types::{Transaction, TransactionReceipt};
+
+    use super::*;
+    use crate::miner::init_miner_pool;
+    use crate::test_util::{miner, start_node, test_config, with_miners, TestNode};
+
+    fn set_balance(node: &TestNode, n: u8, ether: &str) {
+        node.answer_for(
+            "eth_getBalance",
+            miner(n).address,
+            parse_ether(ether).unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn pauses_while_every_miner_is_below_the_pause_balance() {
+        let chain_id = 6001;
+        let node = start_node(chain_id).await;
+        let (mut config, _) = test_config(chain_id).await;
+        with_miners(&mut config.bundler, 2);
+        config.treasury.alert_balance = String::from("1");
+        config.treasury.pause_balance = String::from("0.1");
+        init_miner_pool(chain_id, &config.bundler).await.unwrap();
+
+        // Balances not read yet aren't low
+        check_funds(&config).await.unwrap();
+        assert!(!is_funds_low(chain_id));
+
+        set_balance(&node, 1, "0.05");
+        set_balance(&node, 2, "0.5");
+        check_funds(&config).await.unwrap();
+        assert!(!is_funds_low(chain_id));
+        let report = get_miner_pool(chain_id).report();
+        assert_eq!(report[0].balance, Some(parse_ether("0.05").unwrap()));
+
+        set_balance(&node, 2, "0.01");
+        check_funds(&config).await.unwrap();
+        assert!(is_funds_low(chain_id));
+        let restored = tokio::spawn(wait_funds_restored(chain_id));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!restored.is_finished());
+
+        // Topping up one account is enough
+        set_balance(&node, 2, "2");
+        check_funds(&config).await.unwrap();
+        assert!(!is_funds_low(chain_id));
+        tokio::time::timeout(Duration::from_secs(1), restored)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn sweep_needs_a_destination() {
+        let (config, _) = test_config(6002).await;
+        assert!(sweep_excess(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sweeps_idle_miners_above_the_threshold() {
+        let chain_id = 6003;
+        let node = start_node(chain_id).await;
+        let (mut config, store) = test_config(chain_id).await;
+        with_miners(&mut config.bundler, 3);
+        config.treasury.sweep_to = H160::repeat_byte(0xc0);
+        config.treasury.sweep_threshold = String::from("1");
+        init_miner_pool(chain_id, &config.bundler).await.unwrap();
+
+        // 1 is below the threshold, 2 above it and 3 above it but busy
+        set_balance(&node, 1, "1");
+        set_balance(&node, 2, "3");
+        set_balance(&node, 3, "3");
+        let busy = get_miner_pool(chain_id).lease(miner(3).address).unwrap();
+
+        let gas_price = U256::from(1_000_000_000);
+        let tx_hash = H256::repeat_byte(0x5e);
+        node.answer("eth_gasPrice", gas_price);
+        node.answer("eth_getTransactionCount", U256::zero());
+        node.answer("eth_sendRawTransaction", tx_hash);
+        node.answer(
+            "eth_getTransactionByHash",
+            Transaction {
+                hash: tx_hash,
+                block_number: Some(U64::from(1)),
+                ..Transaction::default()
+            },
+        );
+        node.answer(
+            "eth_getTransactionReceipt",
+            TransactionReceipt {
+                transaction_hash: tx_hash,
+                block_number: Some(U64::from(1)),
+                status: Some(U64::from(1)),
+                ..TransactionReceipt::default()
+            },
+        );
+
+        let entries = sweep_excess(&config).await.unwrap();
+        let amount = parse_ether("2").unwrap() - gas_price * TRANSFER_GAS;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].from, miner(2).address);
+        assert_eq!(entries[0].to, config.treasury.sweep_to);
+        assert_eq!(entries[0].amount, amount);
+        assert_eq!(node.calls("eth_sendRawTransaction").len(), 1);
+        assert_eq!(store.list_ledger(10).await.unwrap().len(), 1);
+
+        // Its lease is given back, the busy one keeps its own
+        let report = get_miner_pool(chain_id).report();
+        assert_eq!(report[1].in_flight, 0);
+        assert_eq!(report[2].in_flight, 1);
+        drop(busy);
+    }
+}
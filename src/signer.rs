@@ -0,0 +1,454 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ethers::providers::{Http, HttpClientError, JsonRpcClient};
+use ethers::signers::{to_eip155_v, LocalWallet, Signer, WalletError};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::transaction::eip712::Eip712;
+use ethers::types::{Address, Bytes, Signature, H160};
+use ethers::utils::rlp::Rlp;
+use serde_json::Value;
+
+use crate::config::MinerConfig;
+
+#[derive(Debug)]
+pub enum SignerError {
+    Wallet(WalletError),
+    // The remote signer did not answer, or answered with an error
+    Remote(HttpClientError),
+    // The remote signer answered something we can't use
+    Invalid(String),
+}
+
+impl Display for SignerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignerError::Wallet(err) => write!(f, "{}", err),
+            SignerError::Remote(err) => write!(f, "Remote signer failed: {}", err),
+            SignerError::Invalid(reason) => write!(f, "Remote signer: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SignerError {}
+
+/// Signs over JSON-RPC for a key it keeps, like Clef, Web3Signer or a KMS
+/// proxy. Only `eth_accounts`, `eth_sign` and `eth_signTransaction` are used.
+#[derive(Clone, Debug)]
+pub struct RemoteSigner {
+    http: Http,
+    address: Address,
+    chain_id: u64,
+}
+
+impl RemoteSigner {
+    pub fn new(url: &str, timeout_ms: u64, address: Address) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(timeout_ms))
+            .connect_timeout(Duration::from_millis(timeout_ms))
+            .build()?;
+
+        Ok(Self {
+            http: Http::new_with_client(reqwest::Url::parse(url)?, client),
+            address,
+            chain_id: 1,
+        })
+    }
+
+    /// The accounts the remote signer holds keys for.
+    pub async fn accounts(&self) -> Result<Vec<Address>, SignerError> {
+        self.http
+            .request("eth_accounts", ())
+            .await
+            .map_err(SignerError::Remote)
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> Result<Signature, SignerError> {
+        let mut tx = tx.clone();
+        tx.set_from(self.address);
+        if tx.chain_id().is_none() {
+            tx.set_chain_id(self.chain_id);
+        }
+
+        // ethers leaves `chainId` out, the signer must not pick its own
+        let mut params = serde_json::to_value(&tx)
+            .map_err(|err| SignerError::Invalid(format!("bad tx: {}", err)))?;
+        params["chainId"] = serde_json::json!(tx.chain_id());
+
+        // The raw tx, alone or next to the decoded one as Clef answers
+        let result: Value = self
+            .http
+            .request("eth_signTransaction", [params])
+            .await
+            .map_err(SignerError::Remote)?;
+        let raw = match result.get("raw").unwrap_or(&result) {
+            Value::String(raw) => raw
+                .parse::<Bytes>()
+                .map_err(|err| SignerError::Invalid(format!("bad raw tx: {}", err)))?,
+            _ => return Err(SignerError::Invalid(format!("bad answer: {}", result))),
+        };
+
+        let (_, mut signature) = TypedTransaction::decode_signed(&Rlp::new(&raw))
+            .map_err(|err| SignerError::Invalid(format!("bad raw tx: {}", err)))?;
+        // Same `v` as a local wallet gives
+        if !matches!(tx, TypedTransaction::Legacy(_)) {
+            let chain_id = tx.chain_id().unwrap_or_default().as_u64();
+            signature.v = to_eip155_v(signature.v as u8, chain_id);
+        }
+
+        // Checks it signed this very tx, with our key
+        signature
+            .verify(tx.sighash(), self.address)
+            .map_err(|_| SignerError::Invalid(String::from("signed another tx or key")))?;
+
+        Ok(signature)
+    }
+
+    async fn sign_message(&self, message: &[u8]) -> Result<Signature, SignerError> {
+        let result: Bytes = self
+            .http
+            .request("eth_sign", (self.address, Bytes::from(message.to_vec())))
+            .await
+            .map_err(SignerError::Remote)?;
+
+        let signature = Signature::try_from(result.as_ref())
+            .map_err(|err| SignerError::Invalid(format!("bad signature: {}", err)))?;
+        signature
+            .verify(message, self.address)
+            .map_err(|_| SignerError::Invalid(String::from("signed with another key")))?;
+
+        Ok(signature)
+    }
+}
+
+/// The key of a miner account, picked by its `signer.backend`: `key` for
+/// the raw private key (dev only), `keystore` for an encrypted JSON keystore
+/// unlocked at startup, `remote` for a `RemoteSigner`.
+#[derive(Clone, Debug)]
+pub enum BundlerSigner {
+    Local(LocalWallet),
+    Remote(RemoteSigner),
+}
+
+impl BundlerSigner {
+    /// Opens the configured signer. A keystore is decrypted here, which
+    /// takes a few seconds.
+    pub fn from_config(config: &MinerConfig) -> anyhow::Result<Self> {
+        let signer = &config.signer;
+
+        match signer.backend.as_str() {
+            "key" => Ok(BundlerSigner::Local(config.private_key.parse()?)),
+            "keystore" => {
+                let wallet =
+                    LocalWallet::decrypt_keystore(&signer.keystore_path, &signer.keystore_password)
+                        .map_err(|err| {
+                            anyhow::anyhow!(
+                                "Unlocking keystore {} failed: {}",
+                                signer.keystore_path,
+                                err
+                            )
+                        })?;
+                Ok(BundlerSigner::Local(wallet))
+            }
+            "remote" => Ok(BundlerSigner::Remote(RemoteSigner::new(
+                &signer.remote_url,
+                signer.remote_timeout_ms,
+                config.address,
+            )?)),
+            backend => anyhow::bail!("Unsupported signer backend: {}", backend),
+        }
+    }
+
+    /// Whether the signer can sign for `address`. A remote signer is asked
+    /// for the accounts it holds.
+    pub async fn check_address(&self, address: H160) -> anyhow::Result<()> {
+        match self {
+            BundlerSigner::Local(wallet) => {
+                if wallet.address() != address {
+                    anyhow::bail!(
+                        "Signer address {:?} does not match miner address {:?}",
+                        wallet.address(),
+                        address
+                    );
+                }
+            }
+            BundlerSigner::Remote(remote) => {
+                if !remote.accounts().await?.contains(&address) {
+                    anyhow::bail!("Remote signer holds no key for miner address {:?}", address);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Signer for BundlerSigner {
+    type Error = SignerError;
+
+    async fn sign_message<S: Send + Sync + AsRef<[u8]>>(
+        &self,
+        message: S,
+    ) -> Result<Signature, Self::Error> {
+        match self {
+            BundlerSigner::Local(wallet) => wallet
+                .sign_message(message)
+                .await
+                .map_err(SignerError::Wallet),
+            BundlerSigner::Remote(remote) => remote.sign_message(message.as_ref()).await,
+        }
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> Result<Signature, Self::Error> {
+        match self {
+            BundlerSigner::Local(wallet) => wallet
+                .sign_transaction(tx)
+                .await
+                .map_err(SignerError::Wallet),
+            BundlerSigner::Remote(remote) => remote.sign_transaction(tx).await,
+        }
+    }
+
+    async fn sign_typed_data<T: Eip712 + Send + Sync>(
+        &self,
+        payload: &T,
+    ) -> Result<Signature, Self::Error> {
+        match self {
+            BundlerSigner::Local(wallet) => wallet
+                .sign_typed_data(payload)
+                .await
+                .map_err(SignerError::Wallet),
+            // The bundler never signs typed data
+            BundlerSigner::Remote(_) => Err(SignerError::Invalid(String::from(
+                "typed data is not supported",
+            ))),
+        }
+    }
+
+    fn address(&self) -> Address {
+        match self {
+            BundlerSigner::Local(wallet) => wallet.address(),
+            BundlerSigner::Remote(remote) => remote.address,
+        }
+    }
+
+    fn chain_id(&self) -> u64 {
+        match self {
+            BundlerSigner::Local(wallet) => wallet.chain_id(),
+            BundlerSigner::Remote(remote) => remote.chain_id,
+        }
+    }
+
+    fn with_chain_id<T: Into<u64>>(self, chain_id: T) -> Self {
+        match self {
+            BundlerSigner::Local(wallet) => BundlerSigner::Local(wallet.with_chain_id(chain_id)),
+            BundlerSigner::Remote(mut remote) => {
+                remote.chain_id = chain_id.into();
+                BundlerSigner::Remote(remote)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ethers::types::{Eip1559TransactionRequest, TransactionRequest};
+    use jsonrpsee::server::{RpcModule, ServerBuilder};
+    use jsonrpsee::types::error::CallError;
+
+    use super::*;
+    use crate::config::SignerConfig;
+
+    // Anvil's first two dev keys
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const OTHER_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    fn wallet(key: &str) -> LocalWallet {
+        key.parse().unwrap()
+    }
+
+    fn txs() -> Vec<TypedTransaction> {
+        let to: Address = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8"
+            .parse()
+            .unwrap();
+        vec![
+            TransactionRequest::new()
+                .to(to)
+                .value(1000)
+                .gas(21000)
+                .gas_price(7)
+                .nonce(3)
+                .chain_id(5)
+                .into(),
+            Eip1559TransactionRequest::new()
+                .to(to)
+                .value(1000)
+                .gas(21000)
+                .max_fee_per_gas(9)
+                .max_priority_fee_per_gas(2)
+                .nonce(4)
+                .chain_id(5)
+                .into(),
+        ]
+    }
+
+    // A remote signer holding `wallet`'s key, answering like Clef
+    async fn start_remote_signer(wallet: LocalWallet) -> String {
+        let mut module = RpcModule::new(wallet);
+        module
+            .register_method("eth_accounts", |_, wallet| Ok(vec![wallet.address()]))
+            .unwrap();
+        module
+            .register_async_method("eth_sign", |params, wallet| async move {
+                let (_, message): (Address, Bytes) = params.parse()?;
+                let signature = wallet
+                    .sign_message(message.as_ref())
+                    .await
+                    .map_err(|err| CallError::Failed(err.into()))?;
+                Ok(Bytes::from(signature.to_vec()))
+            })
+            .unwrap();
+        module
+            .register_async_method("eth_signTransaction", |params, wallet| async move {
+                let (tx,): (TypedTransaction,) = params.parse()?;
+                let signature = wallet
+                    .sign_transaction(&tx)
+                    .await
+                    .map_err(|err| CallError::Failed(err.into()))?;
+                Ok(serde_json::json!({"raw": tx.rlp_signed(&signature), "tx": tx}))
+            })
+            .unwrap();
+
+        let server = ServerBuilder::default().build("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+        tokio::spawn(server.start(module).unwrap().stopped());
+        url
+    }
+
+    fn remote_config(url: &str, address: Address) -> MinerConfig {
+        MinerConfig {
+            address,
+            private_key: String::new(),
+            signer: SignerConfig {
+                backend: String::from("remote"),
+                remote_url: url.to_string(),
+                ..SignerConfig::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn key_signer() {
+        let address = wallet(KEY).address();
+        let config = MinerConfig {
+            address,
+            private_key: KEY.to_string(),
+            signer: SignerConfig::default(),
+        };
+        let signer = BundlerSigner::from_config(&config)
+            .unwrap()
+            .with_chain_id(5u64);
+
+        signer.check_address(address).await.unwrap();
+        assert!(signer
+            .check_address(wallet(OTHER_KEY).address())
+            .await
+            .is_err());
+        for tx in txs() {
+            let signature = signer.sign_transaction(&tx).await.unwrap();
+            signature.verify(tx.sighash(), address).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn keystore_signer() {
+        let dir = std::env::temp_dir().join(format!("bundler-keystore-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (created, _) = LocalWallet::new_keystore(
+            &dir,
+            &mut ethers::core::rand::thread_rng(),
+            "secret",
+            Some("miner"),
+        )
+        .unwrap();
+
+        let mut config = MinerConfig {
+            address: created.address(),
+            private_key: String::new(),
+            signer: SignerConfig {
+                backend: String::from("keystore"),
+                keystore_path: dir.join("miner").display().to_string(),
+                keystore_password: String::from("secret"),
+                ..SignerConfig::default()
+            },
+        };
+        let signer = BundlerSigner::from_config(&config).unwrap();
+        assert_eq!(signer.address(), created.address());
+        signer.check_address(created.address()).await.unwrap();
+
+        config.signer.keystore_password = String::from("wrong");
+        assert!(BundlerSigner::from_config(&config).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_signer() {
+        let local = wallet(KEY).with_chain_id(5u64);
+        let url = start_remote_signer(local.clone()).await;
+        let signer = BundlerSigner::from_config(&remote_config(&url, local.address()))
+            .unwrap()
+            .with_chain_id(5u64);
+
+        signer.check_address(local.address()).await.unwrap();
+        assert!(signer
+            .check_address(wallet(OTHER_KEY).address())
+            .await
+            .is_err());
+
+        // Typed txs come back with a y-parity `v`, normalized to the local one
+        for tx in txs() {
+            let signature = signer.sign_transaction(&tx).await.unwrap();
+            assert_eq!(signature, local.sign_transaction(&tx).await.unwrap());
+            signature.verify(tx.sighash(), local.address()).unwrap();
+        }
+
+        let signature = signer.sign_message("hello").await.unwrap();
+        assert_eq!(signature, local.sign_message("hello").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn remote_signer_with_another_key() {
+        let url = start_remote_signer(wallet(OTHER_KEY)).await;
+        let address = wallet(KEY).address();
+        let signer = BundlerSigner::from_config(&remote_config(&url, address))
+            .unwrap()
+            .with_chain_id(5u64);
+
+        assert!(signer.check_address(address).await.is_err());
+        for tx in txs() {
+            let err = signer.sign_transaction(&tx).await.unwrap_err();
+            assert!(matches!(err, SignerError::Invalid(_)), "{}", err);
+        }
+        assert!(signer.sign_message("hello").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn remote_signer_down() {
+        let signer =
+            BundlerSigner::from_config(&remote_config("http://127.0.0.1:1", Address::zero()))
+                .unwrap();
+
+        let err = signer.sign_transaction(&txs()[0]).await.unwrap_err();
+        assert!(matches!(err, SignerError::Remote(_)), "{}", err);
+        assert!(signer.check_address(Address::zero()).await.is_err());
+    }
+
+    #[test]
+    fn unknown_backend() {
+        let mut config = remote_config("http://127.0.0.1:1", Address::zero());
+        config.signer.backend = String::from("hsm");
+        assert!(BundlerSigner::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use ethers::types::{Bytes, H160, H256, U256};
+use mongodb::bson::DateTime;
+use tokio::sync::RwLock;
+
+use crate::model::ledger_entry::LedgerEntry;
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::store::PoolStore;
+
+#[derive(Default)]
+struct MemoryPool {
+    txs: HashMap<H256, PoolTx>,
+    // Kept in insertion order, like a Mongo collection scan
+    batches: Vec<PoolBatch>,
+    archived_txs: HashMap<H256, PoolTx>,
+    archived_batches: HashMap<H256, PoolBatch>,
+    // Oldest first
+    ledger: Vec<LedgerEntry>,
+    schema_version: u32,
+}
+
+/// Non-persistent `PoolStore`, for tests and single-node dev runs.
+#[derive(Default)]
+pub struct MemoryPoolStore {
+    pool: RwLock<MemoryPool>,
+}
+
+impl MemoryPoolStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl PoolStore for MemoryPoolStore {
+    async fn ensure_indexes(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn schema_version(&self) -> anyhow::Result<u32> {
+        Ok(self.pool.read().await.schema_version)
+    }
+
+    async fn set_schema_version(&self, version: u32) -> anyhow::Result<()> {
+        self.pool.write().await.schema_version = version;
+        Ok(())
+    }
+
+    async fn list_txs(&self) -> anyhow::Result<Vec<PoolTx>> {
+        let mut txs: Vec<PoolTx> = self.pool.read().await.txs.values().cloned().collect();
+        txs.sort_by_key(|pt| pt.created_at);
+        Ok(txs)
+    }
+
+    async fn list_batches(&self) -> anyhow::Result<Vec<PoolBatch>> {
+        Ok(self.pool.read().await.batches.clone())
+    }
+
+    async fn find_tx(&self, tx_hash: H256) -> anyhow::Result<Option<PoolTx>> {
+        Ok(self.pool.read().await.txs.get(&tx_hash).cloned())
+    }
+
+    async fn find_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<Vec<PoolTx>> {
+        let pool = self.pool.read().await;
+        Ok(tx_hashes
+            .iter()
+            .filter_map(|h| pool.txs.get(h).cloned())
+            .collect())
+    }
+
+    async fn find_txs_by_status(
+        &self,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let pool = self.pool.read().await;
+        let mut txs: Vec<PoolTx> = pool
+            .txs
+            .values()
+            .filter(|pt| pt.status == status)
+            .cloned()
+            .collect();
+        txs.sort_by_key(|pt| pt.created_at);
+        txs.truncate(limit);
+        Ok(txs)
+    }
+
+    async fn find_txs_by_entry_point(
+        &self,
+        entry_point: H160,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let mut txs = self.find_txs_by_status(status, usize::MAX).await?;
+        txs.retain(|pt| pt.entry_point == entry_point);
+        txs.truncate(limit);
+        Ok(txs)
+    }
+
+    async fn find_txs_created_before(
+        &self,
+        status: TxStatus,
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let mut txs = self.find_txs_by_status(status, usize::MAX).await?;
+        txs.retain(|pt| pt.created_at < before);
+        txs.truncate(limit);
+        Ok(txs)
+    }
+
+    async fn find_txs_from(
+        &self,
+        tx_from: H160,
+        statuses: &[TxStatus],
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let pool = self.pool.read().await;
+        let mut txs: Vec<PoolTx> = pool
+            .txs
+            .values()
+            .filter(|pt| pt.tx_from == tx_from && statuses.contains(&pt.status))
+            .cloned()
+            .collect();
+        txs.sort_by_key(|pt| pt.created_at);
+        Ok(txs)
+    }
+
+    async fn count_txs(&self, status: TxStatus, tx_from: Option<H160>) -> anyhow::Result<usize> {
+        let pool = self.pool.read().await;
+        Ok(pool
+            .txs
+            .values()
+            .filter(|pt| pt.status == status && (tx_from.is_none() || tx_from == Some(pt.tx_from)))
+            .count())
+    }
+
+    async fn insert_tx(&self, pool_tx: PoolTx) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if pool.txs.contains_key(&pool_tx.tx_hash) {
+            anyhow::bail!("Duplicate tx: {:?}", pool_tx.tx_hash);
+        }
+        pool.txs.insert(pool_tx.tx_hash, pool_tx);
+        Ok(())
+    }
+
+    async fn update_txs_status(&self, tx_hashes: &[H256], status: TxStatus) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        for h in tx_hashes {
+            if let Some(pt) = pool.txs.get_mut(h) {
+                pt.status = status;
+            }
+        }
+        Ok(())
+    }
+
+    async fn update_txs_entry_point(
+        &self,
+        tx_hashes: &[H256],
+        entry_point: H160,
+    ) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        for h in tx_hashes {
+            if let Some(pt) = pool.txs.get_mut(h) {
+                pt.entry_point = entry_point;
+            }
+        }
+        Ok(())
+    }
+
+    async fn evict_txs(&self, tx_hashes: &[H256], reason: &str) -> anyhow::Result<usize> {
+        let mut pool = self.pool.write().await;
+        let mut evicted = 0;
+        for h in tx_hashes {
+            if let Some(pt) = pool
+                .txs
+                .get_mut(h)
+                .filter(|pt| pt.status == TxStatus::Received)
+            {
+                pt.status = TxStatus::Evicted;
+                pt.evict_reason = Some(reason.to_string());
+                evicted += 1;
+            }
+        }
+        Ok(evicted)
+    }
+
+    async fn archive_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        for h in tx_hashes {
+            if let Some(pt) = pool.txs.remove(h) {
+                pool.archived_txs.insert(*h, pt);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "embedded")]
+    async fn list_archived_txs(&self) -> anyhow::Result<Vec<PoolTx>> {
+        let pool = self.pool.read().await;
+        Ok(pool.archived_txs.values().cloned().collect())
+    }
+
+    async fn delete_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        for h in tx_hashes {
+            pool.txs.remove(h);
+        }
+        Ok(())
+    }
+
+    async fn claim_txs(&self, tx_hashes: &[H256], batch_hash: H256) -> anyhow::Result<usize> {
+        let mut pool = self.pool.write().await;
+        let mut claimed = 0;
+        for h in tx_hashes {
+            if let Some(pt) = pool
+                .txs
+                .get_mut(h)
+                .filter(|pt| pt.status == TxStatus::Received)
+            {
+                pt.status = TxStatus::Pending;
+                pt.batch_hash = Some(batch_hash);
+                claimed += 1;
+            }
+        }
+        Ok(claimed)
+    }
+
+    async fn release_txs(&self, batch_hash: H256) -> anyhow::Result<usize> {
+        let mut pool = self.pool.write().await;
+        let mut released = 0;
+        for pt in pool.txs.values_mut() {
+            if (pt.status == TxStatus::Pending || pt.status == TxStatus::Failed)
+                && pt.batch_hash == Some(batch_hash)
+            {
+                pt.status = TxStatus::Received;
+                pt.batch_hash = None;
+                released += 1;
+            }
+        }
+        Ok(released)
+    }
+
+    async fn find_batch(&self, batch_hash: H256) -> anyhow::Result<Option<PoolBatch>> {
+        let pool = self.pool.read().await;
+        Ok(pool
+            .batches
+            .iter()
+            .find(|pb| pb.batch_hash == batch_hash)
+            .cloned())
+    }
+
+    async fn insert_batch(&self, pool_batch: PoolBatch) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if pool
+            .batches
+            .iter()
+            .any(|pb| pb.batch_hash == pool_batch.batch_hash)
+        {
+            anyhow::bail!("Duplicate batch: {:?}", pool_batch.batch_hash);
+        }
+        pool.batches.push(pool_batch);
+        Ok(())
+    }
+
+    async fn find_batches_created_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>> {
+        let pool = self.pool.read().await;
+        Ok(pool
+            .batches
+            .iter()
+            .filter(|pb| statuses.contains(&pb.status) && pb.created_at < before)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn find_batches_updated_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>> {
+        let pool = self.pool.read().await;
+        Ok(pool
+            .batches
+            .iter()
+            .filter(|pb| statuses.contains(&pb.status) && pb.updated_at() < before)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_batch(&self, batch_hash: H256) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        pool.batches.retain(|pb| pb.batch_hash != batch_hash);
+        Ok(())
+    }
+
+    async fn archive_batch(&self, batch_hash: H256) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        let Some(i) = pool.batches.iter().position(|pb| pb.batch_hash == batch_hash) else {
+            return Ok(());
+        };
+
+        let pb = pool.batches.remove(i);
+        for h in pb.tx_hash_list.iter() {
+            if let Some(pt) = pool.txs.remove(h) {
+                pool.archived_txs.insert(*h, pt);
+            }
+        }
+        pool.archived_batches.insert(batch_hash, pb);
+        Ok(())
+    }
+
+    #[cfg(feature = "embedded")]
+    async fn list_archived_batches(&self) -> anyhow::Result<Vec<PoolBatch>> {
+        let pool = self.pool.read().await;
+        Ok(pool.archived_batches.values().cloned().collect())
+    }
+
+    async fn update_batch_status(
+        &self,
+        batch_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if let Some(pb) = pool
+            .batches
+            .iter_mut()
+            .find(|pb| pb.batch_hash == batch_hash)
+        {
+            pb.status = status;
+            pb.updated_at = Some(DateTime::now());
+        }
+        Ok(())
+    }
+
+    async fn update_batch_entry_point(
+        &self,
+        batch_hash: H256,
+        entry_point: H160,
+    ) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if let Some(pb) = pool
+            .batches
+            .iter_mut()
+            .find(|pb| pb.batch_hash == batch_hash)
+        {
+            pb.entry_point = entry_point;
+        }
+        Ok(())
+    }
+
+    async fn update_batch_miner(&self, batch_hash: H256, miner: H160) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if let Some(pb) = pool
+            .batches
+            .iter_mut()
+            .find(|pb| pb.batch_hash == batch_hash)
+        {
+            pb.miner = miner;
+        }
+        Ok(())
+    }
+
+    async fn update_batch_state_roots(
+        &self,
+        batch_hash: H256,
+        pre_state_root: H256,
+        post_state_root: H256,
+    ) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if let Some(pb) = pool
+            .batches
+            .iter_mut()
+            .find(|pb| pb.batch_hash == batch_hash)
+        {
+            pb.pre_state_root = pre_state_root;
+            pb.post_state_root = post_state_root;
+        }
+        Ok(())
+    }
+
+    async fn update_batch_proof(
+        &self,
+        batch_hash: H256,
+        zk_proof: Bytes,
+        zk_pub_inputs: Vec<U256>,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if let Some(pb) = pool
+            .batches
+            .iter_mut()
+            .find(|pb| pb.batch_hash == batch_hash)
+        {
+            pb.zk_proof = Some(zk_proof);
+            pb.zk_pub_inputs = zk_pub_inputs;
+            pb.status = status;
+            pb.updated_at = Some(DateTime::now());
+        }
+        Ok(())
+    }
+
+    async fn update_batch_send_tx_hash(
+        &self,
+        batch_hash: H256,
+        send_tx_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        let mut pool = self.pool.write().await;
+        if let Some(pb) = pool
+            .batches
+            .iter_mut()
+            .find(|pb| pb.batch_hash == batch_hash)
+        {
+            pb.send_tx_hash = send_tx_hash;
+            pb.status = status;
+            pb.updated_at = Some(DateTime::now());
+        }
+        Ok(())
+    }
+
+    async fn insert_ledger_entry(&self, entry: LedgerEntry) -> anyhow::Result<()> {
+        self.pool.write().await.ledger.push(entry);
+        Ok(())
+    }
+
+    async fn list_ledger(&self, limit: usize) -> anyhow::Result<Vec<LedgerEntry>> {
+        let pool = self.pool.read().await;
+        Ok(pool.ledger.iter().rev().take(limit).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{pool_batch, pool_tx, ENTRY_POINT};
+
+    fn ms(millis: i64) -> DateTime {
+        DateTime::from_millis(millis)
+    }
+
+    #[tokio::test]
+    async fn finds_txs_oldest_first() {
+        let store = MemoryPoolStore::new();
+        let newer = pool_tx(1, 0, ms(2_000));
+        let older = pool_tx(2, 0, ms(1_000));
+        let mut other = pool_tx(3, 0, ms(500));
+        other.entry_point = H160::repeat_byte(0x99);
+        for pt in [newer.clone(), older.clone(), other.clone()] {
+            store.insert_tx(pt).await.unwrap();
+        }
+
+        let hashes = |txs: Vec<PoolTx>| txs.iter().map(|pt| pt.tx_hash).collect::<Vec<_>>();
+        assert_eq!(
+            hashes(
+                store
+                    .find_txs_by_status(TxStatus::Received, 10)
+                    .await
+                    .unwrap()
+            ),
+            vec![other.tx_hash, older.tx_hash, newer.tx_hash]
+        );
+        assert_eq!(
+            hashes(
+                store
+                    .find_txs_by_entry_point(ENTRY_POINT, TxStatus::Received, 1)
+                    .await
+                    .unwrap()
+            ),
+            vec![older.tx_hash]
+        );
+        assert_eq!(
+            hashes(
+                store
+                    .find_txs_created_before(TxStatus::Received, ms(2_000), 10)
+                    .await
+                    .unwrap()
+            ),
+            vec![other.tx_hash, older.tx_hash]
+        );
+        assert_eq!(
+            store
+                .count_txs(TxStatus::Received, Some(newer.tx_from))
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_duplicate_txs() {
+        let store = MemoryPoolStore::new();
+        let pt = pool_tx(1, 0, ms(1_000));
+        store.insert_tx(pt.clone()).await.unwrap();
+
+        let mut again = pt.clone();
+        again.status = TxStatus::Evicted;
+        assert!(store.insert_tx(again).await.is_err());
+        assert_eq!(store.find_tx(pt.tx_hash).await.unwrap(), Some(pt));
+    }
+
+    #[tokio::test]
+    async fn claims_only_received_txs() {
+        let store = MemoryPoolStore::new();
+        let received = pool_tx(1, 0, ms(1_000));
+        let mut evicted = pool_tx(2, 0, ms(1_000));
+        evicted.status = TxStatus::Evicted;
+        for pt in [received.clone(), evicted.clone()] {
+            store.insert_tx(pt).await.unwrap();
+        }
+        let hashes = [received.tx_hash, evicted.tx_hash];
+
+        let batch_hash = H256::repeat_byte(1);
+        assert_eq!(store.claim_txs(&hashes, batch_hash).await.unwrap(), 1);
+        assert_eq!(
+            store
+                .claim_txs(&hashes, H256::repeat_byte(2))
+                .await
+                .unwrap(),
+            0
+        );
+        let claimed = store.find_tx(received.tx_hash).await.unwrap().unwrap();
+        assert_eq!(claimed.status, TxStatus::Pending);
+        assert_eq!(claimed.batch_hash, Some(batch_hash));
+        assert_eq!(store.evict_txs(&hashes, "too old").await.unwrap(), 0);
+
+        assert_eq!(store.release_txs(batch_hash).await.unwrap(), 1);
+        let released = store.find_tx(received.tx_hash).await.unwrap().unwrap();
+        assert_eq!(released.status, TxStatus::Received);
+        assert_eq!(released.batch_hash, None);
+        assert_eq!(store.evict_txs(&hashes, "too old").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn tracks_when_batches_change() {
+        let store = MemoryPoolStore::new();
+        let mut pb = pool_batch(1, BatchStatus::Received, None);
+        pb.created_at = ms(1_000);
+        store.insert_batch(pb.clone()).await.unwrap();
+        assert!(store.insert_batch(pb.clone()).await.is_err());
+
+        let statuses = [BatchStatus::Received, BatchStatus::Succeed];
+        // Never changed, so updated when created
+        assert_eq!(
+            store
+                .find_batches_updated_before(&statuses, ms(2_000), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        store
+            .update_batch_status(pb.batch_hash, BatchStatus::Succeed)
+            .await
+            .unwrap();
+        assert!(store
+            .find_batches_updated_before(&statuses, ms(2_000), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store
+                .find_batches_created_before(&statuses, ms(2_000), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn archives_batches_with_their_txs() {
+        let store = MemoryPoolStore::new();
+        let pt = pool_tx(1, 0, ms(1_000));
+        let mut pb = pool_batch(1, BatchStatus::Succeed, None);
+        pb.tx_hash_list = vec![pt.tx_hash];
+        store.insert_tx(pt.clone()).await.unwrap();
+        store.insert_batch(pb.clone()).await.unwrap();
+
+        store.archive_batch(pb.batch_hash).await.unwrap();
+        assert_eq!(store.find_batch(pb.batch_hash).await.unwrap(), None);
+        assert_eq!(store.find_tx(pt.tx_hash).await.unwrap(), None);
+        let pool = store.pool.read().await;
+        assert!(pool.archived_batches.contains_key(&pb.batch_hash));
+        assert!(pool.archived_txs.contains_key(&pt.tx_hash));
+    }
+}
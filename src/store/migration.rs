@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use ethers::types::{H160, H256};
+use futures::future::BoxFuture;
+
+use crate::model::pool_batch::BatchStatus;
+use crate::model::pool_tx::TxStatus;
+use crate::store::PoolStore;
+
+struct Migration {
+    version: u32,
+    description: &'static str,
+    run: for<'a> fn(&'a dyn PoolStore) -> BoxFuture<'a, anyhow::Result<()>>,
+}
+
+// Append only: a store at version N has run every migration <= N.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "mark txs of finished batches succeed/failed",
+        run: mark_finished_txs,
+    },
+    Migration {
+        version: 2,
+        description: "record the EntryPoint of every tx and batch",
+        run: record_entry_points,
+    },
+];
+
+/// Before typed statuses, txs stayed pending after their batch finished.
+fn mark_finished_txs(store: &dyn PoolStore) -> BoxFuture<'_, anyhow::Result<()>> {
+    Box::pin(async move {
+        for pb in store.list_batches().await? {
+            let tx_status = match pb.status {
+                BatchStatus::Succeed => TxStatus::Succeed,
+                BatchStatus::Failed => TxStatus::Failed,
+                _ => continue,
+            };
+            store.update_txs_status(&pb.tx_hash_list, tx_status).await?;
+        }
+        Ok(())
+    })
+}
+
+/// Before several EntryPoints, a tx's EntryPoint was only its `to`, and a
+/// batch's the one of its txs.
+fn record_entry_points(store: &dyn PoolStore) -> BoxFuture<'_, anyhow::Result<()>> {
+    Box::pin(async move {
+        let mut tx_entry_points: HashMap<H256, H160> = HashMap::new();
+        let mut by_entry_point: HashMap<H160, Vec<H256>> = HashMap::new();
+        for pt in store.list_txs().await? {
+            let entry_point = pt.tx.to.unwrap_or_default();
+            tx_entry_points.insert(pt.tx_hash, entry_point);
+            if pt.entry_point.is_zero() {
+                by_entry_point
+                    .entry(entry_point)
+                    .or_default()
+                    .push(pt.tx_hash);
+            }
+        }
+        for (entry_point, tx_hashes) in by_entry_point {
+            store
+                .update_txs_entry_point(&tx_hashes, entry_point)
+                .await?;
+        }
+
+        for pb in store.list_batches().await? {
+            if !pb.entry_point.is_zero() {
+                continue;
+            }
+            let entry_point = pb.tx_hash_list.iter().find_map(|h| tx_entry_points.get(h));
+            if let Some(entry_point) = entry_point {
+                store
+                    .update_batch_entry_point(pb.batch_hash, *entry_point)
+                    .await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Creates missing indexes, then runs the migrations newer than the store's
+/// schema version, recording the version after each one.
+pub async fn run_migrations(store: &dyn PoolStore) -> anyhow::Result<()> {
+    store.ensure_indexes().await?;
+
+    let version = store.schema_version().await?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        println!(
+            "Run migration {}: {}",
+            migration.version, migration.description
+        );
+        (migration.run)(store).await?;
+        store.set_schema_version(migration.version).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,327 @@
+pub mod memory;
+pub mod migration;
+pub mod mongo;
+#[cfg(feature = "embedded")]
+pub mod sqlite;
+
+use std::collections::HashMap;
+#[cfg(feature = "embedded")]
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use ethers::types::{Bytes, H160, H256, U256};
+use lazy_static::lazy_static;
+use mongodb::bson::DateTime;
+
+use crate::config::StoreConfig;
+use crate::model::ledger_entry::LedgerEntry;
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::store::memory::MemoryPoolStore;
+use crate::store::mongo::MongoPoolStore;
+#[cfg(feature = "embedded")]
+use crate::store::sqlite::SqlitePoolStore;
+
+lazy_static! {
+    // By chain id
+    static ref POOL_STORES: RwLock<HashMap<u64, Arc<dyn PoolStore>>> = RwLock::new(HashMap::new());
+}
+
+/// Storage for the tx pool and its batches.
+///
+/// `service::pool` only talks to this trait, so the pool logic can run on
+/// MongoDB in production and on the in-memory backend in tests and
+/// single-node dev runs.
+#[async_trait]
+pub trait PoolStore: Send + Sync {
+    /// Creates the indexes the queries below rely on, if missing.
+    async fn ensure_indexes(&self) -> anyhow::Result<()>;
+
+    /// The last migration applied, 0 for a new store.
+    async fn schema_version(&self) -> anyhow::Result<u32>;
+
+    async fn set_schema_version(&self, version: u32) -> anyhow::Result<()>;
+
+    /// Every tx in the pool, oldest first.
+    async fn list_txs(&self) -> anyhow::Result<Vec<PoolTx>>;
+
+    /// Every batch, in insertion order.
+    async fn list_batches(&self) -> anyhow::Result<Vec<PoolBatch>>;
+
+    async fn find_tx(&self, tx_hash: H256) -> anyhow::Result<Option<PoolTx>>;
+
+    async fn find_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<Vec<PoolTx>>;
+
+    /// Txs with `status`, oldest first.
+    async fn find_txs_by_status(
+        &self,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>>;
+
+    /// Txs calling `entry_point` with `status`, oldest first.
+    async fn find_txs_by_entry_point(
+        &self,
+        entry_point: H160,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>>;
+
+    /// Txs with `status` created before `before`, oldest first.
+    async fn find_txs_created_before(
+        &self,
+        status: TxStatus,
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>>;
+
+    /// Txs from `tx_from` whose status is in `statuses`, oldest first.
+    async fn find_txs_from(
+        &self,
+        tx_from: H160,
+        statuses: &[TxStatus],
+    ) -> anyhow::Result<Vec<PoolTx>>;
+
+    /// Number of txs with `status`, from `tx_from` only if given.
+    async fn count_txs(&self, status: TxStatus, tx_from: Option<H160>) -> anyhow::Result<usize>;
+
+    async fn insert_tx(&self, pool_tx: PoolTx) -> anyhow::Result<()>;
+
+    /// Evicts the txs of `tx_hashes` that are still received. Returns how
+    /// many were evicted.
+    async fn evict_txs(&self, tx_hashes: &[H256], reason: &str) -> anyhow::Result<usize>;
+
+    /// Moves txs to the archive, out of the live pool.
+    async fn archive_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()>;
+
+    /// Every archived tx, in no particular order. Only `copy_pool` reads
+    /// the archives back.
+    #[cfg(feature = "embedded")]
+    async fn list_archived_txs(&self) -> anyhow::Result<Vec<PoolTx>>;
+
+    /// Removes txs without archiving them.
+    async fn delete_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()>;
+
+    async fn update_txs_status(&self, tx_hashes: &[H256], status: TxStatus) -> anyhow::Result<()>;
+
+    async fn update_txs_entry_point(
+        &self,
+        tx_hashes: &[H256],
+        entry_point: H160,
+    ) -> anyhow::Result<()>;
+
+    /// Moves the txs of `tx_hashes` that are still received to pending
+    /// under `batch_hash`, one conditional update per tx so concurrent
+    /// sealers never claim the same tx. Returns how many were claimed.
+    async fn claim_txs(&self, tx_hashes: &[H256], batch_hash: H256) -> anyhow::Result<usize>;
+
+    /// Returns the pending or failed txs claimed by `batch_hash` to
+    /// received.
+    async fn release_txs(&self, batch_hash: H256) -> anyhow::Result<usize>;
+
+    async fn find_batch(&self, batch_hash: H256) -> anyhow::Result<Option<PoolBatch>>;
+
+    /// Fails if a batch with the same `batch_hash` already exists.
+    async fn insert_batch(&self, pool_batch: PoolBatch) -> anyhow::Result<()>;
+
+    /// Batches with a status in `statuses` created before `before`, oldest
+    /// first.
+    async fn find_batches_created_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>>;
+
+    /// Batches with a status in `statuses` whose status last changed
+    /// before `before`, see `PoolBatch::updated_at`.
+    async fn find_batches_updated_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>>;
+
+    async fn delete_batch(&self, batch_hash: H256) -> anyhow::Result<()>;
+
+    /// Moves a batch and its txs to the archive, out of the live pool.
+    /// Safe to repeat after a crash.
+    async fn archive_batch(&self, batch_hash: H256) -> anyhow::Result<()>;
+
+    /// Every archived batch, in no particular order.
+    #[cfg(feature = "embedded")]
+    async fn list_archived_batches(&self) -> anyhow::Result<Vec<PoolBatch>>;
+
+    async fn update_batch_status(
+        &self,
+        batch_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()>;
+
+    async fn update_batch_entry_point(
+        &self,
+        batch_hash: H256,
+        entry_point: H160,
+    ) -> anyhow::Result<()>;
+
+    async fn update_batch_miner(&self, batch_hash: H256, miner: H160) -> anyhow::Result<()>;
+
+    async fn update_batch_state_roots(
+        &self,
+        batch_hash: H256,
+        pre_state_root: H256,
+        post_state_root: H256,
+    ) -> anyhow::Result<()>;
+
+    async fn update_batch_proof(
+        &self,
+        batch_hash: H256,
+        zk_proof: Bytes,
+        zk_pub_inputs: Vec<U256>,
+        status: BatchStatus,
+    ) -> anyhow::Result<()>;
+
+    async fn update_batch_send_tx_hash(
+        &self,
+        batch_hash: H256,
+        send_tx_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()>;
+
+    async fn insert_ledger_entry(&self, entry: LedgerEntry) -> anyhow::Result<()>;
+
+    /// The last `limit` ledger entries, newest first.
+    async fn list_ledger(&self, limit: usize) -> anyhow::Result<Vec<LedgerEntry>>;
+}
+
+/// Opens the store `config` selects.
+pub async fn open_pool_store(config: &StoreConfig) -> anyhow::Result<Arc<dyn PoolStore>> {
+    Ok(match config.backend.as_str() {
+        "memory" => Arc::new(MemoryPoolStore::new()),
+        "mongo" => Arc::new(MongoPoolStore::connect_with_config(&config.mongo).await?),
+        #[cfg(feature = "embedded")]
+        "sqlite" => Arc::new(SqlitePoolStore::open(&config.sqlite_path)?),
+        backend => anyhow::bail!("Unsupported store backend: {}", backend),
+    })
+}
+
+/// Opens the store and makes it the one `get_pool_store` returns for
+/// `chain_id`. Called once per chain at startup.
+pub async fn init_pool_store(
+    chain_id: u64,
+    config: &StoreConfig,
+) -> anyhow::Result<Arc<dyn PoolStore>> {
+    let store = open_pool_store(config).await?;
+
+    let mut stores = POOL_STORES.write().unwrap();
+    if stores.contains_key(&chain_id) {
+        anyhow::bail!("Pool store of chain {} is already initialized", chain_id);
+    }
+    stores.insert(chain_id, store.clone());
+
+    Ok(store)
+}
+
+/// Number of records `copy_pool` copied, per collection.
+#[cfg(feature = "embedded")]
+#[derive(Default, PartialEq, Eq, Debug)]
+pub struct CopyCounts {
+    pub txs: usize,
+    pub batches: usize,
+    pub archived_txs: usize,
+    pub archived_batches: usize,
+    pub ledger: usize,
+}
+
+/// Copies every collection from `from` into `to`: the live pool, the
+/// archives, the ledger and the schema version. Records `to` already has
+/// are skipped, so an interrupted copy can simply be run again.
+#[cfg(feature = "embedded")]
+pub async fn copy_pool(from: &dyn PoolStore, to: &dyn PoolStore) -> anyhow::Result<CopyCounts> {
+    let mut counts = CopyCounts::default();
+
+    for pool_tx in from.list_txs().await? {
+        if to.find_tx(pool_tx.tx_hash).await?.is_none() {
+            to.insert_tx(pool_tx).await?;
+            counts.txs += 1;
+        }
+    }
+
+    for pool_batch in from.list_batches().await? {
+        if to.find_batch(pool_batch.batch_hash).await?.is_none() {
+            to.insert_batch(pool_batch).await?;
+            counts.batches += 1;
+        }
+    }
+
+    // Archived records go through the live pool, the only way in
+    let archived: HashSet<H256> = to
+        .list_archived_txs()
+        .await?
+        .iter()
+        .map(|pt| pt.tx_hash)
+        .collect();
+    for pool_tx in from.list_archived_txs().await? {
+        if !archived.contains(&pool_tx.tx_hash) {
+            let tx_hash = pool_tx.tx_hash;
+            to.insert_tx(pool_tx).await?;
+            to.archive_txs(&[tx_hash]).await?;
+            counts.archived_txs += 1;
+        }
+    }
+
+    let archived: HashSet<H256> = to
+        .list_archived_batches()
+        .await?
+        .iter()
+        .map(|pb| pb.batch_hash)
+        .collect();
+    for pool_batch in from.list_archived_batches().await? {
+        if !archived.contains(&pool_batch.batch_hash) {
+            let batch_hash = pool_batch.batch_hash;
+            to.insert_batch(pool_batch).await?;
+            to.archive_batch(batch_hash).await?;
+            counts.archived_batches += 1;
+        }
+    }
+
+    // Oldest first, so `to` lists them in the same order
+    let copied: HashSet<H256> = to
+        .list_ledger(i64::MAX as usize)
+        .await?
+        .iter()
+        .map(|entry| entry.tx_hash)
+        .collect();
+    for entry in from.list_ledger(i64::MAX as usize).await?.into_iter().rev() {
+        if !copied.contains(&entry.tx_hash) {
+            to.insert_ledger_entry(entry).await?;
+            counts.ledger += 1;
+        }
+    }
+
+    let version = from.schema_version().await?;
+    if version > to.schema_version().await? {
+        to.set_schema_version(version).await?;
+    }
+
+    Ok(counts)
+}
+
+/// Copies the MongoDB pool into the SQLite file at `store.sqlite_path`.
+#[cfg(feature = "embedded")]
+pub async fn migrate_mongo_to_sqlite(config: &StoreConfig) -> anyhow::Result<CopyCounts> {
+    let from = MongoPoolStore::connect_with_config(&config.mongo).await?;
+    let to = SqlitePoolStore::open(&config.sqlite_path)?;
+
+    copy_pool(&from, &to).await
+}
+
+pub fn get_pool_store(chain_id: u64) -> Arc<dyn PoolStore> {
+    POOL_STORES
+        .read()
+        .unwrap()
+        .get(&chain_id)
+        .expect("pool store is not initialized")
+        .clone()
+}
@@ -0,0 +1,565 @@
+use async_trait::async_trait;
+use ethers::abi::AbiEncode;
+use ethers::types::{Bytes, H160, H256, U256};
+use futures::TryStreamExt;
+use mongodb::bson::{doc, to_bson, Bson, DateTime, Document};
+use mongodb::options::{ClientOptions, FindOptions, IndexOptions, ReplaceOptions, UpdateOptions};
+use mongodb::{Client, Collection, Database, IndexModel};
+
+use crate::config::MongoConfig;
+use crate::model::ledger_entry::LedgerEntry;
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::store::PoolStore;
+
+pub struct MongoPoolStore {
+    database: Database,
+}
+
+impl MongoPoolStore {
+    pub async fn connect(uri: &str, database: &str) -> anyhow::Result<Self> {
+        let mut client_options = ClientOptions::parse(uri).await?;
+        client_options.app_name = Some("bundler-client".to_string());
+
+        let client = Client::with_options(client_options)?;
+        let database = client.database(database);
+        database.run_command(doc! {"ping": 1}, None).await?;
+
+        Ok(Self { database })
+    }
+
+    pub async fn connect_with_config(config: &MongoConfig) -> anyhow::Result<Self> {
+        Self::connect(&config.uri(), &config.database).await
+    }
+
+    fn pool_tx(&self) -> Collection<PoolTx> {
+        self.database.collection("pool_tx")
+    }
+
+    fn pool_batch(&self) -> Collection<PoolBatch> {
+        self.database.collection("pool_batch")
+    }
+
+    fn pool_tx_archive(&self) -> Collection<PoolTx> {
+        self.database.collection("pool_tx_archive")
+    }
+
+    fn pool_batch_archive(&self) -> Collection<PoolBatch> {
+        self.database.collection("pool_batch_archive")
+    }
+
+    // Upserts keep archiving idempotent when a run is repeated after a crash
+    async fn archive_tx_docs(&self, txs: Vec<PoolTx>) -> anyhow::Result<()> {
+        for pt in txs {
+            self.pool_tx_archive()
+                .replace_one(
+                    doc! {"tx_hash": pt.tx_hash.encode_hex()},
+                    pt,
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn ledger(&self) -> Collection<LedgerEntry> {
+        self.database.collection("ledger")
+    }
+
+    fn schema_version_collection(&self) -> Collection<Document> {
+        self.database.collection("schema_version")
+    }
+}
+
+fn index(keys: Document, unique: bool) -> IndexModel {
+    IndexModel::builder()
+        .keys(keys)
+        .options(IndexOptions::builder().unique(unique).build())
+        .build()
+}
+
+#[async_trait]
+impl PoolStore for MongoPoolStore {
+    async fn ensure_indexes(&self) -> anyhow::Result<()> {
+        self.pool_tx()
+            .create_indexes(
+                vec![
+                    index(doc! {"tx_hash": 1}, true),
+                    index(doc! {"status": 1, "created_at": 1}, false),
+                    index(doc! {"batch_hash": 1}, false),
+                    index(doc! {"created_at": 1}, false),
+                    index(doc! {"tx_from": 1, "status": 1}, false),
+                    index(doc! {"entry_point": 1, "status": 1, "created_at": 1}, false),
+                ],
+                None,
+            )
+            .await?;
+
+        // Batch sealing relies on batch_hash being unique
+        self.pool_batch()
+            .create_indexes(
+                vec![
+                    index(doc! {"batch_hash": 1}, true),
+                    index(doc! {"status": 1}, false),
+                    index(doc! {"created_at": 1}, false),
+                ],
+                None,
+            )
+            .await?;
+
+        self.ledger()
+            .create_indexes(
+                vec![
+                    index(doc! {"tx_hash": 1}, true),
+                    index(doc! {"created_at": 1}, false),
+                ],
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn schema_version(&self) -> anyhow::Result<u32> {
+        let one = self
+            .schema_version_collection()
+            .find_one(doc! {"_id": "pool"}, None)
+            .await?;
+
+        Ok(match one {
+            Some(d) => d.get_i64("version")? as u32,
+            None => 0,
+        })
+    }
+
+    async fn set_schema_version(&self, version: u32) -> anyhow::Result<()> {
+        self.schema_version_collection()
+            .update_one(
+                doc! {"_id": "pool"},
+                doc! {"$set": {"version": version as i64}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_txs(&self) -> anyhow::Result<Vec<PoolTx>> {
+        let find_options = FindOptions::builder().sort(doc! {"created_at": 1}).build();
+        let cursor = self.pool_tx().find(None, find_options).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn list_batches(&self) -> anyhow::Result<Vec<PoolBatch>> {
+        let cursor = self.pool_batch().find(None, None).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn find_tx(&self, tx_hash: H256) -> anyhow::Result<Option<PoolTx>> {
+        Ok(self
+            .pool_tx()
+            .find_one(doc! {"tx_hash": tx_hash.encode_hex()}, None)
+            .await?)
+    }
+
+    async fn find_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<Vec<PoolTx>> {
+        let cursor = self
+            .pool_tx()
+            .find(doc! {"tx_hash": {"$in": to_bson(tx_hashes)?}}, None)
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn find_txs_by_status(
+        &self,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let find_options = FindOptions::builder()
+            .sort(doc! {"created_at": 1})
+            .limit(limit as i64)
+            .build();
+        let cursor = self
+            .pool_tx()
+            .find(doc! {"status": status as i32}, find_options)
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn find_txs_by_entry_point(
+        &self,
+        entry_point: H160,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let find_options = FindOptions::builder()
+            .sort(doc! {"created_at": 1})
+            .limit(limit as i64)
+            .build();
+        let cursor = self
+            .pool_tx()
+            .find(
+                doc! {"entry_point": to_bson(&entry_point)?, "status": status as i32},
+                find_options,
+            )
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn find_txs_created_before(
+        &self,
+        status: TxStatus,
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let find_options = FindOptions::builder()
+            .sort(doc! {"created_at": 1})
+            .limit(limit as i64)
+            .build();
+        let cursor = self
+            .pool_tx()
+            .find(
+                doc! {"status": status as i32, "created_at": {"$lt": before}},
+                find_options,
+            )
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn find_txs_from(
+        &self,
+        tx_from: H160,
+        statuses: &[TxStatus],
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let statuses: Vec<i32> = statuses.iter().map(|s| *s as i32).collect();
+        let find_options = FindOptions::builder().sort(doc! {"created_at": 1}).build();
+        let cursor = self
+            .pool_tx()
+            .find(
+                doc! {"tx_from": to_bson(&tx_from)?, "status": {"$in": statuses}},
+                find_options,
+            )
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn count_txs(&self, status: TxStatus, tx_from: Option<H160>) -> anyhow::Result<usize> {
+        let mut filter = doc! {"status": status as i32};
+        if let Some(tx_from) = tx_from {
+            filter.insert("tx_from", to_bson(&tx_from)?);
+        }
+
+        Ok(self.pool_tx().count_documents(filter, None).await? as usize)
+    }
+
+    async fn insert_tx(&self, pool_tx: PoolTx) -> anyhow::Result<()> {
+        self.pool_tx().insert_one(pool_tx, None).await?;
+        Ok(())
+    }
+
+    async fn update_txs_status(&self, tx_hashes: &[H256], status: TxStatus) -> anyhow::Result<()> {
+        self.pool_tx()
+            .update_many(
+                doc! {"tx_hash": {"$in": to_bson(tx_hashes)?}},
+                doc! {"$set": {"status": status as i32}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_txs_entry_point(
+        &self,
+        tx_hashes: &[H256],
+        entry_point: H160,
+    ) -> anyhow::Result<()> {
+        self.pool_tx()
+            .update_many(
+                doc! {"tx_hash": {"$in": to_bson(tx_hashes)?}},
+                doc! {"$set": {"entry_point": to_bson(&entry_point)?}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn evict_txs(&self, tx_hashes: &[H256], reason: &str) -> anyhow::Result<usize> {
+        let result = self
+            .pool_tx()
+            .update_many(
+                doc! {"tx_hash": {"$in": to_bson(tx_hashes)?}, "status": TxStatus::Received as i32},
+                doc! {"$set": {"status": TxStatus::Evicted as i32, "evict_reason": reason}},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count as usize)
+    }
+
+    async fn archive_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()> {
+        let txs = self.find_txs(tx_hashes).await?;
+        self.archive_tx_docs(txs).await?;
+        self.delete_txs(tx_hashes).await
+    }
+
+    #[cfg(feature = "embedded")]
+    async fn list_archived_txs(&self) -> anyhow::Result<Vec<PoolTx>> {
+        let cursor = self.pool_tx_archive().find(None, None).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn delete_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()> {
+        self.pool_tx()
+            .delete_many(doc! {"tx_hash": {"$in": to_bson(tx_hashes)?}}, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn claim_txs(&self, tx_hashes: &[H256], batch_hash: H256) -> anyhow::Result<usize> {
+        // A standalone mongod has no multi-document transactions. The status
+        // filter makes the update a claim: a tx another sealer took first is
+        // simply not modified.
+        let result = self
+            .pool_tx()
+            .update_many(
+                doc! {"tx_hash": {"$in": to_bson(tx_hashes)?}, "status": TxStatus::Received as i32},
+                doc! {"$set": {"status": TxStatus::Pending as i32, "batch_hash": batch_hash.encode_hex()}},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count as usize)
+    }
+
+    async fn release_txs(&self, batch_hash: H256) -> anyhow::Result<usize> {
+        let result = self
+            .pool_tx()
+            .update_many(
+                doc! {
+                    "batch_hash": batch_hash.encode_hex(),
+                    "status": {"$in": [TxStatus::Pending as i32, TxStatus::Failed as i32]},
+                },
+                doc! {"$set": {"status": TxStatus::Received as i32, "batch_hash": Bson::Null}},
+                None,
+            )
+            .await?;
+        Ok(result.modified_count as usize)
+    }
+
+    async fn find_batch(&self, batch_hash: H256) -> anyhow::Result<Option<PoolBatch>> {
+        Ok(self
+            .pool_batch()
+            .find_one(doc! {"batch_hash": batch_hash.encode_hex()}, None)
+            .await?)
+    }
+
+    async fn insert_batch(&self, pool_batch: PoolBatch) -> anyhow::Result<()> {
+        self.pool_batch().insert_one(pool_batch, None).await?;
+        Ok(())
+    }
+
+    async fn find_batches_created_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>> {
+        let statuses: Vec<i32> = statuses.iter().map(|s| *s as i32).collect();
+        let find_options = FindOptions::builder()
+            .sort(doc! {"created_at": 1})
+            .limit(limit as i64)
+            .build();
+        let cursor = self
+            .pool_batch()
+            .find(
+                doc! {"status": {"$in": statuses}, "created_at": {"$lt": before}},
+                find_options,
+            )
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn find_batches_updated_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>> {
+        let statuses: Vec<i32> = statuses.iter().map(|s| *s as i32).collect();
+        let find_options = FindOptions::builder()
+            .sort(doc! {"created_at": 1})
+            .limit(limit as i64)
+            .build();
+        let cursor = self
+            .pool_batch()
+            .find(
+                doc! {
+                    "status": {"$in": statuses},
+                    "$or": [
+                        {"updated_at": {"$lt": before}},
+                        {"updated_at": null, "created_at": {"$lt": before}},
+                    ],
+                },
+                find_options,
+            )
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn delete_batch(&self, batch_hash: H256) -> anyhow::Result<()> {
+        self.pool_batch()
+            .delete_one(doc! {"batch_hash": batch_hash.encode_hex()}, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn archive_batch(&self, batch_hash: H256) -> anyhow::Result<()> {
+        let Some(pb) = self.find_batch(batch_hash).await? else {
+            return Ok(());
+        };
+
+        // Copy first, delete last: a crash in between only repeats work
+        let txs = self.find_txs(&pb.tx_hash_list).await?;
+        self.archive_tx_docs(txs).await?;
+        self.pool_batch_archive()
+            .replace_one(
+                doc! {"batch_hash": batch_hash.encode_hex()},
+                pb.clone(),
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        self.delete_txs(&pb.tx_hash_list).await?;
+        self.delete_batch(batch_hash).await
+    }
+
+    #[cfg(feature = "embedded")]
+    async fn list_archived_batches(&self) -> anyhow::Result<Vec<PoolBatch>> {
+        let cursor = self.pool_batch_archive().find(None, None).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn update_batch_status(
+        &self,
+        batch_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        self.pool_batch()
+            .update_one(
+                doc! {"batch_hash": batch_hash.encode_hex()},
+                doc! {"$set": {"status": status as i32, "updated_at": DateTime::now()}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_batch_entry_point(
+        &self,
+        batch_hash: H256,
+        entry_point: H160,
+    ) -> anyhow::Result<()> {
+        self.pool_batch()
+            .update_one(
+                doc! {"batch_hash": batch_hash.encode_hex()},
+                doc! {"$set": {"entry_point": to_bson(&entry_point)?}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_batch_miner(&self, batch_hash: H256, miner: H160) -> anyhow::Result<()> {
+        self.pool_batch()
+            .update_one(
+                doc! {"batch_hash": batch_hash.encode_hex()},
+                doc! {"$set": {"miner": to_bson(&miner)?}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_batch_state_roots(
+        &self,
+        batch_hash: H256,
+        pre_state_root: H256,
+        post_state_root: H256,
+    ) -> anyhow::Result<()> {
+        self.pool_batch()
+            .update_one(
+                doc! {"batch_hash": batch_hash.encode_hex()},
+                doc! {"$set": {
+                    "pre_state_root": pre_state_root.encode_hex(),
+                    "post_state_root": post_state_root.encode_hex(),
+                }},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_batch_proof(
+        &self,
+        batch_hash: H256,
+        zk_proof: Bytes,
+        zk_pub_inputs: Vec<U256>,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        self.pool_batch()
+            .update_one(
+                doc! {"batch_hash": batch_hash.encode_hex()},
+                doc! {"$set": {
+                    "zk_proof": to_bson(&zk_proof)?,
+                    "zk_pub_inputs": to_bson(&zk_pub_inputs)?,
+                    "status": status as i32,
+                    "updated_at": DateTime::now(),
+                }},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_batch_send_tx_hash(
+        &self,
+        batch_hash: H256,
+        send_tx_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        self.pool_batch()
+            .update_one(
+                doc! {"batch_hash": batch_hash.encode_hex()},
+                doc! {"$set": {
+                    "send_tx_hash": send_tx_hash.encode_hex(),
+                    "status": status as i32,
+                    "updated_at": DateTime::now(),
+                }},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_ledger_entry(&self, entry: LedgerEntry) -> anyhow::Result<()> {
+        self.ledger().insert_one(entry, None).await?;
+        Ok(())
+    }
+
+    async fn list_ledger(&self, limit: usize) -> anyhow::Result<Vec<LedgerEntry>> {
+        let find_options = FindOptions::builder()
+            .sort(doc! {"created_at": -1})
+            .limit(limit as i64)
+            .build();
+        let cursor = self.ledger().find(None, find_options).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+}
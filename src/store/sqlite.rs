@@ -0,0 +1,1093 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use ethers::abi::AbiEncode;
+use ethers::types::{Bytes, H160, H256, U256};
+use mongodb::bson::DateTime;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::task;
+
+use crate::model::ledger_entry::LedgerEntry;
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::store::PoolStore;
+
+// Records are kept as JSON, with the fields we filter and sort on copied
+// into indexed columns.
+const TABLES: &str = "
+CREATE TABLE IF NOT EXISTS pool_tx (
+    tx_hash TEXT PRIMARY KEY,
+    status INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    entry_point TEXT NOT NULL,
+    tx_from TEXT NOT NULL,
+    batch_hash TEXT,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pool_batch (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    batch_hash TEXT NOT NULL UNIQUE,
+    status INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pool_tx_archive (
+    tx_hash TEXT PRIMARY KEY,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pool_batch_archive (
+    batch_hash TEXT PRIMARY KEY,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS ledger (
+    tx_hash TEXT PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS schema_version (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    version INTEGER NOT NULL
+);
+";
+
+const INDEXES: &str = "
+CREATE INDEX IF NOT EXISTS pool_tx_status_created_at ON pool_tx (status, created_at);
+CREATE INDEX IF NOT EXISTS pool_tx_created_at ON pool_tx (created_at);
+CREATE INDEX IF NOT EXISTS pool_tx_entry_point ON pool_tx (entry_point, status, created_at);
+CREATE INDEX IF NOT EXISTS pool_tx_tx_from ON pool_tx (tx_from, status);
+CREATE INDEX IF NOT EXISTS pool_tx_batch_hash ON pool_tx (batch_hash);
+CREATE INDEX IF NOT EXISTS pool_batch_status_created_at ON pool_batch (status, created_at);
+CREATE INDEX IF NOT EXISTS pool_batch_status_updated_at ON pool_batch (status, updated_at);
+CREATE INDEX IF NOT EXISTS ledger_created_at ON ledger (created_at);
+";
+
+/// Embedded `PoolStore` on a single SQLite file, for deployments that
+/// don't want to run a MongoDB server.
+///
+/// The database runs in WAL mode with `synchronous = FULL` and every write
+/// is a single transaction, so a write that returned `Ok` survives a crash
+/// or power loss, and a crash mid-write leaves no partial update behind.
+pub struct SqlitePoolStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqlitePoolStore {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        if let Some(dir) = Path::new(path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        conn.execute_batch(TABLES)?;
+        add_columns(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // rusqlite is blocking, keep it off the async workers
+    async fn run<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap();
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+fn query_txs(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> anyhow::Result<Vec<PoolTx>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| row.get::<_, String>(0))?;
+
+    let mut txs = vec![];
+    for data in rows {
+        txs.push(serde_json::from_str(&data?)?);
+    }
+    Ok(txs)
+}
+
+fn query_batches(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> anyhow::Result<Vec<PoolBatch>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params, |row| row.get::<_, String>(0))?;
+
+    let mut batches = vec![];
+    for data in rows {
+        batches.push(serde_json::from_str(&data?)?);
+    }
+    Ok(batches)
+}
+
+fn read_tx(conn: &Connection, tx_hash: H256) -> anyhow::Result<Option<PoolTx>> {
+    let data: Option<String> = conn
+        .query_row(
+            "SELECT data FROM pool_tx WHERE tx_hash = ?1",
+            [tx_hash.encode_hex()],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(data.map(|d| serde_json::from_str(&d)).transpose()?)
+}
+
+fn read_batch(conn: &Connection, batch_hash: H256) -> anyhow::Result<Option<PoolBatch>> {
+    let data: Option<String> = conn
+        .query_row(
+            "SELECT data FROM pool_batch WHERE batch_hash = ?1",
+            [batch_hash.encode_hex()],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(data.map(|d| serde_json::from_str(&d)).transpose()?)
+}
+
+// The columns next to `data` copy the fields the queries filter on
+fn write_tx(conn: &Connection, pool_tx: &PoolTx) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE pool_tx SET status = ?1, entry_point = ?2, tx_from = ?3, batch_hash = ?4, data = ?5
+         WHERE tx_hash = ?6",
+        params![
+            pool_tx.status as u8,
+            pool_tx.entry_point.encode_hex(),
+            pool_tx.tx_from.encode_hex(),
+            pool_tx.batch_hash.map(|h| h.encode_hex()),
+            serde_json::to_string(pool_tx)?,
+            pool_tx.tx_hash.encode_hex()
+        ],
+    )?;
+    Ok(())
+}
+
+fn write_batch(conn: &Connection, pool_batch: &PoolBatch) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE pool_batch SET status = ?1, created_at = ?2, updated_at = ?3, data = ?4
+         WHERE batch_hash = ?5",
+        params![
+            pool_batch.status as u8,
+            pool_batch.created_at.timestamp_millis(),
+            pool_batch.updated_at().timestamp_millis(),
+            serde_json::to_string(pool_batch)?,
+            pool_batch.batch_hash.encode_hex()
+        ],
+    )?;
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> anyhow::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    for name in names {
+        if name? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Files created before the filter columns existed get them here, filled
+// from `data`
+fn add_columns(conn: &mut Connection) -> anyhow::Result<()> {
+    let db_tx = conn.transaction()?;
+    if !has_column(&db_tx, "pool_tx", "tx_from")? {
+        db_tx.execute_batch(
+            "ALTER TABLE pool_tx ADD COLUMN entry_point TEXT NOT NULL DEFAULT '';
+             ALTER TABLE pool_tx ADD COLUMN tx_from TEXT NOT NULL DEFAULT '';
+             ALTER TABLE pool_tx ADD COLUMN batch_hash TEXT;",
+        )?;
+        for pt in query_txs(&db_tx, "SELECT data FROM pool_tx", [])? {
+            write_tx(&db_tx, &pt)?;
+        }
+    }
+    if !has_column(&db_tx, "pool_batch", "created_at")? {
+        db_tx.execute_batch(
+            "ALTER TABLE pool_batch ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+             ALTER TABLE pool_batch ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;",
+        )?;
+        for pb in query_batches(&db_tx, "SELECT data FROM pool_batch", [])? {
+            write_batch(&db_tx, &pb)?;
+        }
+    }
+    db_tx.commit()?;
+    Ok(())
+}
+
+fn statuses_sql(statuses: &[BatchStatus]) -> String {
+    statuses
+        .iter()
+        .map(|s| (*s as u8).to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn archive_txs(conn: &Connection, tx_hashes: &[H256]) -> anyhow::Result<()> {
+    for h in tx_hashes {
+        conn.execute(
+            "INSERT OR REPLACE INTO pool_tx_archive (tx_hash, data)
+             SELECT tx_hash, data FROM pool_tx WHERE tx_hash = ?1",
+            [h.encode_hex()],
+        )?;
+        conn.execute("DELETE FROM pool_tx WHERE tx_hash = ?1", [h.encode_hex()])?;
+    }
+    Ok(())
+}
+
+fn update_batch<F>(conn: &Connection, batch_hash: H256, f: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut PoolBatch),
+{
+    if let Some(mut pb) = read_batch(conn, batch_hash)? {
+        f(&mut pb);
+        write_batch(conn, &pb)?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl PoolStore for SqlitePoolStore {
+    async fn ensure_indexes(&self) -> anyhow::Result<()> {
+        self.run(|conn| Ok(conn.execute_batch(INDEXES)?)).await
+    }
+
+    async fn schema_version(&self) -> anyhow::Result<u32> {
+        self.run(|conn| {
+            let version: Option<u32> = conn
+                .query_row(
+                    "SELECT version FROM schema_version WHERE id = 0",
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(version.unwrap_or(0))
+        })
+        .await
+    }
+
+    async fn set_schema_version(&self, version: u32) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO schema_version (id, version) VALUES (0, ?1)",
+                [version],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_txs(&self) -> anyhow::Result<Vec<PoolTx>> {
+        self.run(|conn| query_txs(conn, "SELECT data FROM pool_tx ORDER BY created_at", []))
+            .await
+    }
+
+    async fn list_batches(&self) -> anyhow::Result<Vec<PoolBatch>> {
+        self.run(|conn| query_batches(conn, "SELECT data FROM pool_batch ORDER BY id", []))
+            .await
+    }
+
+    async fn find_tx(&self, tx_hash: H256) -> anyhow::Result<Option<PoolTx>> {
+        self.run(move |conn| read_tx(conn, tx_hash)).await
+    }
+
+    async fn find_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<Vec<PoolTx>> {
+        let tx_hashes = tx_hashes.to_vec();
+        self.run(move |conn| {
+            let mut txs = vec![];
+            for h in tx_hashes {
+                if let Some(pt) = read_tx(conn, h)? {
+                    txs.push(pt);
+                }
+            }
+            Ok(txs)
+        })
+        .await
+    }
+
+    async fn find_txs_by_status(
+        &self,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        self.run(move |conn| {
+            query_txs(
+                conn,
+                "SELECT data FROM pool_tx WHERE status = ?1 ORDER BY created_at LIMIT ?2",
+                params![status as u8, limit as i64],
+            )
+        })
+        .await
+    }
+
+    async fn find_txs_by_entry_point(
+        &self,
+        entry_point: H160,
+        status: TxStatus,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        self.run(move |conn| {
+            query_txs(
+                conn,
+                "SELECT data FROM pool_tx
+                 WHERE status = ?1 AND entry_point = ?2
+                 ORDER BY created_at LIMIT ?3",
+                params![status as u8, entry_point.encode_hex(), limit as i64],
+            )
+        })
+        .await
+    }
+
+    async fn find_txs_created_before(
+        &self,
+        status: TxStatus,
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        self.run(move |conn| {
+            query_txs(
+                conn,
+                "SELECT data FROM pool_tx WHERE status = ?1 AND created_at < ?2
+                 ORDER BY created_at LIMIT ?3",
+                params![status as u8, before.timestamp_millis(), limit as i64],
+            )
+        })
+        .await
+    }
+
+    async fn find_txs_from(
+        &self,
+        tx_from: H160,
+        statuses: &[TxStatus],
+    ) -> anyhow::Result<Vec<PoolTx>> {
+        let statuses = statuses
+            .iter()
+            .map(|s| (*s as u8).to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        self.run(move |conn| {
+            let sql = format!(
+                "SELECT data FROM pool_tx
+                 WHERE status IN ({}) AND tx_from = ?1
+                 ORDER BY created_at",
+                statuses
+            );
+            query_txs(conn, &sql, [tx_from.encode_hex()])
+        })
+        .await
+    }
+
+    async fn count_txs(&self, status: TxStatus, tx_from: Option<H160>) -> anyhow::Result<usize> {
+        self.run(move |conn| {
+            let count: i64 = match tx_from {
+                Some(tx_from) => conn.query_row(
+                    "SELECT COUNT(*) FROM pool_tx
+                     WHERE status = ?1 AND tx_from = ?2",
+                    params![status as u8, tx_from.encode_hex()],
+                    |row| row.get(0),
+                )?,
+                None => conn.query_row(
+                    "SELECT COUNT(*) FROM pool_tx WHERE status = ?1",
+                    [status as u8],
+                    |row| row.get(0),
+                )?,
+            };
+            Ok(count as usize)
+        })
+        .await
+    }
+
+    async fn insert_tx(&self, pool_tx: PoolTx) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO pool_tx (tx_hash, status, created_at, entry_point, tx_from, batch_hash, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    pool_tx.tx_hash.encode_hex(),
+                    pool_tx.status as u8,
+                    pool_tx.created_at.timestamp_millis(),
+                    pool_tx.entry_point.encode_hex(),
+                    pool_tx.tx_from.encode_hex(),
+                    pool_tx.batch_hash.map(|h| h.encode_hex()),
+                    serde_json::to_string(&pool_tx)?
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_txs_status(&self, tx_hashes: &[H256], status: TxStatus) -> anyhow::Result<()> {
+        let tx_hashes = tx_hashes.to_vec();
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            for h in tx_hashes {
+                if let Some(mut pt) = read_tx(&db_tx, h)? {
+                    pt.status = status;
+                    write_tx(&db_tx, &pt)?;
+                }
+            }
+            db_tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_txs_entry_point(
+        &self,
+        tx_hashes: &[H256],
+        entry_point: H160,
+    ) -> anyhow::Result<()> {
+        let tx_hashes = tx_hashes.to_vec();
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            for h in tx_hashes {
+                if let Some(mut pt) = read_tx(&db_tx, h)? {
+                    pt.entry_point = entry_point;
+                    write_tx(&db_tx, &pt)?;
+                }
+            }
+            db_tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn evict_txs(&self, tx_hashes: &[H256], reason: &str) -> anyhow::Result<usize> {
+        let tx_hashes = tx_hashes.to_vec();
+        let reason = reason.to_string();
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            let mut evicted = 0;
+            for h in tx_hashes {
+                if let Some(mut pt) =
+                    read_tx(&db_tx, h)?.filter(|pt| pt.status == TxStatus::Received)
+                {
+                    pt.status = TxStatus::Evicted;
+                    pt.evict_reason = Some(reason.clone());
+                    write_tx(&db_tx, &pt)?;
+                    evicted += 1;
+                }
+            }
+            db_tx.commit()?;
+            Ok(evicted)
+        })
+        .await
+    }
+
+    async fn archive_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()> {
+        let tx_hashes = tx_hashes.to_vec();
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            archive_txs(&db_tx, &tx_hashes)?;
+            db_tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_archived_txs(&self) -> anyhow::Result<Vec<PoolTx>> {
+        self.run(|conn| query_txs(conn, "SELECT data FROM pool_tx_archive", []))
+            .await
+    }
+
+    async fn delete_txs(&self, tx_hashes: &[H256]) -> anyhow::Result<()> {
+        let tx_hashes = tx_hashes.to_vec();
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            for h in tx_hashes {
+                db_tx.execute("DELETE FROM pool_tx WHERE tx_hash = ?1", [h.encode_hex()])?;
+            }
+            db_tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn claim_txs(&self, tx_hashes: &[H256], batch_hash: H256) -> anyhow::Result<usize> {
+        let tx_hashes = tx_hashes.to_vec();
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            let mut claimed = 0;
+            for h in tx_hashes {
+                if let Some(mut pt) =
+                    read_tx(&db_tx, h)?.filter(|pt| pt.status == TxStatus::Received)
+                {
+                    pt.status = TxStatus::Pending;
+                    pt.batch_hash = Some(batch_hash);
+                    write_tx(&db_tx, &pt)?;
+                    claimed += 1;
+                }
+            }
+            db_tx.commit()?;
+            Ok(claimed)
+        })
+        .await
+    }
+
+    async fn release_txs(&self, batch_hash: H256) -> anyhow::Result<usize> {
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            let claimed = query_txs(
+                &db_tx,
+                "SELECT data FROM pool_tx WHERE batch_hash = ?1 AND status IN (?2, ?3)",
+                params![
+                    batch_hash.encode_hex(),
+                    TxStatus::Pending as u8,
+                    TxStatus::Failed as u8
+                ],
+            )?;
+            for mut pt in claimed.iter().cloned() {
+                pt.status = TxStatus::Received;
+                pt.batch_hash = None;
+                write_tx(&db_tx, &pt)?;
+            }
+            db_tx.commit()?;
+            Ok(claimed.len())
+        })
+        .await
+    }
+
+    async fn find_batch(&self, batch_hash: H256) -> anyhow::Result<Option<PoolBatch>> {
+        self.run(move |conn| read_batch(conn, batch_hash)).await
+    }
+
+    async fn insert_batch(&self, pool_batch: PoolBatch) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO pool_batch (batch_hash, status, created_at, updated_at, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    pool_batch.batch_hash.encode_hex(),
+                    pool_batch.status as u8,
+                    pool_batch.created_at.timestamp_millis(),
+                    pool_batch.updated_at().timestamp_millis(),
+                    serde_json::to_string(&pool_batch)?
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn find_batches_created_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>> {
+        let statuses = statuses_sql(statuses);
+        self.run(move |conn| {
+            let sql = format!(
+                "SELECT data FROM pool_batch WHERE status IN ({}) AND created_at < ?1
+                 ORDER BY id LIMIT ?2",
+                statuses
+            );
+            query_batches(conn, &sql, params![before.timestamp_millis(), limit as i64])
+        })
+        .await
+    }
+
+    async fn find_batches_updated_before(
+        &self,
+        statuses: &[BatchStatus],
+        before: DateTime,
+        limit: usize,
+    ) -> anyhow::Result<Vec<PoolBatch>> {
+        let statuses = statuses_sql(statuses);
+        self.run(move |conn| {
+            let sql = format!(
+                "SELECT data FROM pool_batch WHERE status IN ({}) AND updated_at < ?1
+                 ORDER BY id LIMIT ?2",
+                statuses
+            );
+            query_batches(conn, &sql, params![before.timestamp_millis(), limit as i64])
+        })
+        .await
+    }
+
+    async fn archive_batch(&self, batch_hash: H256) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            let db_tx = conn.transaction()?;
+            if let Some(pb) = read_batch(&db_tx, batch_hash)? {
+                archive_txs(&db_tx, &pb.tx_hash_list)?;
+                db_tx.execute(
+                    "INSERT OR REPLACE INTO pool_batch_archive (batch_hash, data)
+                     SELECT batch_hash, data FROM pool_batch WHERE batch_hash = ?1",
+                    [batch_hash.encode_hex()],
+                )?;
+                db_tx.execute(
+                    "DELETE FROM pool_batch WHERE batch_hash = ?1",
+                    [batch_hash.encode_hex()],
+                )?;
+            }
+            db_tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_archived_batches(&self) -> anyhow::Result<Vec<PoolBatch>> {
+        self.run(|conn| query_batches(conn, "SELECT data FROM pool_batch_archive", []))
+            .await
+    }
+
+    async fn delete_batch(&self, batch_hash: H256) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "DELETE FROM pool_batch WHERE batch_hash = ?1",
+                [batch_hash.encode_hex()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_batch_status(
+        &self,
+        batch_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            update_batch(conn, batch_hash, |pb| {
+                pb.status = status;
+                pb.updated_at = Some(DateTime::now());
+            })
+        })
+        .await
+    }
+
+    async fn update_batch_entry_point(
+        &self,
+        batch_hash: H256,
+        entry_point: H160,
+    ) -> anyhow::Result<()> {
+        self.run(move |conn| update_batch(conn, batch_hash, |pb| pb.entry_point = entry_point))
+            .await
+    }
+
+    async fn update_batch_miner(&self, batch_hash: H256, miner: H160) -> anyhow::Result<()> {
+        self.run(move |conn| update_batch(conn, batch_hash, |pb| pb.miner = miner))
+            .await
+    }
+
+    async fn update_batch_state_roots(
+        &self,
+        batch_hash: H256,
+        pre_state_root: H256,
+        post_state_root: H256,
+    ) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            update_batch(conn, batch_hash, |pb| {
+                pb.pre_state_root = pre_state_root;
+                pb.post_state_root = post_state_root;
+            })
+        })
+        .await
+    }
+
+    async fn update_batch_proof(
+        &self,
+        batch_hash: H256,
+        zk_proof: Bytes,
+        zk_pub_inputs: Vec<U256>,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            update_batch(conn, batch_hash, |pb| {
+                pb.zk_proof = Some(zk_proof);
+                pb.zk_pub_inputs = zk_pub_inputs;
+                pb.status = status;
+                pb.updated_at = Some(DateTime::now());
+            })
+        })
+        .await
+    }
+
+    async fn update_batch_send_tx_hash(
+        &self,
+        batch_hash: H256,
+        send_tx_hash: H256,
+        status: BatchStatus,
+    ) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            update_batch(conn, batch_hash, |pb| {
+                pb.send_tx_hash = send_tx_hash;
+                pb.status = status;
+                pb.updated_at = Some(DateTime::now());
+            })
+        })
+        .await
+    }
+
+    async fn insert_ledger_entry(&self, entry: LedgerEntry) -> anyhow::Result<()> {
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO ledger (tx_hash, created_at, data) VALUES (?1, ?2, ?3)",
+                params![
+                    entry.tx_hash.encode_hex(),
+                    entry.created_at.timestamp_millis(),
+                    serde_json::to_string(&entry)?
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_ledger(&self, limit: usize) -> anyhow::Result<Vec<LedgerEntry>> {
+        self.run(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT data FROM ledger ORDER BY created_at DESC LIMIT ?1")?;
+            let rows = stmt.query_map([limit as i64], |row| row.get::<_, String>(0))?;
+
+            let mut entries = vec![];
+            for data in rows {
+                entries.push(serde_json::from_str(&data?)?);
+            }
+            Ok(entries)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::model::ledger_entry::LedgerKind;
+    use crate::store::memory::MemoryPoolStore;
+    use crate::store::{copy_pool, CopyCounts};
+    use crate::test_util::{pool_batch, pool_tx, ENTRY_POINT};
+
+    // A store in a file of its own, removed with its WAL files on drop
+    struct TempStore {
+        path: PathBuf,
+        store: SqlitePoolStore,
+    }
+
+    impl TempStore {
+        fn open(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "bundler-{}-{}.sqlite",
+                name,
+                std::process::id()
+            ));
+            let store = SqlitePoolStore::open(path.to_str().unwrap()).unwrap();
+            Self { path, store }
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.path.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    fn ms(millis: i64) -> DateTime {
+        DateTime::from_millis(millis)
+    }
+
+    #[tokio::test]
+    async fn stores_and_claims_txs() {
+        let temp = TempStore::open("txs");
+        let store = &temp.store;
+        let newer = pool_tx(1, 0, ms(2_000));
+        let older = pool_tx(2, 0, ms(1_000));
+        let mut other = pool_tx(3, 0, ms(500));
+        other.entry_point = H160::repeat_byte(0x99);
+        for pt in [newer.clone(), older.clone(), other.clone()] {
+            store.insert_tx(pt).await.unwrap();
+        }
+
+        assert_eq!(
+            store.find_tx(newer.tx_hash).await.unwrap(),
+            Some(newer.clone())
+        );
+        let hashes: Vec<H256> = store
+            .find_txs_by_entry_point(ENTRY_POINT, TxStatus::Received, 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|pt| pt.tx_hash)
+            .collect();
+        assert_eq!(hashes, vec![older.tx_hash, newer.tx_hash]);
+
+        let batch_hash = H256::repeat_byte(1);
+        assert_eq!(store.claim_txs(&hashes, batch_hash).await.unwrap(), 2);
+        assert_eq!(
+            store
+                .claim_txs(&hashes, H256::repeat_byte(2))
+                .await
+                .unwrap(),
+            0
+        );
+        let claimed = store.find_tx(older.tx_hash).await.unwrap().unwrap();
+        assert_eq!(claimed.status, TxStatus::Pending);
+        assert_eq!(claimed.batch_hash, Some(batch_hash));
+        assert_eq!(store.count_txs(TxStatus::Received, None).await.unwrap(), 1);
+
+        assert_eq!(store.release_txs(batch_hash).await.unwrap(), 2);
+        assert_eq!(store.count_txs(TxStatus::Received, None).await.unwrap(), 3);
+
+        assert_eq!(
+            store.evict_txs(&[other.tx_hash], "too old").await.unwrap(),
+            1
+        );
+        let evicted = store.find_tx(other.tx_hash).await.unwrap().unwrap();
+        assert_eq!(evicted.status, TxStatus::Evicted);
+        assert_eq!(evicted.evict_reason.as_deref(), Some("too old"));
+    }
+
+    #[tokio::test]
+    async fn stores_and_archives_batches() {
+        let temp = TempStore::open("batches");
+        let store = &temp.store;
+        let pt = pool_tx(1, 0, ms(1_000));
+        let mut pb = pool_batch(1, BatchStatus::Received, None);
+        pb.created_at = ms(1_000);
+        pb.tx_hash_list = vec![pt.tx_hash];
+        store.insert_tx(pt.clone()).await.unwrap();
+        store.insert_batch(pb.clone()).await.unwrap();
+        assert_eq!(
+            store.find_batch(pb.batch_hash).await.unwrap(),
+            Some(pb.clone())
+        );
+
+        store
+            .update_batch_proof(
+                pb.batch_hash,
+                Bytes::from(vec![1; 4]),
+                vec![U256::one()],
+                BatchStatus::Submitting,
+            )
+            .await
+            .unwrap();
+        store
+            .update_batch_status(pb.batch_hash, BatchStatus::Succeed)
+            .await
+            .unwrap();
+        let finished = store.find_batch(pb.batch_hash).await.unwrap().unwrap();
+        assert_eq!(finished.zk_proof, Some(Bytes::from(vec![1; 4])));
+        assert!(finished.updated_at.is_some());
+
+        let statuses = [BatchStatus::Succeed];
+        assert!(store
+            .find_batches_updated_before(&statuses, ms(2_000), 10)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            store
+                .find_batches_created_before(&statuses, ms(2_000), 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            store
+                .find_batches_updated_before(&statuses, DateTime::MAX, 10)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        store.archive_batch(pb.batch_hash).await.unwrap();
+        assert_eq!(store.find_batch(pb.batch_hash).await.unwrap(), None);
+        assert_eq!(store.find_tx(pt.tx_hash).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn adds_filter_columns_to_old_files() {
+        let temp = TempStore::open("old");
+        let pt = pool_tx(1, 0, ms(1_000));
+        let mut pb = pool_batch(1, BatchStatus::Failed, None);
+        pb.created_at = ms(1_000);
+        {
+            let conn = temp.store.conn.lock().unwrap();
+            conn.execute_batch(
+                "DROP TABLE pool_tx;
+                 DROP TABLE pool_batch;
+                 CREATE TABLE pool_tx (
+                     tx_hash TEXT PRIMARY KEY,
+                     status INTEGER NOT NULL,
+                     created_at INTEGER NOT NULL,
+                     data TEXT NOT NULL
+                 );
+                 CREATE TABLE pool_batch (
+                     id INTEGER PRIMARY KEY AUTOINCREMENT,
+                     batch_hash TEXT NOT NULL UNIQUE,
+                     status INTEGER NOT NULL,
+                     data TEXT NOT NULL
+                 );",
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO pool_tx (tx_hash, status, created_at, data) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    pt.tx_hash.encode_hex(),
+                    pt.status as u8,
+                    1_000,
+                    serde_json::to_string(&pt).unwrap()
+                ],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO pool_batch (batch_hash, status, data) VALUES (?1, ?2, ?3)",
+                params![
+                    pb.batch_hash.encode_hex(),
+                    pb.status as u8,
+                    serde_json::to_string(&pb).unwrap()
+                ],
+            )
+            .unwrap();
+        }
+
+        let store = SqlitePoolStore::open(temp.path.to_str().unwrap()).unwrap();
+        store.ensure_indexes().await.unwrap();
+        assert_eq!(
+            store
+                .find_txs_by_entry_point(ENTRY_POINT, TxStatus::Received, 10)
+                .await
+                .unwrap(),
+            vec![pt.clone()]
+        );
+        assert_eq!(
+            store
+                .find_txs_from(pt.tx_from, &[TxStatus::Received])
+                .await
+                .unwrap(),
+            vec![pt.clone()]
+        );
+        assert_eq!(
+            store
+                .count_txs(TxStatus::Received, Some(H160::repeat_byte(9)))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            store
+                .find_batches_created_before(&[BatchStatus::Failed], ms(2_000), 10)
+                .await
+                .unwrap(),
+            vec![pb.clone()]
+        );
+        assert_eq!(
+            store
+                .find_batches_updated_before(&[BatchStatus::Failed], ms(500), 10)
+                .await
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_pool_skips_records_it_has() {
+        let from = MemoryPoolStore::new();
+        let to = TempStore::open("copy");
+        let first = pool_tx(1, 0, ms(1_000));
+        let second = pool_tx(2, 0, ms(2_000));
+        let pb = pool_batch(1, BatchStatus::Received, None);
+        for pt in [first.clone(), second.clone()] {
+            from.insert_tx(pt).await.unwrap();
+        }
+        from.insert_batch(pb.clone()).await.unwrap();
+
+        let mut copied = first.clone();
+        copied.status = TxStatus::Evicted;
+        to.store.insert_tx(copied).await.unwrap();
+
+        let counts = copy_pool(&from, &to.store).await.unwrap();
+        assert_eq!((counts.txs, counts.batches), (1, 1));
+        let kept = to.store.find_tx(first.tx_hash).await.unwrap().unwrap();
+        assert_eq!(kept.status, TxStatus::Evicted);
+        assert_eq!(
+            to.store.find_tx(second.tx_hash).await.unwrap(),
+            Some(second)
+        );
+        assert_eq!(to.store.find_batch(pb.batch_hash).await.unwrap(), Some(pb));
+
+        let counts = copy_pool(&from, &to.store).await.unwrap();
+        assert_eq!(counts, CopyCounts::default());
+    }
+
+    #[tokio::test]
+    async fn copy_pool_copies_archives_ledger_and_schema_version() {
+        let from = MemoryPoolStore::new();
+        let to = TempStore::open("copy-all");
+        let archived = pool_tx(1, 0, ms(1_000));
+        let batched = pool_tx(2, 0, ms(2_000));
+        let mut pb = pool_batch(1, BatchStatus::Succeed, None);
+        pb.tx_hash_list = vec![batched.tx_hash];
+        for pt in [archived.clone(), batched.clone()] {
+            from.insert_tx(pt).await.unwrap();
+        }
+        from.insert_batch(pb.clone()).await.unwrap();
+        from.archive_txs(&[archived.tx_hash]).await.unwrap();
+        from.archive_batch(pb.batch_hash).await.unwrap();
+        let entries: Vec<LedgerEntry> = (1..=2)
+            .map(|i| LedgerEntry {
+                tx_hash: H256::repeat_byte(i),
+                kind: LedgerKind::Sweep,
+                from: H160::repeat_byte(i),
+                to: H160::repeat_byte(9),
+                amount: U256::from(i),
+                created_at: ms(i as i64 * 1_000),
+            })
+            .collect();
+        for entry in entries.iter() {
+            from.insert_ledger_entry(entry.clone()).await.unwrap();
+        }
+        from.set_schema_version(3).await.unwrap();
+
+        let counts = copy_pool(&from, &to.store).await.unwrap();
+        assert_eq!(
+            counts,
+            CopyCounts {
+                archived_txs: 2,
+                archived_batches: 1,
+                ledger: 2,
+                ..CopyCounts::default()
+            }
+        );
+        let mut txs = to.store.list_archived_txs().await.unwrap();
+        txs.sort_by_key(|pt| pt.created_at);
+        assert_eq!(txs, vec![archived, batched]);
+        assert_eq!(to.store.list_archived_batches().await.unwrap(), vec![pb]);
+        assert_eq!(to.store.list_txs().await.unwrap(), vec![]);
+        assert_eq!(to.store.list_batches().await.unwrap(), vec![]);
+        let newest_first: Vec<LedgerEntry> = entries.into_iter().rev().collect();
+        assert_eq!(to.store.list_ledger(10).await.unwrap(), newest_first);
+        assert_eq!(to.store.schema_version().await.unwrap(), 3);
+
+        let counts = copy_pool(&from, &to.store).await.unwrap();
+        assert_eq!(counts, CopyCounts::default());
+    }
+}
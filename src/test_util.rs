@@ -0,0 +1,220 @@
+// Fixtures for the unit tests. The stores, upstreams and switches are
+// registries keyed by chain id, so every test takes a chain id of its own.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use ethers::abi::AbiEncode;
+use ethers::signers::{LocalWallet, Signer};
+use ethers::types::{Bytes, Transaction, H160, H256, U256};
+use jsonrpsee::server::{RpcModule, ServerBuilder};
+use mongodb::bson::DateTime;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::{BundlerConfig, Config, MinerConfig, NetworkConfig, SignerConfig, StoreConfig};
+use crate::model::pool_batch::{BatchStatus, PoolBatch};
+use crate::model::pool_tx::{PoolTx, TxStatus};
+use crate::service::pool::{HandleOpsCall, UserOperation};
+use crate::store::{init_pool_store, PoolStore};
+use crate::upstream::init_upstream;
+
+pub const ENTRY_POINT: H160 = H160([0x37; 20]);
+
+/// A config for `chain_id` serving `ENTRY_POINT`, with its pool in memory.
+pub async fn test_config(chain_id: u64) -> (Config, Arc<dyn PoolStore>) {
+    let mut config = Config::default();
+    config.bundler.chain_id = format!("{:#x}", chain_id);
+    config.bundler.entry_point_address = ENTRY_POINT;
+    config.bundler.check_paymasters = false;
+    config.store = StoreConfig {
+        backend: String::from("memory"),
+        ..StoreConfig::default()
+    };
+
+    let store = init_pool_store(chain_id, &config.store).await.unwrap();
+    (config, store)
+}
+
+pub fn user_op(sender: u8, nonce: u64) -> UserOperation {
+    UserOperation {
+        sender: H160::repeat_byte(sender),
+        nonce: U256::from(nonce),
+        init_code: Bytes::new(),
+        call_data: Bytes::from(vec![sender, 1, 2, 3]),
+        call_gas_limit: U256::from(100_000),
+        verification_gas_limit: U256::from(200_000),
+        pre_verification_gas: U256::from(50_000),
+        max_fee_per_gas: U256::from(1_000_000_000),
+        max_priority_fee_per_gas: U256::from(1_000_000),
+        paymaster_and_data: Bytes::new(),
+        signature: Bytes::from(vec![nonce as u8; 65]),
+    }
+}
+
+/// A received tx of `ENTRY_POINT` calling handleOps with one op.
+pub fn pool_tx(sender: u8, nonce: u64, created_at: DateTime) -> PoolTx {
+    let input = HandleOpsCall {
+        ops: vec![user_op(sender, nonce)],
+        proof: Bytes::new(),
+        pub_signals: [U256::zero()],
+        beneficiary: H160::zero(),
+    }
+    .encode();
+    let tx = Transaction {
+        hash: H256::from(ethers::utils::keccak256(&input)),
+        from: H160::repeat_byte(sender),
+        to: Some(ENTRY_POINT),
+        input: Bytes::from(input),
+        ..Transaction::default()
+    };
+
+    PoolTx {
+        tx_from: tx.from,
+        tx_hash: tx.hash,
+        tx,
+        created_at,
+        status: TxStatus::Received,
+        batch_hash: None,
+        evict_reason: None,
+        entry_point: ENTRY_POINT,
+    }
+}
+
+/// A batch of `ENTRY_POINT` without txs.
+pub fn pool_batch(id: u8, status: BatchStatus, parent: Option<H256>) -> PoolBatch {
+    PoolBatch {
+        batch_hash: H256::repeat_byte(id),
+        tx_hash_list: vec![],
+        zk_proof: None,
+        zk_pub_inputs: vec![],
+        send_tx_hash: H256::zero(),
+        created_at: DateTime::now(),
+        status,
+        entry_point: ENTRY_POINT,
+        miner: H160::zero(),
+        commitment: U256::zero(),
+        pre_state_root: H256::zero(),
+        post_state_root: H256::zero(),
+        parent,
+        circuit_id: String::new(),
+        updated_at: None,
+    }
+}
+
+/// A miner account signing with the private key of byte `n` repeated.
+pub fn miner(n: u8) -> MinerConfig {
+    let private_key = format!("{:02x}", n).repeat(32);
+    let wallet: LocalWallet = private_key.parse().unwrap();
+    MinerConfig {
+        address: wallet.address(),
+        private_key,
+        signer: SignerConfig::default(),
+    }
+}
+
+/// Sends from the accounts of `miner(1)` to `miner(count)`.
+pub fn with_miners(bundler: &mut BundlerConfig, count: u8) {
+    let first = miner(1);
+    bundler.miner_address = first.address;
+    bundler.miner_private_key = first.private_key;
+    bundler.miners = (2..=count).map(miner).collect();
+}
+
+/// Serves `module` on a free local port until the test ends. Returns its
+/// URL.
+pub async fn serve<C: Send + Sync + 'static>(module: RpcModule<C>) -> String {
+    let server = ServerBuilder::default().build("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", server.local_addr().unwrap());
+    tokio::spawn(server.start(module).unwrap().stopped());
+    url
+}
+
+/// A stand-in node. Each method in `NODE_METHODS` answers with what
+/// `answer_for` set for its first param, else what `answer` set for it, null
+/// until then, and records its params.
+#[derive(Default)]
+pub struct TestNode {
+    answers: Mutex<HashMap<&'static str, Value>>,
+    answers_for: Mutex<HashMap<(&'static str, Value), Value>>,
+    calls: Mutex<Vec<(&'static str, Value)>>,
+}
+
+impl TestNode {
+    pub fn answer(&self, method: &'static str, answer: impl Serialize) {
+        let answer = serde_json::to_value(answer).unwrap();
+        self.answers.lock().unwrap().insert(method, answer);
+    }
+
+    /// Answers calls of `method` whose first param is `param`, e.g. the
+    /// balance of one address.
+    pub fn answer_for(&self, method: &'static str, param: impl Serialize, answer: impl Serialize) {
+        let param = serde_json::to_value(param).unwrap();
+        let answer = serde_json::to_value(answer).unwrap();
+        self.answers_for
+            .lock()
+            .unwrap()
+            .insert((method, param), answer);
+    }
+
+    /// The params of every call of `method` so far.
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(called, _)| *called == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+}
+
+const NODE_METHODS: &[&str] = &[
+    "eth_chainId",
+    "eth_blockNumber",
+    "eth_call",
+    "eth_getBlockByNumber",
+    "eth_getBlockByHash",
+    "eth_getTransactionCount",
+    "eth_getTransactionByHash",
+    "eth_getTransactionReceipt",
+    "eth_getBalance",
+    "eth_gasPrice",
+    "eth_sendRawTransaction",
+];
+
+/// Starts a `TestNode` for `chain_id` and makes it the chain's upstream.
+pub async fn start_node(chain_id: u64) -> Arc<TestNode> {
+    let node = Arc::new(TestNode::default());
+    node.answer("eth_chainId", U256::from(chain_id));
+
+    let mut module = RpcModule::new(node.clone());
+    for method in NODE_METHODS {
+        module
+            .register_method(method, |params, node| {
+                let params = params.parse::<Value>().unwrap_or(Value::Null);
+                let first = params.get(0).cloned().unwrap_or(Value::Null);
+                node.calls.lock().unwrap().push((method, params));
+                let answer = node
+                    .answers_for
+                    .lock()
+                    .unwrap()
+                    .get(&(*method, first))
+                    .cloned();
+                let answers = node.answers.lock().unwrap();
+                Ok(answer
+                    .or_else(|| answers.get(method).cloned())
+                    .unwrap_or(Value::Null))
+            })
+            .unwrap();
+    }
+
+    let network = NetworkConfig {
+        rpc_url: serve(module).await,
+        retries: 0,
+        ..NetworkConfig::default()
+    };
+    init_upstream(chain_id, &network).unwrap();
+
+    node
+}
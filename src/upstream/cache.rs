@@ -0,0 +1,415 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ethers::providers::Middleware;
+use ethers::types::{BlockId, BlockNumber};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::error;
+
+use crate::config::CacheConfig;
+use crate::upstream::get_upstream;
+
+/// How long a cached answer stays valid.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Lifetime {
+    // Until the next block, or `cache.block_time_secs` if the watcher
+    // misses it
+    Block,
+    // Finalized data, never changes
+    Forever,
+}
+
+struct CacheEntry {
+    value: Value,
+    lifetime: Lifetime,
+    cached_at: Instant,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheReport {
+    pub entries: usize,
+    pub latest_block: u64,
+    pub methods: BTreeMap<String, CacheStats>,
+}
+
+/// Read-through cache for the chain queries `OpenRpcServerImpl` proxies to
+/// the upstream nodes of one chain. Answers are keyed by method and params.
+pub struct RpcCache {
+    chain_id: u64,
+    config: CacheConfig,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    latest_block: AtomicU64,
+    stats: Mutex<BTreeMap<String, CacheStats>>,
+}
+
+impl RpcCache {
+    pub fn new(chain_id: u64, config: &CacheConfig) -> Self {
+        Self {
+            chain_id,
+            config: config.clone(),
+            entries: Mutex::new(HashMap::new()),
+            latest_block: AtomicU64::new(0),
+            stats: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// `Forever` for a block by hash or one at least `cache.finality_depth`
+    /// blocks old, `Block` for anything that can still change.
+    pub fn lifetime_of(&self, block_id: Option<BlockId>) -> Lifetime {
+        let latest = self.latest_block.load(Ordering::SeqCst);
+
+        match block_id {
+            Some(BlockId::Hash(_)) => Lifetime::Forever,
+            Some(BlockId::Number(BlockNumber::Earliest)) => Lifetime::Forever,
+            Some(BlockId::Number(BlockNumber::Number(number)))
+                if latest > 0 && number.as_u64() + self.config.finality_depth <= latest =>
+            {
+                Lifetime::Forever
+            }
+            _ => Lifetime::Block,
+        }
+    }
+
+    /// The cached answer for `method` with `params`, else the result of
+    /// `fetch`, which is cached if it succeeds.
+    pub async fn get_or_fetch<P, T, E, F>(
+        &self,
+        method: &str,
+        params: P,
+        lifetime: Lifetime,
+        fetch: F,
+    ) -> Result<T, E>
+    where
+        P: Serialize,
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, E>>,
+    {
+        if !self.config.enabled {
+            return fetch.await;
+        }
+
+        let key = format!(
+            "{}:{}",
+            method,
+            serde_json::to_string(&params).unwrap_or_default()
+        );
+        if let Some(value) = self.get(&key) {
+            if let Ok(result) = serde_json::from_value(value) {
+                self.count(method, true);
+                return Ok(result);
+            }
+        }
+        self.count(method, false);
+
+        let result = fetch.await?;
+        // Not found may only mean not yet
+        match serde_json::to_value(&result) {
+            Ok(Value::Null) | Err(_) => {}
+            Ok(value) => self.insert(key, value, lifetime),
+        }
+
+        Ok(result)
+    }
+
+    fn get(&self, key: &str) -> Option<Value> {
+        let block_time = Duration::from_secs(self.config.block_time_secs);
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .get(key)
+            .filter(|entry| {
+                entry.lifetime == Lifetime::Forever || entry.cached_at.elapsed() < block_time
+            })
+            .map(|entry| entry.value.clone())
+    }
+
+    fn insert(&self, key: String, value: Value, lifetime: Lifetime) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.config.max_entries {
+            entries.retain(|_, entry| entry.lifetime == Lifetime::Forever);
+            if entries.len() >= self.config.max_entries {
+                entries.clear();
+            }
+        }
+        entries.insert(
+            key,
+            CacheEntry {
+                value,
+                lifetime,
+                cached_at: Instant::now(),
+            },
+        );
+    }
+
+    fn count(&self, method: &str, hit: bool) {
+        let mut stats = self.stats.lock().unwrap();
+        let method_stats = stats.entry(method.to_string()).or_default();
+        if hit {
+            method_stats.hits += 1;
+        } else {
+            method_stats.misses += 1;
+        }
+    }
+
+    /// Hits and misses per method since startup.
+    pub fn report(&self) -> CacheReport {
+        CacheReport {
+            entries: self.entries.lock().unwrap().len(),
+            latest_block: self.latest_block.load(Ordering::SeqCst),
+            methods: self.stats.lock().unwrap().clone(),
+        }
+    }
+
+    // Drops every per-block answer once the chain moves on
+    fn on_block(&self, block_number: u64) {
+        let previous = self.latest_block.swap(block_number, Ordering::SeqCst);
+        if previous != block_number {
+            self.entries
+                .lock()
+                .unwrap()
+                .retain(|_, entry| entry.lifetime == Lifetime::Forever);
+        }
+    }
+
+    /// Polls the upstream block number every `cache.poll_interval_ms`,
+    /// invalidating per-block answers on every new block. Runs forever.
+    pub async fn watch_new_blocks(self: Arc<Self>) {
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.poll_interval_ms));
+
+        loop {
+            interval.tick().await;
+
+            match get_upstream(self.chain_id).get_block_number().await {
+                Ok(block_number) => self.on_block(block_number.as_u64()),
+                Err(err) => error!("Block watcher failed: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use ethers::types::{H256, U64};
+
+    use super::*;
+    use crate::test_util::start_node;
+
+    fn cache(block_time_secs: u64) -> RpcCache {
+        RpcCache::new(
+            1,
+            &CacheConfig {
+                block_time_secs,
+                poll_interval_ms: 10,
+                finality_depth: 64,
+                ..CacheConfig::default()
+            },
+        )
+    }
+
+    // Counts the fetches that reach the node
+    async fn get(cache: &RpcCache, method: &str, lifetime: Lifetime, fetches: &AtomicUsize) -> u64 {
+        let fetch = async { Ok::<_, ()>(fetches.fetch_add(1, Ordering::SeqCst) as u64) };
+        cache
+            .get_or_fetch(method, (), lifetime, fetch)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn blocks_by_hash_or_deep_enough_are_final() {
+        let cache = cache(12);
+        let number = |n: u64| Some(BlockId::Number(BlockNumber::Number(n.into())));
+        // Nothing is deep enough before the first block is seen
+        assert_eq!(cache.lifetime_of(number(1)), Lifetime::Block);
+
+        cache.on_block(100);
+        assert_eq!(cache.lifetime_of(number(36)), Lifetime::Forever);
+        assert_eq!(cache.lifetime_of(number(37)), Lifetime::Block);
+        assert_eq!(
+            cache.lifetime_of(Some(BlockId::Hash(H256::zero()))),
+            Lifetime::Forever
+        );
+        assert_eq!(
+            cache.lifetime_of(Some(BlockNumber::Earliest.into())),
+            Lifetime::Forever
+        );
+        assert_eq!(
+            cache.lifetime_of(Some(BlockNumber::Latest.into())),
+            Lifetime::Block
+        );
+        assert_eq!(cache.lifetime_of(None), Lifetime::Block);
+    }
+
+    #[tokio::test]
+    async fn block_answers_last_until_the_next_block() {
+        let cache = cache(12);
+        let fetches = AtomicUsize::new(0);
+        cache.on_block(1);
+
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            0
+        );
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            0
+        );
+        assert_eq!(
+            get(&cache, "eth_getCode", Lifetime::Forever, &fetches).await,
+            1
+        );
+
+        // The same block again changes nothing
+        cache.on_block(1);
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            0
+        );
+
+        cache.on_block(2);
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            2
+        );
+        assert_eq!(
+            get(&cache, "eth_getCode", Lifetime::Forever, &fetches).await,
+            1
+        );
+
+        let report = cache.report();
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.latest_block, 2);
+        assert_eq!(report.methods["eth_gasPrice"].hits, 2);
+        assert_eq!(report.methods["eth_gasPrice"].misses, 2);
+        assert_eq!(report.methods["eth_getCode"].hits, 1);
+    }
+
+    #[tokio::test]
+    async fn block_time_bounds_block_answers() {
+        // Expired as soon as cached, as if the watcher missed every block
+        let cache = cache(0);
+        let fetches = AtomicUsize::new(0);
+
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            0
+        );
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            1
+        );
+        assert_eq!(
+            get(&cache, "eth_getCode", Lifetime::Forever, &fetches).await,
+            2
+        );
+        assert_eq!(
+            get(&cache, "eth_getCode", Lifetime::Forever, &fetches).await,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_nulls_and_a_disabled_cache() {
+        let cache = cache(12);
+        let fetch = || async { Ok::<Option<u64>, ()>(None) };
+        for _ in 0..2 {
+            let result = cache
+                .get_or_fetch("eth_getTransactionReceipt", (), Lifetime::Forever, fetch())
+                .await;
+            assert_eq!(result, Ok(None));
+        }
+        assert_eq!(cache.report().entries, 0);
+        assert_eq!(
+            cache.report().methods["eth_getTransactionReceipt"].misses,
+            2
+        );
+
+        let disabled = RpcCache::new(
+            1,
+            &CacheConfig {
+                enabled: false,
+                ..CacheConfig::default()
+            },
+        );
+        let fetches = AtomicUsize::new(0);
+        assert_eq!(
+            get(&disabled, "eth_getCode", Lifetime::Forever, &fetches).await,
+            0
+        );
+        assert_eq!(
+            get(&disabled, "eth_getCode", Lifetime::Forever, &fetches).await,
+            1
+        );
+        assert!(disabled.report().methods.is_empty());
+    }
+
+    async fn wait_for_block(cache: &RpcCache, block_number: u64) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while cache.report().latest_block != block_number {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn watcher_drops_block_answers_on_new_blocks() {
+        let node = start_node(4001).await;
+        node.answer("eth_blockNumber", U64::from(1));
+        let cache = Arc::new(RpcCache::new(
+            4001,
+            &CacheConfig {
+                poll_interval_ms: 10,
+                ..CacheConfig::default()
+            },
+        ));
+        let watcher = tokio::spawn(cache.clone().watch_new_blocks());
+        wait_for_block(&cache, 1).await;
+
+        let fetches = AtomicUsize::new(0);
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            0
+        );
+        assert_eq!(
+            get(&cache, "eth_getCode", Lifetime::Forever, &fetches).await,
+            1
+        );
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            0
+        );
+
+        node.answer("eth_blockNumber", U64::from(2));
+        wait_for_block(&cache, 2).await;
+        assert_eq!(
+            get(&cache, "eth_gasPrice", Lifetime::Block, &fetches).await,
+            2
+        );
+        assert_eq!(
+            get(&cache, "eth_getCode", Lifetime::Forever, &fetches).await,
+            1
+        );
+        assert!(node.calls("eth_blockNumber").len() >= 2);
+
+        watcher.abort();
+    }
+}
@@ -0,0 +1,632 @@
+pub mod cache;
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ethers::providers::{
+    Http, HttpClientError, JsonRpcClient, JsonRpcError, Provider, ProviderError, RpcError,
+};
+use ethers::types::U64;
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::config::NetworkConfig;
+
+lazy_static! {
+    // By chain id
+    static ref UPSTREAMS: RwLock<HashMap<u64, Provider<UpstreamClient>>> = RwLock::new(HashMap::new());
+}
+
+// The backoff stops doubling after this many rounds
+const MAX_BACKOFF_DOUBLINGS: u32 = 6;
+
+// Node methods that only read state, so a retry can't do anything twice
+fn is_idempotent(method: &str) -> bool {
+    method.starts_with("eth_get")
+        || matches!(
+            method,
+            "eth_call"
+                | "eth_chainId"
+                | "eth_blockNumber"
+                | "eth_estimateGas"
+                | "eth_feeHistory"
+                | "eth_gasPrice"
+                | "eth_maxPriorityFeePerGas"
+                | "net_version"
+                | "web3_clientVersion"
+        )
+}
+
+// Errors a later attempt may not hit: the node is down, slow or rate
+// limiting us
+fn is_transient(error: &HttpClientError) -> bool {
+    match error {
+        HttpClientError::ReqwestError(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || matches!(err.status(), Some(s) if s.is_server_error() || s.as_u16() == 429)
+        }
+        HttpClientError::JsonRpcError(err) => err.code == 429 || err.code == -32005,
+        // Not JSON at all, like the empty body or error page of a 5xx from
+        // the node's proxy
+        HttpClientError::SerdeJson { text, .. } => {
+            !text.trim_start().starts_with('{') && !text.trim_start().starts_with('[')
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    // The node answered, with an error
+    Rpc(HttpClientError),
+    // No node answered, after every retry
+    Unreachable {
+        url: String,
+        method: String,
+        attempts: u32,
+        source: HttpClientError,
+    },
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamError::Rpc(err) => write!(f, "{}", err),
+            UpstreamError::Unreachable {
+                url,
+                method,
+                attempts,
+                source,
+            } => write!(
+                f,
+                "Upstream nodes are unreachable ({} failed after {} attempts, last on {}): {}",
+                method, attempts, url, source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+impl RpcError for UpstreamError {
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            UpstreamError::Rpc(err) => err.as_error_response(),
+            UpstreamError::Unreachable { .. } => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            UpstreamError::Rpc(err) => err.as_serde_error(),
+            UpstreamError::Unreachable { .. } => None,
+        }
+    }
+}
+
+impl From<UpstreamError> for ProviderError {
+    fn from(err: UpstreamError) -> Self {
+        ProviderError::JsonRpcClientError(Box::new(err))
+    }
+}
+
+/// What the last health check saw of a node.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeHealth {
+    pub url: String,
+    pub healthy: bool,
+    pub block_number: Option<u64>,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct UpstreamNode {
+    http: Http,
+    health: RwLock<NodeHealth>,
+}
+
+impl UpstreamNode {
+    async fn request<T, R>(&self, method: &str, params: &T) -> Result<R, HttpClientError>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        // Zero-sized params mean no params, and the request leaves them out
+        // only if they stay zero-sized
+        if std::mem::size_of::<T>() == 0 {
+            self.http.request(method, ()).await
+        } else {
+            self.http.request(method, params).await
+        }
+    }
+
+    fn url(&self) -> String {
+        self.health.read().unwrap().url.clone()
+    }
+}
+
+/// HTTP transport to the nodes of `network.rpc_url(s)`. Connections are
+/// kept alive and shared by every clone, and each call is bounded by
+/// `network.timeout_ms`.
+///
+/// Calls go to the healthiest node first. Idempotent reads fail over to the
+/// next node and are retried with exponential backoff once every node has
+/// failed, raw txs are broadcast to the `network.broadcast_count`
+/// healthiest nodes.
+#[derive(Clone, Debug)]
+pub struct UpstreamClient {
+    nodes: Arc<Vec<UpstreamNode>>,
+    retries: u32,
+    retry_backoff: Duration,
+    max_block_lag: u64,
+    broadcast_count: usize,
+}
+
+impl UpstreamClient {
+    pub fn new(config: &NetworkConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms))
+            .connect_timeout(Duration::from_millis(config.timeout_ms))
+            .pool_max_idle_per_host(config.max_idle_connections)
+            .tcp_keepalive(Duration::from_secs(60))
+            .build()?;
+
+        let mut nodes = vec![];
+        for url in config.urls() {
+            nodes.push(UpstreamNode {
+                http: Http::new_with_client(reqwest::Url::parse(&url)?, client.clone()),
+                // Trusted until the first check says otherwise
+                health: RwLock::new(NodeHealth {
+                    url,
+                    healthy: true,
+                    block_number: None,
+                    latency_ms: None,
+                    error: None,
+                }),
+            });
+        }
+        if nodes.is_empty() {
+            anyhow::bail!("No upstream node configured");
+        }
+
+        Ok(Self {
+            nodes: Arc::new(nodes),
+            retries: config.retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            max_block_lag: config.max_block_lag,
+            broadcast_count: config.broadcast_count,
+        })
+    }
+
+    // Healthy nodes first, fastest first; config order among unchecked ones
+    fn ranked_nodes(&self) -> Vec<&UpstreamNode> {
+        let mut nodes: Vec<(&UpstreamNode, bool, u64)> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let health = node.health.read().unwrap();
+                (node, health.healthy, health.latency_ms.unwrap_or(u64::MAX))
+            })
+            .collect();
+        nodes.sort_by_key(|(_, healthy, latency_ms)| (!*healthy, *latency_ms));
+
+        nodes.into_iter().map(|(node, _, _)| node).collect()
+    }
+
+    /// The last health check of every node, in config order.
+    pub fn health(&self) -> Vec<NodeHealth> {
+        self.nodes
+            .iter()
+            .map(|node| node.health.read().unwrap().clone())
+            .collect()
+    }
+
+    /// Asks every node for its block number. A node is unhealthy when it
+    /// does not answer or is more than `network.max_block_lag` blocks behind
+    /// the highest one.
+    pub async fn check_health(&self) {
+        let results = join_all(self.nodes.iter().map(|node| async move {
+            let started = Instant::now();
+            let result = node.request::<_, U64>("eth_blockNumber", &()).await;
+            (result, started.elapsed())
+        }))
+        .await;
+
+        let highest = results
+            .iter()
+            .filter_map(|(result, _)| result.as_ref().ok())
+            .map(|block_number| block_number.as_u64())
+            .max()
+            .unwrap_or_default();
+
+        for (node, (result, latency)) in self.nodes.iter().zip(results) {
+            let mut health = node.health.write().unwrap();
+            let was_healthy = health.healthy;
+
+            match result {
+                Ok(block_number) => {
+                    let lag = highest - block_number.as_u64();
+                    health.healthy = lag <= self.max_block_lag;
+                    health.block_number = Some(block_number.as_u64());
+                    health.latency_ms = Some(latency.as_millis() as u64);
+                    health.error = (!health.healthy).then(|| format!("{} blocks behind", lag));
+                }
+                Err(err) => {
+                    health.healthy = false;
+                    health.latency_ms = None;
+                    health.error = Some(err.to_string());
+                }
+            }
+
+            if health.healthy != was_healthy {
+                match &health.error {
+                    Some(error) => println!("Upstream {} is unhealthy: {}", health.url, error),
+                    None => println!("Upstream {} is healthy again", health.url),
+                }
+            }
+        }
+    }
+
+    async fn broadcast<T, R>(&self, method: &str, params: &T) -> Result<R, UpstreamError>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let nodes: Vec<&UpstreamNode> = self
+            .ranked_nodes()
+            .into_iter()
+            .take(self.broadcast_count.max(1))
+            .collect();
+        let results = join_all(
+            nodes
+                .iter()
+                .map(|node| node.request::<_, serde_json::Value>(method, params)),
+        )
+        .await;
+
+        // Any node accepting the tx is enough, it gossips from there
+        let mut first_err = None;
+        let mut accepted = None;
+        for (node, result) in nodes.iter().zip(results) {
+            match result {
+                Ok(value) => {
+                    accepted.get_or_insert(value);
+                }
+                Err(err) => {
+                    println!("Broadcast {} to {} failed: {}", method, node.url(), err);
+                    first_err.get_or_insert((node.url(), err));
+                }
+            }
+        }
+
+        match (accepted, first_err) {
+            (Some(value), _) => serde_json::from_value(value.clone()).map_err(|err| {
+                UpstreamError::Rpc(HttpClientError::SerdeJson {
+                    err,
+                    text: value.to_string(),
+                })
+            }),
+            (None, Some((url, err))) if is_transient(&err) => Err(UpstreamError::Unreachable {
+                url,
+                method: method.to_string(),
+                attempts: nodes.len() as u32,
+                source: err,
+            }),
+            (None, Some((_, err))) => Err(UpstreamError::Rpc(err)),
+            (None, None) => unreachable!("broadcast to at least one node"),
+        }
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for UpstreamClient {
+    type Error = UpstreamError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        if method == "eth_sendRawTransaction" {
+            return self.broadcast(method, &params).await;
+        }
+
+        let nodes = self.ranked_nodes();
+        let retries = if is_idempotent(method) {
+            self.retries
+        } else {
+            0
+        };
+
+        // Every node is tried once per round, `retries` is the number of
+        // rounds after the first
+        let mut round: u32 = 0;
+        loop {
+            let mut last_err = None;
+            for node in nodes.iter() {
+                let err = match node.request(method, &params).await {
+                    Ok(result) => return Ok(result),
+                    Err(err) => err,
+                };
+                if !is_transient(&err) {
+                    return Err(UpstreamError::Rpc(err));
+                }
+                last_err = Some((node.url(), err));
+            }
+
+            let (url, err) = last_err.expect("at least one node");
+            if round >= retries {
+                return Err(UpstreamError::Unreachable {
+                    url,
+                    method: method.to_string(),
+                    attempts: (round + 1) * nodes.len() as u32,
+                    source: err,
+                });
+            }
+
+            // Every node failed, back off before the next round
+            let backoff = 2u32.pow(round.min(MAX_BACKOFF_DOUBLINGS));
+            tokio::time::sleep(self.retry_backoff.saturating_mul(backoff)).await;
+            round += 1;
+        }
+    }
+}
+
+/// Builds the shared provider of `chain_id`. Called once per chain at
+/// startup.
+pub fn init_upstream(chain_id: u64, config: &NetworkConfig) -> anyhow::Result<()> {
+    let provider = Provider::new(UpstreamClient::new(config)?);
+
+    let mut upstreams = UPSTREAMS.write().unwrap();
+    if upstreams.contains_key(&chain_id) {
+        anyhow::bail!("Upstream of chain {} is already initialized", chain_id);
+    }
+    upstreams.insert(chain_id, provider);
+
+    Ok(())
+}
+
+/// The shared provider of `chain_id`. Clones share connections.
+pub fn get_upstream(chain_id: u64) -> Provider<UpstreamClient> {
+    UPSTREAMS
+        .read()
+        .unwrap()
+        .get(&chain_id)
+        .expect("upstream is not initialized")
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use jsonrpsee::server::RpcModule;
+    use jsonrpsee::types::error::{CallError, ErrorObject};
+
+    use super::*;
+    use crate::test_util::serve;
+
+    // A node that fails its first `failures` calls with a rate limit
+    #[derive(Default)]
+    struct Node {
+        block_number: u64,
+        failures: AtomicUsize,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Node {
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    fn rpc_error(code: i32) -> jsonrpsee::core::Error {
+        CallError::Custom(ErrorObject::owned(code, "failed", None::<()>)).into()
+    }
+
+    async fn start(block_number: u64, failures: usize) -> (String, Arc<Node>) {
+        let node = Arc::new(Node {
+            block_number,
+            failures: AtomicUsize::new(failures),
+            ..Node::default()
+        });
+        let mut module = RpcModule::new(node.clone());
+        for method in [
+            "eth_chainId",
+            "eth_blockNumber",
+            "eth_sendTransaction",
+            "eth_sendRawTransaction",
+        ] {
+            module
+                .register_method(method, move |_, node| {
+                    node.calls.lock().unwrap().push(method.to_string());
+                    let failing = node
+                        .failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    if failing {
+                        return Err(rpc_error(429));
+                    }
+                    Ok(format!("{:#x}", node.block_number))
+                })
+                .unwrap();
+        }
+        module
+            .register_method("eth_reject", |_, node| {
+                node.calls.lock().unwrap().push("eth_reject".to_string());
+                Err::<(), _>(rpc_error(-32000))
+            })
+            .unwrap();
+
+        (serve(module).await, node)
+    }
+
+    // Nothing listens there, connecting is refused
+    fn closed_url() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    fn client(urls: Vec<String>, retries: u32) -> UpstreamClient {
+        UpstreamClient::new(&NetworkConfig {
+            rpc_url: urls[0].clone(),
+            rpc_urls: urls[1..].to_vec(),
+            retries,
+            retry_backoff_ms: 1,
+            ..NetworkConfig::default()
+        })
+        .unwrap()
+    }
+
+    async fn call(client: &UpstreamClient, method: &str) -> Result<String, UpstreamError> {
+        client.request(method, ()).await
+    }
+
+    #[tokio::test]
+    async fn retries_only_idempotent_calls() {
+        let (url, node) = start(7, 2).await;
+        let client = client(vec![url], 2);
+
+        assert_eq!(call(&client, "eth_chainId").await.unwrap(), "0x7");
+        assert_eq!(node.calls().len(), 3);
+
+        node.failures.store(1, Ordering::SeqCst);
+        let err = call(&client, "eth_sendTransaction").await.unwrap_err();
+        assert!(matches!(
+            err,
+            UpstreamError::Unreachable { attempts: 1, .. }
+        ));
+        assert_eq!(node.calls().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_round() {
+        let (url, node) = start(7, usize::MAX).await;
+        let client = client(vec![url], 2);
+
+        let err = call(&client, "eth_blockNumber").await.unwrap_err();
+        assert!(matches!(
+            err,
+            UpstreamError::Unreachable { attempts: 3, .. }
+        ));
+        assert_eq!(node.calls().len(), 3);
+
+        // An answer that is an error is not retried
+        let err = call(&client, "eth_reject").await.unwrap_err();
+        assert!(matches!(err, UpstreamError::Rpc(_)));
+        assert_eq!(node.calls().len(), 4);
+    }
+
+    #[test]
+    fn ranks_healthy_fast_nodes_first() {
+        let urls: Vec<String> = (1..=3).map(|i| format!("http://node{}", i)).collect();
+        let client = client(urls, 0);
+        let ranked = |client: &UpstreamClient| -> Vec<String> {
+            client
+                .ranked_nodes()
+                .iter()
+                .map(|node| node.url())
+                .collect()
+        };
+        assert_eq!(
+            ranked(&client),
+            vec!["http://node1", "http://node2", "http://node3"]
+        );
+
+        for (node, healthy, latency_ms) in [
+            (0, false, Some(5)),
+            (1, true, Some(50)),
+            (2, true, Some(10)),
+        ] {
+            let mut health = client.nodes[node].health.write().unwrap();
+            health.healthy = healthy;
+            health.latency_ms = latency_ms;
+        }
+        assert_eq!(
+            ranked(&client),
+            vec!["http://node3", "http://node2", "http://node1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn health_checks_flag_lagging_and_down_nodes() {
+        let (ahead, _) = start(100, 0).await;
+        let (behind, _) = start(90, 0).await;
+        let (close, _) = start(98, 0).await;
+        let client = client(vec![ahead, behind, close, closed_url()], 0);
+
+        client.check_health().await;
+        let health = client.health();
+        assert!(health[0].healthy);
+        assert_eq!(health[0].block_number, Some(100));
+        assert!(!health[1].healthy);
+        assert_eq!(health[1].error.as_deref(), Some("10 blocks behind"));
+        assert!(health[2].healthy);
+        assert!(!health[3].healthy);
+        assert!(health[3].error.is_some());
+        assert_eq!(client.ranked_nodes().last().unwrap().url(), health[3].url);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_node() {
+        let (url, node) = start(7, 0).await;
+        let failing_over = client(vec![closed_url(), url], 0);
+
+        assert_eq!(call(&failing_over, "eth_chainId").await.unwrap(), "0x7");
+        // Not idempotent, but the first node was never reached
+        assert_eq!(
+            call(&failing_over, "eth_sendTransaction").await.unwrap(),
+            "0x7"
+        );
+        assert_eq!(node.calls(), vec!["eth_chainId", "eth_sendTransaction"]);
+
+        // A node that answers with an error is not failed over
+        let (first, first_node) = start(7, 0).await;
+        let rejecting = client(vec![first, closed_url()], 0);
+        let err = call(&rejecting, "eth_reject").await.unwrap_err();
+        assert!(matches!(err, UpstreamError::Rpc(_)));
+        assert_eq!(first_node.calls(), vec!["eth_reject"]);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_raw_txs_to_the_healthiest_nodes() {
+        let (rejecting, rejecting_node) = start(7, 1).await;
+        let (accepting, accepting_node) = start(7, 0).await;
+        let (spare, spare_node) = start(7, 0).await;
+        let mut both = client(vec![rejecting.clone(), accepting.clone(), spare], 0);
+        both.broadcast_count = 2;
+
+        // One node accepting is enough
+        assert_eq!(call(&both, "eth_sendRawTransaction").await.unwrap(), "0x7");
+        for node in [&rejecting_node, &accepting_node] {
+            assert_eq!(node.calls(), vec!["eth_sendRawTransaction"]);
+        }
+        assert!(spare_node.calls().is_empty());
+
+        both.nodes[0].health.write().unwrap().healthy = false;
+        call(&both, "eth_sendRawTransaction").await.unwrap();
+        assert_eq!(rejecting_node.calls().len(), 1);
+        assert_eq!(spare_node.calls(), vec!["eth_sendRawTransaction"]);
+
+        // Refused by every node it went to, and never retried
+        rejecting_node.failures.store(1, Ordering::SeqCst);
+        accepting_node.failures.store(1, Ordering::SeqCst);
+        let mut both = client(vec![rejecting, accepting], 3);
+        both.broadcast_count = 2;
+        let err = call(&both, "eth_sendRawTransaction").await.unwrap_err();
+        assert!(matches!(
+            err,
+            UpstreamError::Unreachable { attempts: 2, .. }
+        ));
+        assert_eq!(rejecting_node.calls().len(), 2);
+        assert_eq!(accepting_node.calls().len(), 3);
+    }
+}